@@ -0,0 +1,163 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * build.rs
+ *
+ * Generates the manual page qmanager.1 from the command line in src/cli.rs,
+ * help2man-style: the help text of every subcommand becomes a section of the
+ * page. The types the command line refers to are reduced to strings here,
+ * clap only needs their names.
+ **/
+#[macro_use]
+extern crate clap;
+extern crate humantime;
+extern crate structopt;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use structopt::StructOpt;
+
+#[allow(dead_code)]
+#[path = "src/cli.rs"]
+mod cli;
+
+mod federation {
+    pub type JobRef = String;
+}
+
+mod output {
+    pub type OutputFormat = String;
+}
+
+mod qmanager {
+    pub mod job_queue {
+        pub type JobEvent = String;
+    }
+
+    pub mod listing {
+        pub type SortKey = String;
+        pub type StateFilter = String;
+    }
+}
+
+/// One-line description for the NAME section
+const DESCRIPTION: &str = "Hybrid Computer Job Submission Tool";
+
+/// Width the help texts are wrapped at
+const WIDTH: usize = 80;
+
+/// Escapes text for roff
+fn escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The help text of the given subcommand
+fn help(command: &[&str]) -> String {
+    let mut args = vec![crate_name!()];
+    args.extend_from_slice(command);
+    args.push("--help");
+    match cli::Opt::clap()
+        .set_term_width(WIDTH)
+        .get_matches_from_safe(args)
+    {
+        Err(e) => e.message,
+        Ok(_) => panic!("no help for {:?}", command),
+    }
+}
+
+/// Names of the subcommands listed in a help text
+fn subcommands(help: &str) -> Vec<String> {
+    help.lines()
+        .skip_while(|line| line.trim() != "SUBCOMMANDS:")
+        .skip(1)
+        .filter(|line| line.starts_with("    ") && !line.starts_with("     "))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| *name != "help")
+        .map(String::from)
+        .collect()
+}
+
+/// Appends a section for the given subcommand and its own subcommands
+fn render_command(page: &mut String, command: &[&str]) {
+    let help = help(command);
+    page.push_str(&format!(
+        ".SS \"{} {}\"\n.nf\n{}\n.fi\n",
+        crate_name!(),
+        escape(&command.join(" ")),
+        escape(&help)
+    ));
+    for sub in subcommands(&help) {
+        let mut sub_command = command.to_vec();
+        sub_command.push(&sub);
+        render_command(page, &sub_command);
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/cli.rs");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let top = help(&[]);
+    let mut page = format!(
+        ".TH QMANAGER 1 \"\" \"{} {}\" \"User Commands\"\n",
+        crate_name!(),
+        crate_version!()
+    );
+    page.push_str(&format!(
+        ".SH NAME\n{} \\- {}\n",
+        crate_name!(),
+        escape(DESCRIPTION)
+    ));
+    page.push_str(&format!(".SH SYNOPSIS\n.nf\n{}\n.fi\n", escape(&top)));
+
+    page.push_str(".SH COMMANDS\n");
+    for sub in subcommands(&top) {
+        render_command(&mut page, &[&sub]);
+    }
+
+    page.push_str(
+        ".SH ENVIRONMENT\n\
+         Every key of the configuration file can be set as environment variable \
+         QMANAGER_<KEY>, i.e. QMANAGER_HOST or QMANAGER_STATE_FILE, with __ separating \
+         the keys of nested tables. QMANAGER_CONFIG, QMANAGER_CLIENT_CONFIG and \
+         QMANAGER_PROFILE set \\-\\-config, \\-\\-client\\-config and \\-\\-profile.\n\
+         .SH FILES\n\
+         .TP\n/etc/qmanager.conf\nSystem\\-wide configuration file of daemon and clients\n\
+         .TP\n~/.config/qmanager/client.toml\nServer profiles of the client\n",
+    );
+
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("qmanager.1"), page).unwrap();
+}
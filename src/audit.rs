@@ -0,0 +1,305 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * audit.rs
+ *
+ * Append-only log of all state-changing client requests. Every entry is a
+ * single JSON object on its own line (JSON Lines), recording who asked for
+ * what, from where, and how the daemon responded.
+ **/
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::Result;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde_json::{self, Value};
+use url::Url;
+
+use daemon::Caller;
+use protocol::{ErrorCode, Request, Response};
+
+/// File mode for newly created audit logs. Requests may contain
+/// personal data, so the log is not world-readable.
+const AUDIT_LOG_MODE: u32 = 0o640;
+
+/// Replacement for the values of secret request fields
+const REDACTED: &str = "[redacted]";
+
+/// Request field names whose values are never written to the log
+const SECRET_KEYS: [&str; 4] = ["secret", "token", "password", "authorization"];
+
+/// Request field names holding URLs, which are logged without their
+/// credentials and query values
+const URL_KEYS: [&str; 1] = ["url"];
+
+/// A single audit log entry
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Time of the request, RFC 3339 in UTC
+    timestamp: String,
+
+    /// Identity of the caller, if known
+    user: Option<&'a str>,
+
+    /// Remote address or local socket the request was received on
+    address: &'a str,
+
+    /// The request as sent by the client, secrets redacted
+    request: Value,
+
+    /// Either "success" or "failure"
+    outcome: &'static str,
+
+    /// Error code for failed requests
+    code: Option<ErrorCode>,
+
+    /// Error message for failed requests
+    message: Option<&'a str>,
+
+    /// HTTP status code returned to the client
+    status: u16,
+}
+
+/// The audit log file
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens the audit log for appending, creating it if necessary
+    pub fn open(path: &Path) -> Result<AuditLog> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(AUDIT_LOG_MODE)
+            .open(path)?;
+
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends an entry for the given request and the daemon's response to it
+    pub fn record(&self, caller: &Caller, request: &Request, status: u16, response: &Response) {
+        let mut request = serde_json::to_value(request).unwrap_or(Value::Null);
+        redact(&mut request);
+
+        let (code, message) = match response {
+            Response::Error(e) => (Some(e.code), Some(e.message.as_str())),
+            _ => (None, None),
+        };
+
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            user: caller.user.as_deref(),
+            address: &caller.address,
+            request,
+            outcome: if message.is_none() && status == 200 {
+                "success"
+            } else {
+                "failure"
+            },
+            code,
+            message,
+            status,
+        };
+
+        let line = serde_json::to_string(&entry).unwrap();
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            error!("Could not write audit log entry {}: {}", line, e);
+        }
+    }
+}
+
+/// Replaces the values of all secret fields in a JSON document, at any depth,
+/// and the credentials and query values of URLs
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                let k = k.to_lowercase();
+                if SECRET_KEYS.iter().any(|s| k.contains(s)) {
+                    *v = Value::String(REDACTED.to_owned());
+                } else if URL_KEYS.contains(&k.as_str()) && v.is_string() {
+                    let url = redact_url(v.as_str().unwrap_or(""));
+                    *v = Value::String(url);
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Replaces the user info and the values of the query parameters of a URL.
+/// URLs that cannot be parsed are replaced as a whole.
+fn redact_url(url: &str) -> String {
+    let mut url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return REDACTED.to_owned(),
+    };
+
+    let userinfo = !url.username().is_empty() || url.password().is_some();
+    let query = url.query().map(|q| {
+        q.split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("{}={}", p.split('=').next().unwrap_or(""), REDACTED))
+            .collect::<Vec<String>>()
+            .join("&")
+    });
+    let fragment = url.fragment().map(String::from);
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.set_query(None);
+    url.set_fragment(None);
+
+    // the marker is inserted literally, the URL setters would encode it
+    let mut redacted = url.into_string();
+    if userinfo {
+        if let Some(i) = redacted.find("://") {
+            redacted.insert_str(i + 3, &format!("{}@", REDACTED));
+        }
+    }
+    if let Some(query) = query {
+        redacted.push('?');
+        redacted.push_str(&query);
+    }
+    if let Some(fragment) = fragment {
+        redacted.push('#');
+        redacted.push_str(&fragment);
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use job_queue::{NotifyTarget, Submission};
+    use protocol::ApiError;
+
+    #[test]
+    fn redacts_secret_fields() {
+        let mut value = json!({
+            "Login": {"user": "alice", "Password": "hunter2", "nested": [{"api_token": "abc"}]},
+            "SubmitJob": {
+                "cmdline": "gwas --chr 1",
+                "notify": [
+                    {"url": "https://bob:pw@example.org/hook?token=abc&id=1#frag", "events": []},
+                    {"url": "http://example.org/plain", "events": []},
+                    {"url": "mailto:me@example.org?subject=done", "events": []},
+                    {"url": "not a url", "events": []}
+                ]
+            }
+        });
+        redact(&mut value);
+
+        assert_eq!(value["Login"]["user"], "alice");
+        assert_eq!(value["Login"]["Password"], REDACTED);
+        assert_eq!(value["Login"]["nested"][0]["api_token"], REDACTED);
+        assert_eq!(value["SubmitJob"]["cmdline"], "gwas --chr 1");
+
+        let urls: Vec<&str> = value["SubmitJob"]["notify"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["url"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://[redacted]@example.org/hook?token=[redacted]&id=[redacted]#frag",
+                "http://example.org/plain",
+                "mailto:me@example.org?subject=[redacted]",
+                "[redacted]",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let path = env::temp_dir().join(format!("qmanager-audit-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        let log = AuditLog::open(&path).unwrap();
+
+        let caller = Caller {
+            user: Some("alice".to_string()),
+            address: "/run/qmanager/qmanager.sock".to_string(),
+        };
+        let submission = Submission {
+            cmdline: "gwas --chr 1".to_string(),
+            notify: vec![NotifyTarget {
+                url: "https://example.org/hook?token=abc".to_string(),
+                events: Vec::new(),
+            }],
+            tags: Vec::new(),
+        };
+        log.record(
+            &caller,
+            &Request::SubmitJob(submission),
+            200,
+            &Response::SubmitJob(1),
+        );
+        let error = ApiError::new(ErrorCode::NoSuchJob, "No such job");
+        log.record(&caller, &Request::KillJob(4), 404, &Response::Error(error));
+
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let entries: Vec<Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+
+        let success = &entries[0];
+        assert!(humantime::parse_rfc3339(success["timestamp"].as_str().unwrap()).is_ok());
+        assert_eq!(success["user"], "alice");
+        assert_eq!(success["address"], "/run/qmanager/qmanager.sock");
+        assert_eq!(
+            success["request"]["SubmitJob"]["notify"][0]["url"],
+            "https://example.org/hook?token=[redacted]"
+        );
+        assert_eq!(success["outcome"], "success");
+        assert_eq!(success["code"], Value::Null);
+        assert_eq!(success["status"], 200);
+
+        let failure = &entries[1];
+        assert_eq!(failure["request"], json!({"KillJob": 4}));
+        assert_eq!(failure["outcome"], "failure");
+        assert_eq!(
+            failure["code"],
+            serde_json::to_value(ErrorCode::NoSuchJob).unwrap()
+        );
+        assert_eq!(failure["message"], "No such job");
+        assert_eq!(failure["status"], 404);
+    }
+}
@@ -0,0 +1,447 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * cli.rs
+ *
+ * The command line of the qmanager binary. Kept apart from merging the
+ * configuration so that the build script can include it to generate the
+ * manual page, see build.rs.
+ **/
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use clap::{AppSettings, Shell};
+use federation::JobRef;
+use output::OutputFormat;
+use qmanager::job_queue::JobEvent;
+use qmanager::listing::{SortKey, StateFilter};
+use structopt::StructOpt;
+
+/// A TCP address the daemon should listen on, as given in the config file
+#[derive(Debug, Clone)]
+pub struct ListenAddress {
+    /// IP address and port, i.e. '127.0.0.1:1337' or '[::1]:1337'
+    pub address: String,
+
+    /// Whether to use SSL/TLS on this address. If not given, SSL/TLS is used
+    /// unless --insecure is set.
+    pub tls: Option<bool>,
+}
+
+/// Pre and post hook executables as given in the config file
+#[derive(Debug, Clone, Default)]
+pub struct HookOptions {
+    /// Executable to run before a job is started
+    pub pre: Option<PathBuf>,
+
+    /// Executable to run after a job has finished
+    pub post: Option<PathBuf>,
+
+    /// Time a hook may take, in seconds or i.e. '5min'
+    pub timeout: Option<String>,
+}
+
+/// Options selecting the jobs listed by the status command
+#[derive(Debug, StructOpt)]
+pub struct ListOptions {
+    /// Comma-separated job states to list: queued, running, finished, succeeded, failed, killed
+    #[structopt(long, use_delimiter = true)]
+    pub state: Vec<StateFilter>,
+
+    /// List jobs of this appkey only
+    #[structopt(long)]
+    pub appkey: Option<String>,
+
+    /// List jobs submitted by this user only
+    #[structopt(long)]
+    pub owner: Option<String>,
+
+    /// List jobs carrying this tag only. May be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub tag: Vec<String>,
+
+    /// List jobs scheduled since, i.e. '2021-06-01T12:00:00Z' or '2h' ago
+    #[structopt(long, parse(try_from_str = parse_time))]
+    pub since: Option<SystemTime>,
+
+    /// List jobs scheduled before, i.e. '2021-06-01T12:00:00Z' or '2h' ago
+    #[structopt(long, parse(try_from_str = parse_time))]
+    pub until: Option<SystemTime>,
+
+    /// List jobs finished since, i.e. '2021-06-01T12:00:00Z' or '2h' ago
+    #[structopt(long, parse(try_from_str = parse_time))]
+    pub finished_since: Option<SystemTime>,
+
+    /// List jobs finished before, i.e. '2021-06-01T12:00:00Z' or '2h' ago
+    #[structopt(long, parse(try_from_str = parse_time))]
+    pub finished_until: Option<SystemTime>,
+
+    /// Sort jobs by id, started or finished
+    #[structopt(long)]
+    pub sort: Option<SortKey>,
+
+    /// Sort in descending order
+    #[structopt(long)]
+    pub desc: bool,
+
+    /// Maximum number of jobs to list
+    #[structopt(long)]
+    pub limit: Option<usize>,
+
+    /// Number of jobs to skip
+    #[structopt(long)]
+    pub offset: Option<usize>,
+
+    /// Continue a previous listing at the cursor it printed
+    #[structopt(long)]
+    pub cursor: Option<String>,
+
+    /// Include the jobs' stdout and stderr
+    #[structopt(long)]
+    pub with_output: bool,
+}
+
+/// Parses a point in time given either as RFC 3339 timestamp or as a
+/// duration before now, such as '2h'
+fn parse_time(s: &str) -> std::result::Result<SystemTime, String> {
+    humantime::parse_rfc3339_weak(s)
+        .or_else(|_| humantime::parse_duration(s).map(|d| SystemTime::now() - d))
+        .map_err(|_| {
+            format!(
+                "Invalid time '{}', expected i.e. '2021-06-01T12:00:00Z' or '2h'",
+                s
+            )
+        })
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name=crate_name!(), version=crate_version!(), author=crate_authors!(), about=crate_description!())]
+pub struct Opt {
+    /// Set CA certificate
+    #[structopt(long, parse(from_os_str))]
+    pub ca: Option<PathBuf>,
+
+    /// Use plain TCP instead of SSL/TLS
+    #[structopt(long)]
+    pub insecure: bool,
+
+    /// For clients, the host name to connect to. For servers ignored (default: localhost)
+    #[structopt(long, default_value = "")]
+    pub host: String,
+
+    /// For clients, the port to connect to. For servers, the port to listen on (default: 1337)
+    #[structopt(long, default_value = "0")]
+    pub port: u16,
+
+    /// For clients, the local socket to connect to instead of host and port. For servers,
+    /// an additional local socket to listen on
+    #[structopt(long, parse(from_os_str))]
+    pub socket: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Dump client requests and responses to stdout
+    pub dump_json: bool,
+
+    #[structopt(long, default_value = "")]
+    /// The log level (default: Info, possible: Error, Warn, Info, Debug)
+    pub loglevel: String,
+
+    #[structopt(long, parse(from_os_str), env = "QMANAGER_CONFIG")]
+    /// Path to configuration file (default: /etc/qmanager.conf)
+    pub config: Option<PathBuf>,
+
+    #[structopt(long, env = "QMANAGER_PROFILE")]
+    /// For clients, the server profile of the client configuration file to use
+    pub profile: Option<String>,
+
+    #[structopt(long, parse(from_os_str), env = "QMANAGER_CLIENT_CONFIG")]
+    /// For clients, path to the client configuration file
+    /// (default: ~/.config/qmanager/client.toml)
+    pub client_config: Option<PathBuf>,
+
+    #[structopt(skip)]
+    /// Bearer token for clients, from the server profile
+    pub token: Option<String>,
+
+    #[structopt(skip)]
+    /// Application keys
+    pub appkeys: HashMap<String, PathBuf>,
+
+    #[structopt(skip)]
+    /// Addresses for the daemon to listen on. Listens on all addresses on
+    /// --port if empty.
+    pub listen: Vec<ListenAddress>,
+
+    #[structopt(skip)]
+    /// Hooks run for every job (daemon only)
+    pub hooks: HookOptions,
+
+    #[structopt(skip)]
+    /// Hooks run for jobs of specific appkeys (daemon only)
+    pub appkey_hooks: HashMap<String, HookOptions>,
+
+    #[structopt(subcommand)]
+    pub cmd: OptCommand,
+
+    #[structopt(long, parse(from_os_str))]
+    pub state_file: Option<PathBuf>,
+}
+
+// Only ever constructed once, so the size of the daemon variant does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+pub enum OptCommand {
+    /// Starts the qmanager daemon
+    Daemon {
+        /// Stays in foreground, does not detach. Pidfile argument is ignored
+        #[structopt(long)]
+        foreground: bool,
+
+        /// Certificate file for SSL/TLS operation
+        #[structopt(long, parse(from_os_str))]
+        cert: Option<PathBuf>,
+
+        /// Key for SSL/TLS certificate
+        #[structopt(long, parse(from_os_str))]
+        key: Option<PathBuf>,
+
+        /// PID file location
+        #[structopt(long, parse(from_os_str))]
+        pidfile: Option<PathBuf>,
+
+        /// Notify URL
+        #[structopt(long)]
+        notify_url: Option<String>,
+
+        /// How to call the notify URL: 'get' (default) or 'post' with a JSON job description
+        #[structopt(long)]
+        notify_method: Option<String>,
+
+        /// Shared secret to sign notification calls with (HMAC-SHA256)
+        #[structopt(long)]
+        notify_secret: Option<String>,
+
+        /// Number of delivery attempts before a notification is given up (default: 8)
+        #[structopt(long)]
+        notify_retries: Option<u32>,
+
+        /// Sendmail binary to send notification mails with (default: /usr/sbin/sendmail)
+        #[structopt(long, parse(from_os_str))]
+        sendmail: Option<PathBuf>,
+
+        /// SMTP relay ('host:port') to send notification mails to instead of using sendmail
+        #[structopt(long)]
+        smtp_relay: Option<String>,
+
+        /// Sender address of notification mails (default: qmanager@localhost)
+        #[structopt(long)]
+        mail_from: Option<String>,
+
+        /// File mode of the local socket, in octal (default: 0660)
+        #[structopt(long)]
+        socket_mode: Option<String>,
+
+        /// Group owning the local socket
+        #[structopt(long)]
+        socket_group: Option<String>,
+
+        /// Audit log file recording all state-changing requests (JSON Lines)
+        #[structopt(long, parse(from_os_str))]
+        audit_log: Option<PathBuf>,
+    },
+
+    /// Requests the queue to be stopped
+    Stop {},
+
+    /// Requests queue operations to be resumed
+    Start {},
+
+    /// Requests queue status and lists jobs, optionally filtered and paged
+    Status {
+        #[structopt(flatten)]
+        list: ListOptions,
+
+        /// List the jobs of the daemons of all server profiles
+        #[structopt(long, conflicts_with = "cursor")]
+        all_profiles: bool,
+
+        /// Output format: table, json, yaml or csv
+        #[structopt(short, long, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    /// Submits a job to the queue
+    Submit {
+        #[structopt(name = "CMDLINE", parse(from_str), required_unless = "from")]
+        cmdline: Option<String>,
+
+        /// Submit all jobs of a manifest (.toml, .json or .csv) at once instead of a single one
+        #[structopt(long, parse(from_os_str), conflicts_with_all = &["CMDLINE", "wait"])]
+        from: Option<PathBuf>,
+
+        /// URL (http(s) or mailto) to notify of job events instead of the daemon's notify URL. May be repeated
+        #[structopt(long, number_of_values = 1)]
+        notify: Vec<String>,
+
+        /// Comma-separated events to notify of: queued, started, succeeded, failed, killed
+        /// (default: succeeded, failed, killed)
+        #[structopt(long, use_delimiter = true, requires = "notify")]
+        notify_on: Vec<JobEvent>,
+
+        /// Tag to attach to the job, for filtering job listings. May be repeated
+        #[structopt(long, number_of_values = 1)]
+        tag: Vec<String>,
+
+        /// Wait for the job to finish, print its output and exit with its exit code
+        #[structopt(long)]
+        wait: bool,
+
+        /// Give up waiting after this time, i.e. '2h', and exit with 124
+        #[structopt(long, requires = "wait")]
+        timeout: Option<humantime::Duration>,
+
+        /// Output format: table, json, yaml or csv
+        #[structopt(short, long, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    /// Waits for a job to finish, prints its output and exits with its exit code
+    Wait {
+        /// Job ID to wait for
+        #[structopt(long)]
+        job_id: u64,
+
+        /// Give up waiting after this time, i.e. '2h', and exit with 124
+        #[structopt(long)]
+        timeout: Option<humantime::Duration>,
+    },
+
+    /// Shows a single job in detail
+    Show {
+        /// Job ID to show
+        #[structopt(long)]
+        job_id: u64,
+
+        /// Do not show the job's stdout
+        #[structopt(long)]
+        no_stdout: bool,
+
+        /// Do not show the job's stderr
+        #[structopt(long)]
+        no_stderr: bool,
+
+        /// Output format: table, json, yaml or csv
+        #[structopt(short, long, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    /// Looks up a job on the daemons of all server profiles
+    Find {
+        /// Job ID, optionally with the profile or host name of its daemon, i.e. 'gwas1:42'
+        #[structopt(long)]
+        job_id: JobRef,
+
+        /// Output format: table, json, yaml or csv
+        #[structopt(short, long, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    /// Removes a finished job from the queue
+    Remove {
+        /// Job ID to remove from the 'finished' queue
+        #[structopt(long)]
+        job_id: u64,
+
+        /// Output format: table, json, yaml or csv
+        #[structopt(short, long, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    /// Asks a running job to terminate
+    Kill {
+        /// Job ID to terminate
+        #[structopt(long)]
+        job_id: u64,
+
+        /// Output format: table, json, yaml or csv
+        #[structopt(short, long, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    /// Lists undelivered job notifications or schedules them for redelivery
+    Notifications {
+        /// ID of a notification to deliver again immediately
+        #[structopt(long)]
+        retry: Option<u64>,
+
+        /// Deliver all notifications again that have failed for good
+        #[structopt(long, conflicts_with = "retry")]
+        retry_all: bool,
+    },
+
+    /// Shows the daemon's version and supported features
+    ServerInfo {},
+
+    /// Shows the queue in a full-screen dashboard and controls its jobs
+    Top {},
+
+    /// Removes finished jobs from the queue based on timestamps
+    Cleanup {
+        /// Maximum age of a job's 'finished' timestamp, i.e. '8 days 3 seconds'
+        #[structopt(long)]
+        max_age: humantime::Duration,
+    },
+
+    /// Works with the configuration
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+
+    /// Prints a shell completion script for bash, zsh or fish
+    Completions {
+        /// Shell to complete the command line of
+        #[structopt(possible_values = &["bash", "zsh", "fish"])]
+        shell: Shell,
+    },
+
+    /// Lists the current job IDs or appkeys, as used by the completion scripts
+    #[structopt(setting = AppSettings::Hidden)]
+    Complete {
+        /// What to list
+        #[structopt(possible_values = &["job-ids", "appkeys"])]
+        what: String,
+    },
+
+    /// Prints the manual page generated at build time, in roff format
+    Manpage {},
+}
+
+// Subcommands of 'config'. No doc comment, it would replace the help text of
+// 'config' itself.
+#[derive(StructOpt, Debug)]
+pub enum ConfigCommand {
+    /// Loads the configuration as the daemon would and reports all problems
+    Check {},
+}
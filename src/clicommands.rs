@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -23,191 +23,243 @@
 /**
  * clicommands.rs
  *
- * Contains various functions that create JSON requests out of CLI arguments,
- * parse the JSON response and provide a human-readable(-ish) console output.
+ * Contains various functions that turn CLI arguments into client requests and
+ * print their results to the console.
  **/
-use std::io::Result;
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use qmanager::client::QmanagerClient;
+use qmanager::clierror::Result;
+use qmanager::job_queue::*;
+use qmanager::listing::{JobFilter, JobPage};
+use qmanager::outbox::Notification;
+
+use output::{self, JobRecord, OutputFormat};
+
+/// A job listing as printed in JSON and YAML
+#[derive(Serialize)]
+struct StatusDocument {
+    queue_state: QueueState,
+    jobs: Vec<JobRecord>,
+
+    /// Number of matching jobs, on all pages
+    total: usize,
+    next_cursor: Option<String>,
+}
 
-use serde_json;
+/// Prints a detailed description of a single job to the console
+fn print_job_details(job: &Job, stdout: bool, stderr: bool) {
+    println!("Job #{}", job.id);
+    println!("  Command:   {}", job.cmdline);
+    println!("  State:     {:?}", job.state);
+    println!("  Owner:     {}", job.owner.as_deref().unwrap_or("-"));
+    if !job.tags.is_empty() {
+        println!("  Tags:      {}", job.tags.join(", "));
+    }
+    println!("  Scheduled: {}", output::local_time(Some(job.scheduled)));
+    println!("  Started:   {}", output::local_time(job.started));
+    println!("  Finished:  {}", output::local_time(job.finished));
+    println!("  Duration:  {}", output::format_run_time(job));
+    println!(
+        "  PID:       {}",
+        job.pid
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    );
+    for target in &job.notify {
+        let events: Vec<String> = if target.events.is_empty() {
+            JobEvent::DEFAULT.iter().map(|e| e.to_string()).collect()
+        } else {
+            target.events.iter().map(|e| e.to_string()).collect()
+        };
+        println!("  Notify:    {} ({})", target.url, events.join(", "));
+    }
 
-use job_queue::*;
-use protocol::{Request, Response};
+    if stdout {
+        println!("\n--- stdout ---\n{}", job.stdout.trim_end());
+    }
+    if stderr {
+        println!("\n--- stderr ---\n{}", job.stderr.trim_end());
+    }
+}
 
-/// Dumps a job vector to the console
-fn print_jobs(header: &str, jobs: Vec<Job>) {
-    println!("{}", header);
-    for j in jobs {
-        println!("{:?}", j);
+/// Dumps a notification list to the console
+fn print_notifications(notifications: Vec<Notification>) {
+    for n in notifications {
+        println!(
+            "#{} job {} '{}' to {}: {:?} after {} attempt(s){}",
+            n.id,
+            n.job.id,
+            n.event,
+            n.url,
+            n.state,
+            n.attempts,
+            n.last_error
+                .map(|e| format!(", last error: {}", e))
+                .unwrap_or_default()
+        );
     }
 }
 
-/// Sends a job submission request to the server and processes its result
+/// Submits a job and prints its ID
 ///
 /// # Arguments
 ///
-/// * `client` - a HTTP(S) client object to be used for the connection
-/// * `url` - the absolute URL that the client should use for posting the request
-/// * `cmdline`- command line to be submitted for execution
-/// * `dump_protocol` - a flag indicating that the JSON requests and responses are to be dumped
+/// * `client` - the client connected to the server
+/// * `submission`- command line to be submitted for execution and notification targets
+/// * `wait` - the job is going to be waited for, so its ID is not printed to stdout
+/// * `format` - how to print the job ID
 pub fn handle_submit(
-    client: &reqwest::Client,
-    url: reqwest::Url,
-    cmdline: &str,
-    dump_protocol: bool,
-) -> Result<()> {
-    // serialize the request into a JSON object
-    let request_s = serde_json::to_string_pretty(&Request::SubmitJob(cmdline.to_string()))?;
-
-    // write it to the server
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+    client: &QmanagerClient,
+    submission: Submission,
+    wait: bool,
+    format: OutputFormat,
+) -> Result<u64> {
+    // make sure the job can be waited for before it is submitted
+    if wait {
+        client.require_features(&["wait-job"])?;
     }
 
-    // block for the server's response...
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+    let id = client.submit(submission)?;
+    match format {
+        // keep stdout free for the job's output when waiting
+        _ if wait => eprintln!("Submitted as job #{}", id),
+        OutputFormat::Table => println!("Submitted as job #{}", id),
+        OutputFormat::Csv => output::print_csv(&["id"], &[id.to_string()]),
+        _ => output::print_document(format, &json!({ "id": id })),
     }
+    Ok(id)
+}
 
-    // ...and deserialize the response object from JSON
-    let response = serde_json::from_str(&response_s)?;
-
-    match response {
-        Response::SubmitJob(id) => println!("Submitted as job #{}", id),
-        Response::Error(s) => eprintln!("Could not submit job: {}", s),
-        _ => panic!("Unexpected response: {:?}", response),
+/// Submits the jobs of a manifest at once and prints their IDs
+pub fn handle_submit_batch(
+    client: &QmanagerClient,
+    submissions: Vec<Submission>,
+    format: OutputFormat,
+) -> Result<()> {
+    let ids = client.submit_batch(submissions)?;
+    match format {
+        OutputFormat::Table => match (ids.first(), ids.last()) {
+            (Some(first), Some(last)) if last - first + 1 == ids.len() as u64 => {
+                println!("Submitted {} jobs as #{} to #{}", ids.len(), first, last)
+            }
+            _ => println!(
+                "Submitted {} jobs as {}",
+                ids.len(),
+                ids.iter()
+                    .map(|id| format!("#{}", id))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        },
+        OutputFormat::Csv => {
+            println!("id");
+            for id in ids {
+                println!("{}", id);
+            }
+        }
+        _ => output::print_document(format, &json!({ "ids": ids })),
     }
-
     Ok(())
 }
 
-/// Requests a job to be removed from the queue
-pub fn handle_remove(
-    client: &reqwest::Client,
-    url: reqwest::Url,
-    jobid: u64,
-    dump_protocol: bool,
-) -> Result<Job> {
-    let request_s = serde_json::to_string_pretty(&Request::RemoveJob(jobid))?;
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+/// Exit status of `wait` for a job in the given state: the job's exit code,
+/// 128 plus the signal for killed jobs as in the shell, and 126 for jobs
+/// that could not be run. None while the job has not finished.
+fn job_exit_status(state: &JobState) -> Option<i32> {
+    match state {
+        JobState::Queued | JobState::Running => None,
+        JobState::Terminated(code) => Some(*code),
+        JobState::Killed(signal) => Some(128 + signal),
+        JobState::Failed(_) => Some(126),
     }
+}
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
-    }
-    let response = serde_json::from_str(&response_s)?;
+/// Waits for a job to finish, then copies its stdout and stderr to ours
+/// and returns its exit status (see job_exit_status()), or 124 if the job
+/// has not finished within the timeout
+pub fn handle_wait(client: &QmanagerClient, id: u64, timeout: Option<Duration>) -> Result<i32> {
+    let job = client.wait(id, timeout)?;
 
-    match response {
-        Response::GetJob(job) => Ok(job),
-        Response::Error(s) => {
-            eprintln!("Could not remove job: {}", s);
-            Err(::std::io::Error::from(::std::io::ErrorKind::Other))
+    match job_exit_status(&job.state) {
+        Some(status) => {
+            io::stdout().write_all(job.stdout.as_bytes())?;
+            io::stdout().flush()?;
+            io::stderr().write_all(job.stderr.as_bytes())?;
+            if let JobState::Failed(ref reason) = job.state {
+                eprintln!("Job #{} could not be run: {}", id, reason);
+            }
+            Ok(status)
+        }
+        None => {
+            eprintln!("Job #{} has not finished yet ({:?})", id, job.state);
+            Ok(124)
         }
-        _ => panic!("Unexpected response: {:?}", response),
     }
 }
 
-/// Requests a running job to be terminated
-pub fn handle_kill(
-    client: &reqwest::Client,
-    url: reqwest::Url,
-    jobid: u64,
-    dump_protocol: bool,
+/// Requests a single job and prints it in detail
+pub fn handle_show(
+    client: &QmanagerClient,
+    id: u64,
+    stdout: bool,
+    stderr: bool,
+    format: OutputFormat,
 ) -> Result<()> {
-    let request_s = serde_json::to_string_pretty(&Request::KillJob(jobid))?;
-
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+    let job = client.get_job(id, stdout, stderr)?;
+    match format {
+        OutputFormat::Table => print_job_details(&job, stdout, stderr),
+        OutputFormat::Csv => output::print_job_csv(&[JobRecord::new(&job, false)]),
+        _ => {
+            let mut record = JobRecord::new(&job, true);
+            record.stdout = record.stdout.filter(|_| stdout);
+            record.stderr = record.stderr.filter(|_| stderr);
+            output::print_document(format, &record);
+        }
     }
+    Ok(())
+}
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+/// Requests a job to be removed from the queue and prints it
+pub fn handle_remove(client: &QmanagerClient, jobid: u64, format: OutputFormat) -> Result<()> {
+    let job = client.remove(jobid)?;
+    match format {
+        OutputFormat::Table => output::print_job_table(std::slice::from_ref(&job)),
+        OutputFormat::Csv => output::print_job_csv(&[JobRecord::new(&job, false)]),
+        _ => output::print_document(format, &JobRecord::new(&job, false)),
     }
-    let response = serde_json::from_str(&response_s)?;
+    Ok(())
+}
 
-    match response {
-        Response::Ok => Ok(()),
-        Response::Error(s) => {
-            eprintln!("Could not kill job: {}", s);
-            Err(::std::io::Error::from(::std::io::ErrorKind::Other))
-        }
-        _ => panic!("Unexpected response: {:?}", response),
+/// Requests a running job to be terminated
+pub fn handle_kill(client: &QmanagerClient, jobid: u64, format: OutputFormat) -> Result<()> {
+    client.kill(jobid)?;
+    match format {
+        OutputFormat::Table => println!("Job #{} has been asked to terminate.", jobid),
+        OutputFormat::Csv => output::print_csv(&["id"], &[jobid.to_string()]),
+        _ => output::print_document(format, &json!({ "id": jobid })),
     }
+    Ok(())
 }
 
 /// Sets the current state of the queue.
 /// Note that 'Stopped' cannot be set manually and will yield errors. You will have
 /// to set 'Stopping' and let the queue itself to decide to go into 'Stopped' mode.
-pub fn handle_set_queue_status(
-    client: &reqwest::Client,
-    url: reqwest::Url,
-    new_state: QueueState,
-    dump_protocol: bool,
-) -> Result<()> {
-    let request_s = serde_json::to_string_pretty(&Request::SetQueueState(new_state)).unwrap();
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
-    }
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
-    }
-    let response = serde_json::from_str(&response_s)?;
-    match response {
-        Response::QueueState(s) => println!("Current queue status: {:?}", s),
-        Response::Error(s) => eprintln!("Could not get queue status: {}", s),
-        _ => panic!("Unexpected response: {:?}", response),
-    };
+pub fn handle_set_queue_status(client: &QmanagerClient, new_state: QueueState) -> Result<()> {
+    let state = client.set_state(new_state)?;
+    println!("Current queue status: {:?}", state);
     Ok(())
 }
 
 /// Removes jobs from the finished queue based on their age.
 /// There is no direct JSON command to do this, so it requests
-/// the job lists and removes them manually.
-pub fn handle_cleanup(
-    client: &reqwest::Client,
-    url: reqwest::Url,
-    max_age: humantime::Duration,
-    dump_protocol: bool,
-) -> Result<usize> {
+/// the job lists and removes them manually. Fails if any of the jobs could
+/// not be removed.
+pub fn handle_cleanup(client: &QmanagerClient, max_age: humantime::Duration) -> Result<()> {
     // Request list of finished jobs
-    let request_s = serde_json::to_string_pretty(&Request::GetFinishedJobs).unwrap();
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
-    }
-
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
-    }
+    let jobs = client.finished_jobs()?;
 
     // Get time stamp of oldest acceptable finished job
     let oldest_time = std::time::SystemTime::now() - *max_age;
@@ -220,103 +272,150 @@ pub fn handle_cleanup(
 
     // Counter for removed jobs
     let mut jobs_removed = 0;
+    let mut last_error = None;
 
-    // Find and remove expired jobs
-    if let Response::GetJobs(jobs) = serde_json::from_str(&response_s)? {
-        for job in &jobs {
-            if let Some(t) = job.finished {
-                if t < oldest_time {
-                    match handle_remove(client, url.clone(), job.id, dump_protocol) {
-                        Ok(_) => jobs_removed += 1,
-                        Err(e) => println!("Could not remove job {}: {}", job.id, e),
+    // Find and remove expired jobs, carrying on after errors
+    for job in &jobs {
+        if let Some(t) = job.finished {
+            if t < oldest_time {
+                match client.remove(job.id) {
+                    Ok(_) => jobs_removed += 1,
+                    // the last error is reported by the caller
+                    Err(e) => {
+                        if let Some(previous) = last_error.replace(e) {
+                            eprintln!("{}", previous);
+                        }
                     }
                 }
             }
         }
     }
 
-    Ok(jobs_removed)
+    println!("{} jobs removed.", jobs_removed);
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
 /// Requests the job queue state, the list of queued, running and finished jobs respectively
 pub fn handle_queue_status(
-    client: &reqwest::Client,
-    url: reqwest::Url,
-    dump_protocol: bool,
+    client: &QmanagerClient,
+    filter: Option<JobFilter>,
+    format: OutputFormat,
 ) -> Result<()> {
-    // Request general queue state
-    let mut request_s = serde_json::to_string_pretty(&Request::GetQueueState).unwrap();
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
-    }
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+    // older daemons know nothing but the complete lists
+    if filter.is_some() {
+        client.require_features(&["job-filters"])?;
     }
-    let response = serde_json::from_str(&response_s)?;
-    match response {
-        Response::QueueState(s) => println!("Current queue status: {:?}", s),
-        Response::Error(s) => eprintln!("Could not get queue status: {}", s),
-        _ => panic!("Unexpected response: {:?}", response),
-    };
 
-    // Request list of queued jobs (including running)
-    request_s = serde_json::to_string_pretty(&Request::GetQueuedJobs).unwrap();
-    response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+    // Request general queue state
+    let queue_state = client.queue_state()?;
+    if format == OutputFormat::Table {
+        println!("Current queue status: {:?}", queue_state);
     }
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
-    }
-    let mut response = serde_json::from_str(&response_s)?;
+    let (with_output, page) = match filter {
+        Some(filter) => (filter.with_output, client.list(filter)?),
+        None => {
+            // Without options, list queued (including running) and finished
+            // jobs separately, as always. This works with every daemon.
+            let queued = client.queued_jobs()?;
+            let finished = client.finished_jobs()?;
+            if format == OutputFormat::Table {
+                println!("\nQUEUED JOBS");
+                output::print_job_table(&queued);
+                println!("\nFINISHED JOBS");
+                output::print_job_table(&finished);
+                return Ok(());
+            }
 
-    match response {
-        Response::GetJobs(jobs) => print_jobs("QUEUED JOBS", jobs),
-        Response::Error(s) => {
-            eprintln!("Could not get queued jobs: {}", s);
+            // the machine-readable formats list all jobs at once
+            let jobs: Vec<Job> = queued.into_iter().chain(finished).collect();
+            let page = JobPage {
+                total: jobs.len(),
+                jobs,
+                next_cursor: None,
+            };
+            (false, page)
         }
-        _ => {
-            panic!("Unexpected response: {:?}", response);
+    };
+
+    let records = || {
+        page.jobs
+            .iter()
+            .map(|job| JobRecord::new(job, with_output))
+            .collect::<Vec<_>>()
+    };
+    match format {
+        OutputFormat::Table => {
+            println!();
+            output::print_job_table(&page.jobs);
+            println!(
+                "\n{} of {} matching job(s) shown.",
+                page.jobs.len(),
+                page.total
+            );
+            if let Some(ref cursor) = page.next_cursor {
+                println!("More jobs available, continue with --cursor {}", cursor);
+            }
+            if with_output {
+                for job in &page.jobs {
+                    println!(
+                        "\n--- job #{} stdout ---\n{}",
+                        job.id,
+                        job.stdout.trim_end()
+                    );
+                    println!(
+                        "\n--- job #{} stderr ---\n{}",
+                        job.id,
+                        job.stderr.trim_end()
+                    );
+                }
+            }
         }
+        OutputFormat::Csv => output::print_job_csv(&records()),
+        _ => output::print_document(
+            format,
+            &StatusDocument {
+                queue_state,
+                jobs: records(),
+                total: page.total,
+                next_cursor: page.next_cursor.clone(),
+            },
+        ),
     }
 
-    // Request list of finished jobs
-    request_s = serde_json::to_string_pretty(&Request::GetFinishedJobs).unwrap();
-    response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
-    }
+    Ok(())
+}
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
-    }
-    response = serde_json::from_str(&response_s)?;
-    match response {
-        Response::GetJobs(jobs) => print_jobs("FINISHED JOBS", jobs),
-        Response::Error(s) => {
-            eprintln!("Could not get finished jobs: {}", s);
-        }
-        _ => {
-            panic!("Unexpected response: {:?}", response);
-        }
+/// Requests the list of undelivered notifications
+pub fn handle_notifications(client: &QmanagerClient) -> Result<()> {
+    let notifications = client.notifications()?;
+    if notifications.is_empty() {
+        println!("No undelivered notifications.");
+    } else {
+        print_notifications(notifications);
     }
+    Ok(())
+}
+
+/// Schedules the given notification, or all failed ones, for immediate redelivery
+pub fn handle_retry_notifications(client: &QmanagerClient, id: Option<u64>) -> Result<()> {
+    let notifications = client.retry_notifications(id)?;
+    println!(
+        "{} notification(s) scheduled for delivery.",
+        notifications.len()
+    );
+    Ok(())
+}
 
+/// Requests the daemon's protocol version, program version and features
+pub fn handle_server_info(client: &QmanagerClient) -> Result<()> {
+    let info = client.server_info()?;
+    println!("Daemon version:   {}", info.daemon_version);
+    println!("Protocol version: {}", info.protocol_version);
+    println!("Features:         {}", info.features.join(", "));
+    println!("Appkeys:          {}", info.appkeys.join(", "));
     Ok(())
 }
@@ -0,0 +1,781 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * client.rs
+ *
+ * Typed client for the qmanager daemon, used by the command line client and
+ * by Rust programs embedding qmanager. All methods send a single request (or
+ * a series of requests, for waiting) and return the daemon's answer as typed
+ * values; nothing is printed unless the protocol dump is enabled.
+ **/
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONNECTION};
+use serde_json;
+
+use clierror::{ClientError, Result};
+use events::Event;
+use job_queue::{Job, JobEvent, NotifyTarget, QueueState, Submission};
+use listing::{JobFilter, JobPage};
+use outbox::Notification;
+use protocol::{
+    ApiError, ErrorCode, Request, RequestEnvelope, Response, ResponseEnvelope, ServerInfo,
+};
+use unix_socket;
+
+/// Longest time a single WaitJob request may take, well below the HTTP
+/// client's timeout of 30 seconds
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(20);
+
+/// The way the client reaches the server
+#[derive(Clone)]
+pub enum Connection {
+    /// HTTP(S) client object and the absolute URL to post requests to
+    Http(reqwest::Client, reqwest::Url),
+
+    /// Path of the server's local Unix socket
+    Unix(PathBuf),
+}
+
+/// Error body of the REST API
+#[derive(Deserialize)]
+struct RestError {
+    error: ApiError,
+}
+
+/// Innermost cause of an error, i.e. 'Connection refused' instead of the
+/// whole chain of wrapping errors
+fn root_cause(e: &dyn Error) -> String {
+    let mut cause = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+impl Connection {
+    /// Address of the server for error messages
+    pub fn address(&self) -> String {
+        match self {
+            Connection::Http(_, url) => format!(
+                "{}:{}",
+                url.host_str().unwrap_or_default(),
+                url.port_or_known_default().unwrap_or_default()
+            ),
+            Connection::Unix(path) => path.to_string_lossy().to_string(),
+        }
+    }
+
+    fn connect_error(&self, reason: String) -> ClientError {
+        ClientError::Connect {
+            address: self.address(),
+            reason,
+        }
+    }
+
+    /// Posts a JSON request to the server and returns the HTTP status and
+    /// raw body of its response
+    pub fn post(&self, request_s: &str) -> Result<(u16, String)> {
+        match self {
+            Connection::Http(client, url) => {
+                let mut response = client
+                    .post(url.clone())
+                    .body(request_s.to_owned())
+                    .send()
+                    .map_err(|e| self.connect_error(root_cause(&e)))?;
+                let body = response.text().map_err(|e| {
+                    ClientError::Protocol(format!(
+                        "Cannot read the daemon's answer: {}",
+                        root_cause(&e)
+                    ))
+                })?;
+                Ok((response.status().as_u16(), body))
+            }
+            Connection::Unix(path) => unix_socket::transact(path, "POST", "/", request_s)
+                .map(|m| (m.status().unwrap_or_default(), m.body))
+                .map_err(|e| self.connect_error(e.to_string())),
+        }
+    }
+
+    /// Opens a streaming GET request, i.e. to /events, and returns the
+    /// response body once the server has answered with 200 OK
+    fn open_stream(&self, path_and_query: &str) -> Result<Box<dyn BufRead + Send>> {
+        let (status, mut body): (u16, Box<dyn BufRead + Send>) = match self {
+            Connection::Http(client, url) => {
+                let url = url
+                    .join(path_and_query)
+                    .map_err(|e| ClientError::Config(e.to_string()))?;
+                let response = client
+                    .get(url)
+                    .send()
+                    .map_err(|e| self.connect_error(root_cause(&e)))?;
+                (
+                    response.status().as_u16(),
+                    Box::new(BufReader::new(response)),
+                )
+            }
+            Connection::Unix(path) => {
+                let (head, body) = unix_socket::open_stream(path, path_and_query)
+                    .map_err(|e| self.connect_error(e.to_string()))?;
+                (head.status().unwrap_or_default(), Box::new(body))
+            }
+        };
+
+        if status == 200 {
+            return Ok(body);
+        }
+        let mut error = String::new();
+        body.read_to_string(&mut error)?;
+        match serde_json::from_str::<RestError>(&error) {
+            Ok(e) => Err(ClientError::api("Could not stream events", e.error)),
+            Err(_) => Err(ClientError::Protocol(format!(
+                "Cannot understand the daemon's answer (HTTP status {}): {}",
+                status,
+                error.trim()
+            ))),
+        }
+    }
+}
+
+/// Events sent by the daemon, see QmanagerClient::stream_events(). Ends when
+/// the connection is closed.
+pub struct EventStream {
+    body: Box<dyn BufRead + Send>,
+}
+
+impl Iterator for EventStream {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut line = String::new();
+            match self.body.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) if line.trim().is_empty() => continue, // heartbeat
+                Ok(_) => {
+                    return Some(serde_json::from_str(&line).map_err(|e| {
+                        ClientError::Protocol(format!("Cannot understand event: {}", e))
+                    }))
+                }
+                Err(e) => return Some(Err(ClientError::Io(e))),
+            }
+        }
+    }
+}
+
+/// Client for the qmanager daemon
+#[derive(Clone)]
+pub struct QmanagerClient {
+    connection: Connection,
+    dump_protocol: bool,
+}
+
+impl QmanagerClient {
+    /// Creates a client using the given connection
+    pub fn new(connection: Connection) -> QmanagerClient {
+        QmanagerClient {
+            connection,
+            dump_protocol: false,
+        }
+    }
+
+    /// Creates a client talking to the daemon's local Unix socket
+    pub fn unix<P: Into<PathBuf>>(path: P) -> QmanagerClient {
+        QmanagerClient::new(Connection::Unix(path.into()))
+    }
+
+    /// Creates a client talking to the daemon over TCP. With a CA
+    /// certificate (PEM), SSL/TLS is used, plain TCP otherwise.
+    pub fn tcp(host: &str, port: u16, ca: Option<&Path>) -> Result<QmanagerClient> {
+        QmanagerClient::tcp_with_token(host, port, ca, None)
+    }
+
+    /// Like tcp(), but sends a bearer token with every request, i.e. for
+    /// daemons behind a reverse proxy that authenticates clients
+    pub fn tcp_with_token(
+        host: &str,
+        port: u16,
+        ca: Option<&Path>,
+        token: Option<&str>,
+    ) -> Result<QmanagerClient> {
+        // the daemon's HTTPS listener stalls on connections kept alive
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        if let Some(token) = token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| ClientError::Config("Invalid token".to_string()))?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+        if let Some(ca) = ca {
+            let invalid_ca = |e: String| {
+                ClientError::Config(format!("Cannot use CA certificate {:?}: {}", ca, e))
+            };
+            let mut buf = Vec::new();
+            File::open(ca)
+                .and_then(|mut f| f.read_to_end(&mut buf))
+                .map_err(|e| invalid_ca(e.to_string()))?;
+            let pkcs12 =
+                reqwest::Certificate::from_pem(&buf).map_err(|e| invalid_ca(e.to_string()))?;
+            builder = builder
+                .add_root_certificate(pkcs12)
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| ClientError::Config(format!("Cannot set up HTTP client: {}", e)))?;
+
+        let scheme = if ca.is_some() { "https" } else { "http" };
+        let url = reqwest::Url::parse(&format!("{}://{}:{}/", scheme, host, port))
+            .map_err(|e| ClientError::Config(format!("Invalid host '{}': {}", host, e)))?;
+
+        Ok(QmanagerClient::new(Connection::Http(client, url)))
+    }
+
+    /// Dumps all requests and responses to stdout, for debugging
+    pub fn dump_protocol(mut self, dump_protocol: bool) -> QmanagerClient {
+        self.dump_protocol = dump_protocol;
+        self
+    }
+
+    /// The connection to the daemon
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Sends a request to the server, wrapped in a versioned envelope, and
+    /// returns the server's response. Requests the server does not support
+    /// and answers the client cannot decode yield errors.
+    pub fn request(&self, request: Request) -> Result<Response> {
+        let request_s = serde_json::to_string_pretty(&RequestEnvelope::new(request))
+            .map_err(io::Error::from)?;
+        if self.dump_protocol {
+            println!("Sent: {} ", request_s);
+        }
+
+        let (status, response_s) = self.connection.post(&request_s)?;
+        if self.dump_protocol {
+            println!("Received: {} ", response_s);
+        }
+
+        let envelope = match serde_json::from_str::<ResponseEnvelope>(&response_s) {
+            Ok(envelope) => envelope,
+            // i.e. from a proxy in front of the daemon
+            Err(_) if status == 401 || status == 403 => {
+                return Err(ClientError::api(
+                    "Access denied",
+                    ApiError::new(ErrorCode::Unauthorized, format!("HTTP status {}", status)),
+                ));
+            }
+            Err(_) => {
+                return Err(ClientError::Protocol(format!(
+                    "Cannot understand the daemon's answer (HTTP status {}), it may be too old for this client: {}",
+                    status,
+                    response_s.trim()
+                )));
+            }
+        };
+
+        match envelope.response {
+            Response::Unsupported(s) => Err(unsupported(s)),
+            response => Ok(response),
+        }
+    }
+
+    /// The daemon's protocol version, program version and features
+    pub fn server_info(&self) -> Result<ServerInfo> {
+        match self.request(Request::Hello)? {
+            Response::ServerInfo(info) => Ok(info),
+            Response::Error(e) => Err(ClientError::api("Could not get server information", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Fails unless the daemon supports all given features. Used before
+    /// sending requests whose new fields older daemons would silently ignore.
+    pub fn require_features(&self, features: &[&str]) -> Result<()> {
+        let info = self.server_info()?;
+        match features.iter().find(|f| !info.supports(f)) {
+            Some(feature) => Err(unsupported(format!(
+                "qmanager {} does not support '{}'",
+                info.daemon_version, feature
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Submits a job and returns its ID. Fails if the daemon does not
+    /// support the notification targets of the submission.
+    pub fn submit(&self, submission: Submission) -> Result<u64> {
+        // make sure the daemon does not silently ignore notification targets
+        let mut features = Vec::new();
+        if !submission.notify.is_empty() {
+            features.push("notify-targets");
+        }
+        if submission
+            .notify
+            .iter()
+            .any(|t| t.url.starts_with("mailto:"))
+        {
+            features.push("mail-notifications");
+        }
+        if !features.is_empty() {
+            self.require_features(&features)?;
+        }
+
+        match self.request(Request::SubmitJob(submission))? {
+            Response::SubmitJob(id) => Ok(id),
+            Response::Error(e) => Err(ClientError::api("Could not submit job", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Submits several jobs at once and returns their IDs in order. The
+    /// daemon queues either all of them or, if any is invalid, none.
+    pub fn submit_batch(&self, submissions: Vec<Submission>) -> Result<Vec<u64>> {
+        let mut features = vec!["submit-batch"];
+        let targets: Vec<&NotifyTarget> = submissions.iter().flat_map(|s| &s.notify).collect();
+        if !targets.is_empty() {
+            features.push("notify-targets");
+        }
+        if targets.iter().any(|t| t.url.starts_with("mailto:")) {
+            features.push("mail-notifications");
+        }
+        self.require_features(&features)?;
+
+        match self.request(Request::SubmitBatch(submissions))? {
+            Response::SubmitBatch(ids) => Ok(ids),
+            Response::Error(e) => Err(ClientError::api("Could not submit jobs", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// A single job, optionally with its stdout and stderr
+    pub fn get_job(&self, id: u64, stdout: bool, stderr: bool) -> Result<Job> {
+        match self.request(Request::GetJob { id, stdout, stderr })? {
+            Response::GetJob(job) => Ok(job),
+            Response::Error(e) => Err(ClientError::api(&format!("Could not get job {}", id), e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// A page of jobs selected by the filter
+    pub fn list(&self, filter: JobFilter) -> Result<JobPage> {
+        match self.request(Request::ListJobs(filter))? {
+            Response::JobPage(page) => Ok(page),
+            Response::Error(e) => Err(ClientError::api("Could not list jobs", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// All queued jobs in scheduling order, the running job first
+    pub fn queued_jobs(&self) -> Result<Vec<Job>> {
+        match self.request(Request::GetQueuedJobs)? {
+            Response::GetJobs(jobs) => Ok(jobs),
+            Response::Error(e) => Err(ClientError::api("Could not list queued jobs", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// All finished jobs, including their output. Unlike list(), this is
+    /// supported by all daemons.
+    pub fn finished_jobs(&self) -> Result<Vec<Job>> {
+        match self.request(Request::GetFinishedJobs)? {
+            Response::GetJobs(jobs) => Ok(jobs),
+            Response::Error(e) => Err(ClientError::api("Could not list finished jobs", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Asks a running job to terminate
+    pub fn kill(&self, id: u64) -> Result<()> {
+        match self.request(Request::KillJob(id))? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(ClientError::api(&format!("Could not kill job {}", id), e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Removes a queued or finished job and returns it
+    pub fn remove(&self, id: u64) -> Result<Job> {
+        match self.request(Request::RemoveJob(id))? {
+            Response::GetJob(job) => Ok(job),
+            Response::Error(e) => Err(ClientError::api(&format!("Could not remove job {}", id), e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Holds a queued job, so that it is skipped by the scheduler, and
+    /// returns it
+    pub fn hold(&self, id: u64) -> Result<Job> {
+        match self.request(Request::HoldJob(id))? {
+            Response::GetJob(job) => Ok(job),
+            Response::Error(e) => Err(ClientError::api(&format!("Could not hold job {}", id), e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Releases a held job and returns it
+    pub fn release(&self, id: u64) -> Result<Job> {
+        match self.request(Request::ReleaseJob(id))? {
+            Response::GetJob(job) => Ok(job),
+            Response::Error(e) => Err(ClientError::api(
+                &format!("Could not release job {}", id),
+                e,
+            )),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Moves a queued job to the given position among the waiting jobs,
+    /// 0 being the next one to run, and returns it
+    pub fn move_job(&self, id: u64, position: usize) -> Result<Job> {
+        match self.request(Request::MoveJob { id, position })? {
+            Response::GetJob(job) => Ok(job),
+            Response::Error(e) => Err(ClientError::api(&format!("Could not move job {}", id), e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// The current state of the queue
+    pub fn queue_state(&self) -> Result<QueueState> {
+        match self.request(Request::GetQueueState)? {
+            Response::QueueState(s) => Ok(s),
+            Response::Error(e) => Err(ClientError::api("Could not get queue status", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Sets the state of the queue and returns the resulting state. Note
+    /// that 'Stopped' cannot be set manually; set 'Stopping' and let the
+    /// queue decide to go into 'Stopped' mode.
+    pub fn set_state(&self, state: QueueState) -> Result<QueueState> {
+        match self.request(Request::SetQueueState(state))? {
+            Response::QueueState(s) => Ok(s),
+            Response::Error(e) => Err(ClientError::api("Could not set queue status", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Waits for a job to finish and returns it with its output. If the
+    /// timeout passes first, the job is returned in its current state.
+    pub fn wait(&self, id: u64, timeout: Option<Duration>) -> Result<Job> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        loop {
+            // each request must be answered before the HTTP client gives up
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let poll = remaining.map_or(WAIT_POLL_INTERVAL, |r| r.min(WAIT_POLL_INTERVAL));
+            let request = Request::WaitJob {
+                id,
+                timeout: (poll.as_millis() as u64).div_ceil(1000),
+            };
+
+            let job = match self.request(request)? {
+                Response::GetJob(job) => job,
+                Response::Error(e) => {
+                    return Err(ClientError::api(
+                        &format!("Could not wait for job {}", id),
+                        e,
+                    ))
+                }
+                response => return Err(ClientError::unexpected(response)),
+            };
+
+            if JobEvent::of_state(&job.state).is_terminal()
+                || remaining == Some(Duration::from_secs(0))
+            {
+                return Ok(job);
+            }
+        }
+    }
+
+    /// Undelivered job notifications
+    pub fn notifications(&self) -> Result<Vec<Notification>> {
+        match self.request(Request::GetNotifications)? {
+            Response::Notifications(n) => Ok(n),
+            Response::Error(e) => Err(ClientError::api("Could not get notifications", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Schedules the given notification, or all failed ones, for immediate
+    /// redelivery and returns them
+    pub fn retry_notifications(&self, id: Option<u64>) -> Result<Vec<Notification>> {
+        match self.request(Request::RetryNotifications(id))? {
+            Response::Notifications(n) => Ok(n),
+            Response::Error(e) => Err(ClientError::api("Could not retry notifications", e)),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Streams job and queue events as they happen, only those of the given
+    /// jobs if any. With a cursor, i.e. the ID of the last event received
+    /// before a reconnect, retained events after it are sent first.
+    pub fn stream_events(&self, jobs: &[u64], cursor: Option<u64>) -> Result<EventStream> {
+        self.require_features(&["events"])?;
+
+        let mut query = vec!["format=jsonl".to_string()];
+        if !jobs.is_empty() {
+            let jobs: Vec<String> = jobs.iter().map(|id| id.to_string()).collect();
+            query.push(format!("job={}", jobs.join(",")));
+        }
+        if let Some(cursor) = cursor {
+            query.push(format!("cursor={}", cursor));
+        }
+
+        let body = self
+            .connection
+            .open_stream(&format!("/events?{}", query.join("&")))?;
+        Ok(EventStream { body })
+    }
+}
+
+/// A request or feature the daemon does not support
+fn unsupported(message: String) -> ClientError {
+    ClientError::api(
+        "The daemon cannot handle the request",
+        ApiError::new(ErrorCode::UnsupportedRequest, message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use serde_json::Value;
+    use tiny_http;
+
+    use job_queue::JobState;
+    use protocol::PROTOCOL_VERSION;
+
+    /// Test certificate and key for 'localhost'
+    fn resource(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/test")
+            .join(name)
+    }
+
+    fn envelope(response: Response) -> String {
+        serde_json::to_string(&ResponseEnvelope::new(response.encode(PROTOCOL_VERSION))).unwrap()
+    }
+
+    fn server_info(features: &[&str]) -> Response {
+        Response::ServerInfo(ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            daemon_version: "0.8.4".to_string(),
+            features: features.iter().map(|f| f.to_string()).collect(),
+            appkeys: Vec::new(),
+        })
+    }
+
+    fn job(state: JobState) -> Job {
+        Job {
+            id: 4,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: None,
+            finished: None,
+            stderr: String::new(),
+            stdout: String::new(),
+            state,
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    /// Answers one connection to a Unix socket per given answer, in order,
+    /// and returns the requests received
+    fn fake_daemon(
+        name: &str,
+        answers: Vec<(u16, String)>,
+    ) -> (QmanagerClient, thread::JoinHandle<Vec<Value>>) {
+        let path = env::temp_dir().join(format!("qmanager-client-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = QmanagerClient::unix(&path);
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in answers {
+                let (stream, _) = listener.accept().unwrap();
+                let request = unix_socket::read_request(&stream).unwrap();
+                requests.push(serde_json::from_str(&request.body).unwrap());
+                unix_socket::write_response(&stream, status, "application/json", &body).unwrap();
+            }
+            let _ = fs::remove_file(&path);
+            requests
+        });
+        (client, handle)
+    }
+
+    #[test]
+    fn submits_after_checking_features() {
+        let (client, daemon) = fake_daemon(
+            "submit",
+            vec![
+                (200, envelope(server_info(&["notify-targets"]))),
+                (200, envelope(Response::SubmitJob(3))),
+            ],
+        );
+        let submission = Submission {
+            cmdline: "gwas --chr 1".to_string(),
+            notify: vec![NotifyTarget {
+                url: "https://example.org/hook".to_string(),
+                events: Vec::new(),
+            }],
+            tags: Vec::new(),
+        };
+        assert_eq!(client.submit(submission).unwrap(), 3);
+
+        let requests = daemon.join().unwrap();
+        assert_eq!(
+            requests[0],
+            json!({"version": PROTOCOL_VERSION, "request": "Hello"})
+        );
+        assert_eq!(
+            requests[1]["request"]["SubmitJob"]["cmdline"],
+            "gwas --chr 1"
+        );
+    }
+
+    #[test]
+    fn missing_features_are_unsupported() {
+        let (client, daemon) = fake_daemon("features", vec![(200, envelope(server_info(&[])))]);
+        match client.stream_events(&[], None) {
+            Err(ClientError::Api { error, .. }) => {
+                assert_eq!(error.code, ErrorCode::UnsupportedRequest)
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("events streamed from a daemon without the feature"),
+        }
+        assert_eq!(daemon.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = ApiError::new(ErrorCode::NoSuchJob, "No such job");
+        let (client, daemon) = fake_daemon(
+            "errors",
+            vec![
+                (404, envelope(Response::Error(error))),
+                (401, "<html>Unauthorized</html>".to_string()),
+                (200, "nonsense".to_string()),
+                (200, envelope(Response::SubmitJob(1))),
+            ],
+        );
+
+        match client.kill(4) {
+            Err(ClientError::Api { context, error }) => {
+                assert_eq!(context, "Could not kill job 4");
+                assert_eq!(error.code, ErrorCode::NoSuchJob);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match client.kill(4) {
+            Err(ClientError::Api { error, .. }) => assert_eq!(error.code, ErrorCode::Unauthorized),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(client.kill(4), Err(ClientError::Protocol(_))));
+        assert!(matches!(client.kill(4), Err(ClientError::Protocol(_))));
+        daemon.join().unwrap();
+
+        let client = QmanagerClient::unix("/nonexistent/qmanager.sock");
+        match client.kill(4) {
+            Err(ClientError::Connect { address, .. }) => {
+                assert_eq!(address, "/nonexistent/qmanager.sock")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn waits_until_finished() {
+        let (client, daemon) = fake_daemon(
+            "wait",
+            vec![
+                (200, envelope(Response::GetJob(job(JobState::Running)))),
+                (
+                    200,
+                    envelope(Response::GetJob(job(JobState::Terminated(0)))),
+                ),
+            ],
+        );
+        let job = client.wait(4, None).unwrap();
+        assert_eq!(job.state, JobState::Terminated(0));
+
+        let requests = daemon.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0]["request"],
+            json!({"WaitJob": {"id": 4, "timeout": WAIT_POLL_INTERVAL.as_secs()}})
+        );
+    }
+
+    #[test]
+    fn uses_tls_with_ca() {
+        let plain = QmanagerClient::tcp("localhost", 1337, None).unwrap();
+        match plain.connection() {
+            Connection::Http(_, url) => assert_eq!(url.as_str(), "http://localhost:1337/"),
+            Connection::Unix(_) => panic!("not a TCP connection"),
+        }
+
+        let e = QmanagerClient::tcp("localhost", 1337, Some(Path::new("/nonexistent.pem")));
+        assert!(matches!(e, Err(ClientError::Config(_))));
+
+        let ssl_config = tiny_http::SslConfig {
+            certificate: fs::read(resource("cert.pem")).unwrap(),
+            private_key: fs::read(resource("key.pem")).unwrap(),
+        };
+        let server = tiny_http::Server::https("127.0.0.1:0", ssl_config).unwrap();
+        let port = server.server_addr().port();
+        let daemon = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let response = tiny_http::Response::from_string(envelope(server_info(&[])));
+            request.respond(response).unwrap();
+        });
+
+        let client = QmanagerClient::tcp("127.0.0.1", port, Some(&resource("cert.pem"))).unwrap();
+        match client.connection() {
+            Connection::Http(_, url) => assert_eq!(url.scheme(), "https"),
+            Connection::Unix(_) => panic!("not a TCP connection"),
+        }
+        assert_eq!(client.server_info().unwrap().daemon_version, "0.8.4");
+        daemon.join().unwrap();
+    }
+}
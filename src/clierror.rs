@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * clierror.rs
+ *
+ * Errors of client commands. Each kind of error is reported with a message
+ * for humans and an exit status of its own, so that scripts and cron jobs can
+ * tell an unreachable daemon from a missing job or a rejected request. The
+ * exit statuses lie in the range 64 to 78 that sysexits.h sets aside for
+ * such errors. Note that 'wait' passes through any exit code of the job, so
+ * its exit status alone does not tell a job's failure from a client error.
+ **/
+use std::error;
+use std::fmt;
+use std::io;
+
+use protocol::ApiError;
+
+/// Exit status for invalid options or configuration files
+pub const EXIT_CONFIG: i32 = 64;
+
+/// Exit status if the daemon cannot be reached
+pub const EXIT_CONNECT: i32 = 74;
+
+/// Exit status if the daemon's answer cannot be understood
+pub const EXIT_PROTOCOL: i32 = 75;
+
+/// Exit status for any other error, i.e. while writing output
+pub const EXIT_IO: i32 = 77;
+
+/// Result of client commands
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Why a client command failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// The command line or configuration file is invalid
+    Config(String),
+
+    /// The daemon cannot be reached at the given address
+    Connect { address: String, reason: String },
+
+    /// The daemon has answered, but not with a response this client
+    /// understands or expects
+    Protocol(String),
+
+    /// The daemon has rejected the request. `context` describes what was
+    /// attempted.
+    Api { context: String, error: ApiError },
+
+    /// Any other I/O error, i.e. while writing output
+    Io(io::Error),
+}
+
+impl ClientError {
+    /// Reports an error response of the daemon
+    pub fn api(context: &str, error: ApiError) -> ClientError {
+        ClientError::Api {
+            context: context.to_string(),
+            error,
+        }
+    }
+
+    /// Reports a response that does not fit the request
+    pub fn unexpected<T: fmt::Debug>(response: T) -> ClientError {
+        ClientError::Protocol(format!("Unexpected response: {:?}", response))
+    }
+
+    /// Exit status of the client for this error. Errors reported by the
+    /// daemon have one exit status per error code.
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            ClientError::Config(_) => EXIT_CONFIG,
+            ClientError::Connect { .. } => EXIT_CONNECT,
+            ClientError::Protocol(_) => EXIT_PROTOCOL,
+            ClientError::Api { error, .. } => error.code.exit_status(),
+            ClientError::Io(_) => EXIT_IO,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Config(message) => write!(f, "{}", message),
+            ClientError::Connect { address, reason } => {
+                write!(f, "Cannot connect to {}: {}", address, reason)
+            }
+            ClientError::Protocol(message) => write!(f, "{}", message),
+            ClientError::Api { context, error } => write!(f, "{}: {}", context, error),
+            ClientError::Io(e) => write!(f, "Error: {}", e),
+        }
+    }
+}
+
+impl error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::ErrorCode;
+
+    #[test]
+    fn exit_statuses_and_messages() {
+        let errors = vec![
+            (
+                ClientError::Config("No appkeys configured".to_string()),
+                EXIT_CONFIG,
+                "No appkeys configured",
+            ),
+            (
+                ClientError::Connect {
+                    address: "localhost:1337".to_string(),
+                    reason: "Connection refused".to_string(),
+                },
+                EXIT_CONNECT,
+                "Cannot connect to localhost:1337: Connection refused",
+            ),
+            (
+                ClientError::Protocol("Unexpected response: Ok".to_string()),
+                EXIT_PROTOCOL,
+                "Unexpected response: Ok",
+            ),
+            (
+                ClientError::api(
+                    "Could not kill job 4",
+                    ApiError::new(ErrorCode::NoSuchJob, "No such job"),
+                ),
+                65,
+                "Could not kill job 4: No such job",
+            ),
+            (
+                ClientError::api(
+                    "Could not remove job 4",
+                    ApiError::new(ErrorCode::WrongJobState, "Job is running"),
+                ),
+                67,
+                "Could not remove job 4: Job is running",
+            ),
+            (
+                ClientError::from(io::Error::new(io::ErrorKind::BrokenPipe, "Broken pipe")),
+                EXIT_IO,
+                "Error: Broken pipe",
+            ),
+        ];
+        for (error, status, message) in errors {
+            assert_eq!(error.exit_status(), status, "{:?}", error);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
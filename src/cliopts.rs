@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -20,13 +20,20 @@
  * SOFTWARE.
  **/
 
-
-use std::io::{ErrorKind, Result};
+use std::env;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use cli::{ConfigCommand, HookOptions, ListOptions, ListenAddress, Opt, OptCommand};
 use config::Config;
+use profiles::Profile;
+use qmanager::clierror::{ClientError, Result};
+use qmanager::hooks;
+use qmanager::job_queue::NotifyTarget;
+use qmanager::listing::JobFilter;
+use qmanager::notify::{self, NotifyMethod};
 use std::collections::HashMap;
-use structopt::StructOpt;
 
 /// Default port for use with both daemon and client code
 pub const DEFAULT_PORT: u16 = 1337;
@@ -38,115 +45,222 @@ pub const DEFAULT_HOST: &str = "localhost";
 /// Default program state file to be used by the daemon.
 pub const DEFAULT_STATE: &str = "/var/lib/qmanager/qmanager.state";
 
-#[derive(Debug, StructOpt)]
-#[structopt(name=crate_name!(), version=crate_version!(), author=crate_authors!(), about=crate_description!())]
-pub struct Opt {
-    /// Set CA certificate
-    #[structopt(long, parse(from_os_str))]
-    pub ca: Option<PathBuf>,
+/// Default system-wide configuration file, shared by daemon and clients
+pub const DEFAULT_CONFIG: &str = "/etc/qmanager.conf";
 
-    /// Use plain TCP instead of SSL/TLS
-    #[structopt(long)]
-    pub insecure: bool,
+/// Prefix of environment variables overriding config file keys
+const ENV_PREFIX: &str = "QMANAGER_";
 
-    /// For clients, the host name to connect to. For servers ignored (default: localhost)
-    #[structopt(long, default_value = "")]
-    pub host: String,
+/// Environment variables read as command-line options, not as config keys
+const ENV_OPTIONS: &[&str] = &[
+    "QMANAGER_CONFIG",
+    "QMANAGER_CLIENT_CONFIG",
+    "QMANAGER_PROFILE",
+];
 
-    /// For clients, the port to connect to. For servers, the port to listen on (default: 1337)
-    #[structopt(long, default_value = "0")]
-    pub port: u16,
+/// Config keys holding a list, given as comma-separated environment values
+const ENV_LISTS: &[&str] = &["listen"];
 
-    #[structopt(long)]
-    /// Dump client requests and responses to stdout
-    pub dump_json: bool,
+/// Collects the config keys given as environment variables. The variable
+/// name after the prefix is the key, with '__' separating the keys of
+/// nested tables, i.e. QMANAGER_STATE_FILE sets 'state-file' and
+/// QMANAGER_APPKEYS__GWAS sets 'appkeys.gwas'.
+pub fn environment_overrides() -> Vec<(String, config::Value)> {
+    overrides_from(env::vars())
+}
 
-    #[structopt(long, default_value = "")]
-    /// The log level (default: Info, possible: Error, Warn, Info, Debug)
-    pub loglevel: String,
+/// Collects the config keys given by the variables of an environment
+fn overrides_from<I>(vars: I) -> Vec<(String, config::Value)>
+where
+    I: Iterator<Item = (String, String)>,
+{
+    let mut overrides: Vec<(String, config::Value)> = vars
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && !ENV_OPTIONS.contains(&name.as_str()))
+        .map(|(name, value)| {
+            let mut path: Vec<String> = name[ENV_PREFIX.len()..]
+                .to_lowercase()
+                .split("__")
+                .map(String::from)
+                .collect();
+            // top-level keys are hyphenated, table keys are taken as they are
+            path[0] = path[0].replace('_', "-");
+            let key = path.join(".");
+
+            let value = if ENV_LISTS.contains(&key.as_str()) {
+                config::Value::from(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(String::from)
+                        .collect::<Vec<String>>(),
+                )
+            } else {
+                config::Value::from(value)
+            };
+            (key, value)
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.0.cmp(&b.0));
+    overrides
+}
 
-    #[structopt(long, parse(from_os_str), default_value = "/etc/qmanager.conf")]
-    /// Path to configuration file
-    pub config: PathBuf,
+/// Sets the config keys given as environment variables, replacing those of
+/// the config file
+pub fn apply_environment(conf: &mut Config, overrides: &[(String, config::Value)]) -> Result<()> {
+    for (key, value) in overrides {
+        conf.set(key, value.clone()).map_err(|e| {
+            ClientError::Config(format!(
+                "Invalid environment variable {}{}: {}",
+                ENV_PREFIX,
+                key.to_uppercase().replace('-', "_").replace('.', "__"),
+                e
+            ))
+        })?;
+    }
+    Ok(())
+}
 
-    #[structopt(skip)]
-    /// Application keys
-    pub appkeys: HashMap<String, PathBuf>,
+impl HookOptions {
+    /// Reads the 'pre', 'post' and 'timeout' keys of a config table
+    fn from_table(mut table: HashMap<String, config::Value>) -> HookOptions {
+        HookOptions {
+            pre: table
+                .remove("pre")
+                .and_then(|v| v.into_str().ok())
+                .map(PathBuf::from),
+            post: table
+                .remove("post")
+                .and_then(|v| v.into_str().ok())
+                .map(PathBuf::from),
+            timeout: table.remove("timeout").and_then(|v| v.into_str().ok()),
+        }
+    }
+}
 
-    #[structopt(subcommand)]
-    pub cmd: OptCommand,
+impl ListOptions {
+    /// The filter to list jobs with, or None if no option was given
+    pub fn filter(self) -> Option<JobFilter> {
+        let filter = JobFilter {
+            states: self.state,
+            appkey: self.appkey,
+            owner: self.owner,
+            tags: self.tag,
+            scheduled_after: self.since,
+            scheduled_before: self.until,
+            finished_after: self.finished_since,
+            finished_before: self.finished_until,
+            sort: self.sort.unwrap_or_default(),
+            descending: self.desc,
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit,
+            cursor: self.cursor,
+            with_output: self.with_output,
+        };
 
-    #[structopt(long, parse(from_os_str))]
-    pub state_file: Option<PathBuf>,
+        let unfiltered = filter.states.is_empty()
+            && filter.appkey.is_none()
+            && filter.owner.is_none()
+            && filter.tags.is_empty()
+            && filter.scheduled_after.is_none()
+            && filter.scheduled_before.is_none()
+            && filter.finished_after.is_none()
+            && filter.finished_before.is_none()
+            && self.sort.is_none()
+            && !filter.descending
+            && self.offset.is_none()
+            && filter.limit.is_none()
+            && filter.cursor.is_none()
+            && !filter.with_output;
+
+        if unfiltered {
+            None
+        } else {
+            Some(filter)
+        }
+    }
 }
 
-#[derive(Debug, StructOpt)]
-pub enum OptCommand {
-    /// Starts the qmanager daemon
-    Daemon {
-        /// Stays in foreground, does not detach. Pidfile argument is ignored
-        #[structopt(long)]
-        foreground: bool,
-
-        /// Certificate file for SSL/TLS operation
-        #[structopt(long, parse(from_os_str))]
-        cert: Option<PathBuf>,
-
-        /// Key for SSL/TLS certificate
-        #[structopt(long, parse(from_os_str))]
-        key: Option<PathBuf>,
-
-        /// PID file location
-        #[structopt(long, parse(from_os_str))]
-        pidfile: Option<PathBuf>,
-
-        /// Notify URL
-        #[structopt(long)]
-        notify_url: Option<String>,
-    },
-
-    /// Requests the queue to be stopped
-    Stop {},
-
-    /// Requests queue operations to be resumed
-    Start {},
-
-    /// Requests queue status
-    Status {},
-
-    /// Submits a job to the queue
-    Submit {
-        #[structopt(name = "CMDLINE", parse(from_str))]
-        cmdline: String,
-    },
-
-    /// Removes a finished job from the queue
-    Remove {
-        /// Job ID to remove from the 'finished' queue
-        #[structopt(long)]
-        job_id: u64,
-    },
-
-    /// Asks a running job to terminate
-    Kill {
-        /// Job ID to terminate
-        #[structopt(long)]
-        job_id: u64,
-    },
-
-    /// Removes finished jobs from the queue based on timestamps
-    Cleanup {
-        /// Maximum age of a job's 'finished' timestamp, i.e. '8 days 3 seconds'
-        #[structopt(long)]
-        max_age: humantime::Duration,
-    },
+impl OptCommand {
+    /// The daemon command without any command-line options, as used to
+    /// check the daemon's configuration
+    pub fn daemon() -> OptCommand {
+        OptCommand::Daemon {
+            foreground: false,
+            cert: None,
+            key: None,
+            pidfile: None,
+            notify_url: None,
+            notify_method: None,
+            notify_secret: None,
+            notify_retries: None,
+            sendmail: None,
+            smtp_relay: None,
+            mail_from: None,
+            socket_mode: None,
+            socket_group: None,
+            audit_log: None,
+        }
+    }
 }
 
 impl Opt {
-    /// Merges a config file with the command-line options.
-    /// CLI options generally take precedence over options imported from
-    /// the config file.
-    pub fn merge_config(&mut self, conf: Config) {
+    /// Merges a config file and, for clients, the connection settings of a
+    /// server profile or the config file with the command-line options. CLI
+    /// options generally take precedence over options imported from the
+    /// profile or config file.
+    pub fn merge_config(&mut self, conf: Config, profile: Profile) -> Result<()> {
+        let merged = if self.is_daemon() {
+            self.merge_daemon_config(&conf)
+        } else {
+            self.merge_client_config(profile);
+            Ok(())
+        };
+
+        // "dump-json" debug flag
+        if !self.dump_json {
+            self.dump_json = conf.get_bool("dump-json").unwrap_or(false);
+        }
+
+        // set log level
+        if self.loglevel.is_empty() {
+            self.loglevel = conf
+                .get_str("loglevel")
+                .unwrap_or_else(|_| "Info".to_owned());
+        }
+        merged
+    }
+
+    /// Merges the connection settings of a server profile (client only)
+    fn merge_client_config(&mut self, profile: Profile) {
+        // if --insecure is not present on the CL, check the profile for CA
+        if !self.insecure {
+            if self.ca.is_none() {
+                self.ca = profile.ca;
+            }
+            self.insecure |= profile.insecure;
+        }
+
+        // An explicitly given host or port takes precedence over the local
+        // socket
+        let tcp_requested = self.port != 0 || !self.host.is_empty();
+        if self.socket.is_none() && !tcp_requested {
+            self.socket = profile.socket;
+        }
+
+        if self.port == 0 {
+            self.port = profile.port.unwrap_or(DEFAULT_PORT);
+        }
+
+        if self.host.is_empty() {
+            self.host = profile.host.unwrap_or_else(|| DEFAULT_HOST.to_string());
+        }
+
+        self.token = profile.token;
+    }
+
+    /// Merges the daemon's settings of a config file (daemon only)
+    fn merge_daemon_config(&mut self, conf: &Config) -> Result<()> {
         // if --insecure is not present on the CL, check config for CA.
         // Certs and keys will be checked when destructuring the self.cmd.
         if !self.insecure {
@@ -156,27 +270,19 @@ impl Opt {
             self.insecure |= conf.get_bool("insecure").unwrap_or(false);
         }
 
-        // TCP port for connecting (client) or listening (daemon)
-        self.port = if self.port == 0 {
-            conf.get_int("port")
-                .unwrap_or_else(|_| i64::from(DEFAULT_PORT)) as u16
-        } else {
-            self.port
-        };
-
-        // Host name for client connection (client only)
-        if self.host.is_empty() {
-            self.host = conf
-                .get_str("host")
-                .unwrap_or_else(|_| DEFAULT_HOST.to_string());
+        // Additional local socket to listen on
+        if self.socket.is_none() {
+            self.socket = conf.get_str("socket").ok().map(PathBuf::from);
         }
 
-        // "dump-json" debug flag
-        if !self.dump_json {
-            self.dump_json = conf.get_bool("dump-json").unwrap_or(false);
+        // TCP port to listen on
+        if self.port == 0 {
+            self.port = conf
+                .get_int("port")
+                .unwrap_or_else(|_| i64::from(DEFAULT_PORT)) as u16;
         }
 
-        // state file location (daemon only)
+        // state file location
         if self.state_file.is_none() {
             self.state_file = Some(PathBuf::from(
                 conf.get_str("state-file")
@@ -190,9 +296,42 @@ impl Opt {
             ref mut key,
             ref mut pidfile,
             ref mut notify_url,
+            ref mut notify_method,
+            ref mut notify_secret,
+            ref mut notify_retries,
+            ref mut socket_mode,
+            ref mut socket_group,
+            ref mut audit_log,
+            ref mut sendmail,
+            ref mut smtp_relay,
+            ref mut mail_from,
             ..
         } = &mut self.cmd
         {
+            if sendmail.is_none() {
+                *sendmail = conf.get_str("sendmail").ok().map(PathBuf::from);
+            }
+
+            if smtp_relay.is_none() {
+                *smtp_relay = conf.get_str("smtp-relay").ok();
+            }
+
+            if mail_from.is_none() {
+                *mail_from = conf.get_str("mail-from").ok();
+            }
+
+            if audit_log.is_none() {
+                *audit_log = conf.get_str("audit-log").ok().map(PathBuf::from);
+            }
+
+            if socket_mode.is_none() {
+                *socket_mode = conf.get_str("socket-mode").ok();
+            }
+
+            if socket_group.is_none() {
+                *socket_group = conf.get_str("socket-group").ok();
+            }
+
             if cert.is_none() {
                 *cert = conf.get_str("cert").ok().map(PathBuf::from);
             }
@@ -208,55 +347,278 @@ impl Opt {
             if notify_url.is_none() {
                 *notify_url = conf.get_str("notify-url").ok();
             }
+
+            if notify_method.is_none() {
+                *notify_method = conf.get_str("notify-method").ok();
+            }
+
+            if notify_secret.is_none() {
+                *notify_secret = conf.get_str("notify-secret").ok();
+            }
+
+            if notify_retries.is_none() {
+                *notify_retries = conf.get_int("notify-retries").ok().map(|n| n as u32);
+            }
         }
 
-        let appkeys = conf
-            .get_table("appkeys")
-            .expect("Could not load appkeys from config file!");
-        for (k, v) in appkeys {
-            self.appkeys.insert(k, PathBuf::from(v.into_str().unwrap()));
+        // listening addresses. Entries are either plain address strings or
+        // tables with 'address' and 'tls' keys.
+        for entry in conf.get_array("listen").unwrap_or_default() {
+            let listen = match entry.clone().into_table() {
+                Ok(mut t) => ListenAddress {
+                    address: t
+                        .remove("address")
+                        .and_then(|a| a.into_str().ok())
+                        .unwrap_or_default(),
+                    tls: t.remove("tls").and_then(|t| t.into_bool().ok()),
+                },
+                Err(_) => ListenAddress {
+                    address: entry.into_str().unwrap_or_default(),
+                    tls: None,
+                },
+            };
+            self.listen.push(listen);
         }
 
-        // set log level
-        if self.loglevel.is_empty() {
-            self.loglevel = conf
-                .get_str("loglevel")
-                .unwrap_or_else(|_| "Info".to_owned());
+        // pre and post hooks
+        if let Ok(table) = conf.get_table("hooks") {
+            self.hooks = HookOptions::from_table(table);
         }
+        for (appkey, table) in conf.get_table("appkey-hooks").unwrap_or_default() {
+            if let Ok(table) = table.into_table() {
+                self.appkey_hooks
+                    .insert(appkey, HookOptions::from_table(table));
+            }
+        }
+
+        let appkeys = conf.get_table("appkeys").map_err(|_| {
+            ClientError::Config("The daemon needs an [appkeys] table in the config file".into())
+        })?;
+        for (k, v) in appkeys {
+            let path = v.into_str().map_err(|e| {
+                ClientError::Config(format!("Invalid path for appkey '{}': {}", k, e))
+            })?;
+            self.appkeys.insert(k, PathBuf::from(path));
+        }
+        Ok(())
+    }
+
+    /// Whether the daemon is to be started
+    pub fn is_daemon(&self) -> bool {
+        matches!(self.cmd, OptCommand::Daemon { .. })
+    }
+
+    /// Whether the configuration is to be checked
+    pub fn is_config_check(&self) -> bool {
+        matches!(
+            self.cmd,
+            OptCommand::Config {
+                cmd: ConfigCommand::Check {}
+            }
+        )
+    }
+
+    /// Whether the daemons of all server profiles are queried instead of a
+    /// single one
+    pub fn is_federated(&self) -> bool {
+        matches!(
+            self.cmd,
+            OptCommand::Find { .. }
+                | OptCommand::Status {
+                    all_profiles: true,
+                    ..
+                }
+        )
     }
 
     /// Checks general validity of the option occurrences
     pub fn verify(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(ClientError::Config(problem)),
+            None => Ok(()),
+        }
+    }
+
+    /// Lists all invalid option occurrences
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if log::LevelFilter::from_str(&self.loglevel).is_err() {
+            problems.push(format!(
+                "Invalid log level '{}', expected Error, Warn, Info or Debug!",
+                self.loglevel
+            ));
+        }
+
+        if let OptCommand::Daemon {
+            notify_method: Some(method),
+            ..
+        } = &self.cmd
+        {
+            if let Err(e) = method.parse::<NotifyMethod>() {
+                problems.push(format!("{}!", e));
+            }
+        }
+
+        if let OptCommand::Daemon {
+            notify_url: Some(url),
+            ..
+        } = &self.cmd
+        {
+            let target = NotifyTarget {
+                url: url.clone(),
+                events: Vec::new(),
+            };
+            if let Err(e) = notify::validate_target(&target) {
+                problems.push(format!("{}!", e));
+            }
+        }
+
+        if let OptCommand::Daemon {
+            socket_mode: Some(mode),
+            ..
+        } = &self.cmd
+        {
+            if u32::from_str_radix(mode, 8).is_err() {
+                problems.push(format!(
+                    "Invalid socket mode '{}', expected an octal number!",
+                    mode
+                ));
+            }
+        }
+
+        let hook_timeouts = self
+            .appkey_hooks
+            .values()
+            .chain(Some(&self.hooks))
+            .filter_map(|h| h.timeout.as_ref());
+        for timeout in hook_timeouts {
+            if let Err(e) = hooks::parse_timeout(timeout) {
+                problems.push(format!("{}, expected seconds or i.e. '5min'!", e));
+            }
+        }
+
+        for listen in &self.listen {
+            if listen.address.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "Invalid listen address '{}', expected i.e. '127.0.0.1:1337'!",
+                    listen.address
+                ));
+            }
+            if self.insecure && listen.tls == Some(true) {
+                problems.push(format!(
+                    "You cannot specify --insecure in combination with SSL/TLS on {}!",
+                    listen.address
+                ));
+            }
+        }
+
+        // local clients and clients of all server profiles do not need any
+        // SSL/TLS settings
+        if !self.is_daemon() && (self.socket.is_some() || self.is_federated()) {
+            return problems;
+        }
+
         // it does not make sense to specify --insecure AND any SSL-related stuff
         if self.insecure {
             if self.ca.is_some() {
-                eprintln!("You cannot specify both --insecure and --ca!");
-                return Err(std::io::Error::from(ErrorKind::InvalidInput));
+                problems.push("You cannot specify both --insecure and --ca!".to_string());
             }
             if let OptCommand::Daemon { cert, key, .. } = &self.cmd {
                 if cert.is_some() || key.is_some() {
-                    eprintln!(
+                    problems.push(
                         "You cannot specify --insecure in combination with --cert and --key!"
+                            .to_string(),
                     );
-                    return Err(std::io::Error::from(ErrorKind::InvalidInput));
                 }
             }
         } else {
             if self.ca.is_none() {
-                eprintln!("You need to specify either --ca or --insecure!");
-                return Err(std::io::Error::from(ErrorKind::InvalidInput));
+                problems.push("You need to specify either --ca or --insecure!".to_string());
             }
             if let OptCommand::Daemon { cert, key, .. } = &self.cmd {
                 if cert.is_none() || key.is_none() {
-                    eprintln!(
+                    problems.push(
                         "You cannot use daemon mode without specifying both --cert and --key!"
+                            .to_string(),
                     );
-                    return Err(std::io::Error::from(ErrorKind::InvalidInput));
                 }
             }
         }
 
-        // PathBuf validity is checked when the path is actually opened later, no need to check here.
-        Ok(())
+        // PathBuf validity is checked when the path is actually opened later,
+        // or by 'config check'
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn overrides(vars: &[(&str, &str)]) -> Vec<(String, config::Value)> {
+        overrides_from(vars.iter().map(|&(k, v)| (k.to_string(), v.to_string())))
+    }
+
+    #[test]
+    fn environment_keys() {
+        let overrides = overrides(&[
+            ("QMANAGER_STATE_FILE", "/var/lib/q.state"),
+            ("QMANAGER_APPKEYS__GWAS_CHR", "/opt/gwas"),
+            ("QMANAGER_HOOKS__PRE", "/opt/pre"),
+            ("QMANAGER_LISTEN", "0.0.0.0:1337, [::]:1338,"),
+            ("QMANAGER_CONFIG", "/etc/other.conf"),
+            ("HOME", "/root"),
+        ]);
+        let keys: Vec<&str> = overrides.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["appkeys.gwas_chr", "hooks.pre", "listen", "state-file"]
+        );
+
+        let mut conf = Config::default();
+        apply_environment(&mut conf, &overrides).unwrap();
+        assert_eq!(conf.get_str("state-file").unwrap(), "/var/lib/q.state");
+        assert_eq!(conf.get_str("appkeys.gwas_chr").unwrap(), "/opt/gwas");
+        assert_eq!(
+            conf.get::<Vec<String>>("listen").unwrap(),
+            vec!["0.0.0.0:1337", "[::]:1338"]
+        );
+    }
+
+    #[test]
+    fn environment_host_replaces_profile_socket() {
+        let profile = Profile {
+            socket: Some(PathBuf::from("/run/qmanager.sock")),
+            ..Profile::default()
+        };
+        let mut env_config = Config::default();
+        apply_environment(
+            &mut env_config,
+            &overrides(&[("QMANAGER_HOST", "q.example.org")]),
+        )
+        .unwrap();
+
+        let mut opt = Opt::from_iter_safe(vec!["qmanager", "status"]).unwrap();
+        let mut overridden = profile.clone();
+        overridden.override_with(&env_config);
+        opt.merge_config(env_config, overridden).unwrap();
+        assert_eq!(opt.socket, None);
+        assert_eq!(opt.host, "q.example.org");
+
+        // the command line still wins over the environment
+        let mut env_config = Config::default();
+        apply_environment(
+            &mut env_config,
+            &overrides(&[("QMANAGER_HOST", "q.example.org")]),
+        )
+        .unwrap();
+        let mut opt =
+            Opt::from_iter_safe(vec!["qmanager", "--socket", "/tmp/q.sock", "status"]).unwrap();
+        let mut overridden = profile;
+        overridden.override_with(&env_config);
+        opt.merge_config(env_config, overridden).unwrap();
+        assert_eq!(opt.socket, Some(PathBuf::from("/tmp/q.sock")));
     }
 }
@@ -0,0 +1,310 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * completions.rs
+ *
+ * Shell completion scripts for bash, zsh and fish as generated by clap,
+ * extended to complete job IDs and appkeys. Those are asked from the daemon
+ * with the hidden 'complete' subcommand whenever the shell completes them,
+ * using the default connection settings or server profile.
+ **/
+use std::io;
+
+use clap::Shell;
+use qmanager::client::QmanagerClient;
+use qmanager::clierror::Result;
+use qmanager::listing::JobFilter;
+use structopt::StructOpt;
+
+use cli::Opt;
+
+/// Manual page generated by the build script
+const MANPAGE: &str = include_str!(concat!(env!("OUT_DIR"), "/qmanager.1"));
+
+/// Options of 'submit' taking a value. The appkey is the first word after
+/// 'submit' that is neither an option nor the value of one of these.
+const SUBMIT_VALUE_OPTIONS: &[&str] = &[
+    "--from",
+    "--notify",
+    "--notify-on",
+    "--tag",
+    "--timeout",
+    "-o",
+    "--output",
+];
+
+/// Replaces the completion function generated for bash
+const BASH_DYNAMIC: &str = r#"
+_qmanager_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "${prev}" == "--job-id" ]]; then
+        COMPREPLY=( $(compgen -W "$(qmanager complete job-ids 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+
+    local i submit=0 positional=0
+    for (( i = 1; i < COMP_CWORD; i++ )); do
+        if (( submit )); then
+            case "${COMP_WORDS[i]}" in
+                @SUBMIT_VALUE_OPTIONS@) (( ++i >= COMP_CWORD )) && positional=1 ;;
+                -*) ;;
+                *) positional=1 ;;
+            esac
+        elif [[ "${COMP_WORDS[i]}" == "submit" ]]; then
+            submit=1
+        fi
+    done
+    if (( submit && !positional )) && [[ "${cur}" != -* ]]; then
+        COMPREPLY=( $(compgen -W "$(qmanager complete appkeys 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+
+    _qmanager
+}
+
+complete -F _qmanager_dynamic -o bashdefault -o default qmanager
+"#;
+
+/// Completion functions referred to by the zsh argument specs
+const ZSH_DYNAMIC: &str = r#"(( $+functions[_qmanager_job_ids] )) ||
+_qmanager_job_ids() {
+    local ids; ids=(${(f)"$(qmanager complete job-ids 2>/dev/null)"})
+    _describe -t job-ids 'job ID' ids
+}
+(( $+functions[_qmanager_appkeys] )) ||
+_qmanager_appkeys() {
+    local appkeys; appkeys=(${(f)"$(qmanager complete appkeys 2>/dev/null)"})
+    _describe -t appkeys 'appkey' appkeys
+}
+
+"#;
+
+/// Completes the appkey as first word after 'submit' in fish
+const FISH_DYNAMIC: &str = r#"
+function __qmanager_needs_appkey
+    set -l submit 0
+    set -l skip 0
+    for token in (commandline -opc)[2..-1]
+        if test $skip -eq 1
+            set skip 0
+        else if test $submit -eq 0
+            test "$token" = submit; and set submit 1
+        else
+            switch $token
+                case @SUBMIT_VALUE_OPTIONS@
+                    set skip 1
+                case '-*'
+                case '*'
+                    return 1
+            end
+        end
+    end
+    test $submit -eq 1 -a $skip -eq 0
+end
+complete -c qmanager -n __qmanager_needs_appkey -f -a "(qmanager complete appkeys 2>/dev/null)"
+"#;
+
+/// Prints the completion script for the given shell
+pub fn print_script(shell: Shell) {
+    print!("{}", script(shell));
+}
+
+/// The completion script for the given shell
+fn script(shell: Shell) -> String {
+    let mut script = Vec::new();
+    Opt::clap().gen_completions_to(crate_name!(), shell, &mut script);
+    let script = String::from_utf8(script).unwrap();
+
+    match shell {
+        Shell::Bash => {
+            script
+                + &BASH_DYNAMIC.replace("@SUBMIT_VALUE_OPTIONS@", &SUBMIT_VALUE_OPTIONS.join("|"))
+        }
+        Shell::Zsh => {
+            let mut lines: Vec<String> = script
+                .lines()
+                .map(|line| {
+                    if line.contains("--job-id=[") && line.ends_with("]' \\") {
+                        line.replace("]' \\", "]:job ID:_qmanager_job_ids' \\")
+                    } else if line == "':CMDLINE:_files' \\" {
+                        "':CMDLINE:_qmanager_appkeys' \\".to_string()
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect();
+            // the functions must be defined before the script calls _qmanager
+            let last = lines.pop().unwrap_or_default();
+            lines.push(ZSH_DYNAMIC.to_string() + &last);
+            lines.join("\n") + "\n"
+        }
+        _ => {
+            let script = script.replace(
+                " -l job-id",
+                " -l job-id -x -a \"(qmanager complete job-ids 2>/dev/null)\"",
+            );
+            script
+                + &FISH_DYNAMIC.replace("@SUBMIT_VALUE_OPTIONS@", &SUBMIT_VALUE_OPTIONS.join(" "))
+        }
+    }
+}
+
+/// Prints the IDs of all jobs or the daemon's appkeys, one per line
+pub fn handle_complete(client: &QmanagerClient, what: &str) -> Result<()> {
+    for word in words(client, what)? {
+        println!("{}", word);
+    }
+    Ok(())
+}
+
+/// The IDs of all jobs or the daemon's appkeys
+fn words(client: &QmanagerClient, what: &str) -> Result<Vec<String>> {
+    if what == "appkeys" {
+        Ok(client.server_info()?.appkeys)
+    } else {
+        let page = client.list(JobFilter::default())?;
+        Ok(page.jobs.iter().map(|job| job.id.to_string()).collect())
+    }
+}
+
+/// Prints the manual page
+pub fn print_manpage() -> io::Result<()> {
+    use std::io::Write;
+    io::stdout().write_all(MANPAGE.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use qmanager::job_queue::{Job, JobState};
+    use qmanager::listing::JobPage;
+    use qmanager::protocol::{Response, ResponseEnvelope, ServerInfo, PROTOCOL_VERSION};
+    use qmanager::unix_socket;
+
+    fn job(id: u64) -> Job {
+        Job {
+            id,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: None,
+            finished: None,
+            stderr: String::new(),
+            stdout: String::new(),
+            state: JobState::Queued,
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    /// Answers a single request on a Unix socket
+    fn fake_daemon(name: &str, response: Response) -> (QmanagerClient, thread::JoinHandle<()>) {
+        let path =
+            env::temp_dir().join(format!("qmanager-complete-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = QmanagerClient::unix(&path);
+        let body = serde_json::to_string(&ResponseEnvelope::new(response.encode(PROTOCOL_VERSION)))
+            .unwrap();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            unix_socket::read_request(&stream).unwrap();
+            unix_socket::write_response(&stream, 200, "application/json", &body).unwrap();
+            let _ = fs::remove_file(&path);
+        });
+        (client, handle)
+    }
+
+    #[test]
+    fn completes_appkeys() {
+        let (client, daemon) = fake_daemon(
+            "appkeys",
+            Response::ServerInfo(ServerInfo {
+                protocol_version: PROTOCOL_VERSION,
+                daemon_version: "0.8.4".to_string(),
+                features: Vec::new(),
+                appkeys: vec!["gwas".to_string(), "imputation".to_string()],
+            }),
+        );
+        assert_eq!(
+            words(&client, "appkeys").unwrap(),
+            vec!["gwas", "imputation"]
+        );
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn completes_job_ids() {
+        let (client, daemon) = fake_daemon(
+            "job-ids",
+            Response::JobPage(JobPage {
+                jobs: vec![job(3), job(12)],
+                total: 2,
+                next_cursor: None,
+            }),
+        );
+        assert_eq!(words(&client, "job-ids").unwrap(), vec!["3", "12"]);
+        daemon.join().unwrap();
+
+        assert!(Opt::from_iter_safe(vec!["qmanager", "complete", "job-ids"]).is_ok());
+        assert!(Opt::from_iter_safe(vec!["qmanager", "complete", "jobs"]).is_err());
+    }
+
+    #[test]
+    fn scripts_complete_dynamically() {
+        for shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = script(*shell);
+            assert!(script.contains("qmanager complete appkeys"), "{}", shell);
+            assert!(script.contains("qmanager complete job-ids"), "{}", shell);
+            assert!(!script.contains("@SUBMIT_VALUE_OPTIONS@"), "{}", shell);
+        }
+
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("--from|--notify|--notify-on|--tag|--timeout|-o|--output)"));
+        assert!(
+            bash.ends_with("complete -F _qmanager_dynamic -o bashdefault -o default qmanager\n")
+        );
+
+        // the generated argument specs refer to the completion functions,
+        // which are defined before _qmanager is called
+        let zsh = script(Shell::Zsh);
+        assert!(zsh.contains("]:job ID:_qmanager_job_ids' \\\n"));
+        assert!(zsh.contains("\n':CMDLINE:_qmanager_appkeys' \\\n"));
+        assert!(!zsh.contains(":CMDLINE:_files"));
+        assert!(zsh.ends_with(&format!("{}_qmanager \"$@\"\n", ZSH_DYNAMIC)));
+
+        let fish = script(Shell::Fish);
+        assert!(fish.contains(" -l job-id -x -a \"(qmanager complete job-ids 2>/dev/null)\""));
+        assert!(fish.contains("case --from --notify --notify-on --tag --timeout -o --output\n"));
+    }
+}
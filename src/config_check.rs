@@ -0,0 +1,471 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * config_check.rs
+ *
+ * Offline validation of the daemon's configuration for 'qmanager config
+ * check'. The configuration is loaded exactly as the daemon would load it,
+ * then the files it refers to are inspected. All problems are reported at
+ * once instead of the daemon failing on the first one at startup.
+ **/
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use nix::libc;
+use openssl::asn1::Asn1Time;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use qmanager::clierror::{ClientError, Result};
+use qmanager::mail;
+
+use cli::{Opt, OptCommand};
+
+/// Certificates expiring within this many days are warned about
+const EXPIRY_WARNING_DAYS: i32 = 30;
+
+/// Whether the current user may write to the given path
+fn writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Problems found in the configuration
+#[derive(Default)]
+struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn warning(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    /// Checks that the daemon is able to run the given file
+    fn check_executable(&mut self, what: &str, path: &Path) {
+        match fs::metadata(path) {
+            Err(e) => self.error(format!("{} {:?} cannot be accessed: {}", what, path, e)),
+            Ok(ref meta) if !meta.is_file() => {
+                self.error(format!("{} {:?} is not a file", what, path))
+            }
+            Ok(ref meta) if meta.permissions().mode() & 0o111 == 0 => {
+                self.error(format!("{} {:?} is not executable", what, path))
+            }
+            Ok(_) => {}
+        }
+    }
+
+    /// Checks that a certificate and key can be read, belong together and
+    /// are currently valid
+    fn check_certificate(&mut self, cert_path: &Path, key_path: &Path) {
+        let cert = match fs::read(cert_path) {
+            Ok(pem) => match X509::from_pem(&pem) {
+                Ok(cert) => Some(cert),
+                Err(e) => {
+                    self.error(format!("Invalid certificate {:?}: {}", cert_path, e));
+                    None
+                }
+            },
+            Err(e) => {
+                self.error(format!("Cannot read certificate {:?}: {}", cert_path, e));
+                None
+            }
+        };
+        let key = match fs::read(key_path) {
+            Ok(pem) => match PKey::private_key_from_pem(&pem) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    self.error(format!("Invalid private key {:?}: {}", key_path, e));
+                    None
+                }
+            },
+            Err(e) => {
+                self.error(format!("Cannot read private key {:?}: {}", key_path, e));
+                None
+            }
+        };
+        let cert = match cert {
+            Some(cert) => cert,
+            None => return,
+        };
+
+        if let Some(key) = key {
+            let matching = cert
+                .public_key()
+                .map(|public| public.public_eq(&key))
+                .unwrap_or(false);
+            if !matching {
+                self.error(format!(
+                    "Private key {:?} does not belong to certificate {:?}",
+                    key_path, cert_path
+                ));
+            }
+        }
+
+        let now = Asn1Time::days_from_now(0).unwrap();
+        let not_before = cert.not_before();
+        let not_after = cert.not_after();
+        if now
+            .diff(not_before)
+            .map(|d| d.days > 0 || d.secs > 0)
+            .unwrap_or(false)
+        {
+            self.error(format!(
+                "Certificate {:?} is not valid before {}",
+                cert_path, not_before
+            ));
+        }
+        match now.diff(not_after) {
+            Ok(d) if d.days < 0 || (d.days == 0 && d.secs < 0) => self.error(format!(
+                "Certificate {:?} has expired on {}",
+                cert_path, not_after
+            )),
+            Ok(d) if d.days < EXPIRY_WARNING_DAYS => self.warning(format!(
+                "Certificate {:?} expires in {} days, on {}",
+                cert_path, d.days, not_after
+            )),
+            _ => {}
+        }
+    }
+
+    /// Checks that the CA certificates can be read
+    fn check_ca(&mut self, ca_path: &Path) {
+        match fs::read(ca_path).map(|pem| X509::stack_from_pem(&pem)) {
+            Err(e) => self.error(format!("Cannot read CA certificate {:?}: {}", ca_path, e)),
+            Ok(Err(e)) => self.error(format!("Invalid CA certificate {:?}: {}", ca_path, e)),
+            Ok(Ok(ref certs)) if certs.is_empty() => {
+                self.error(format!("No CA certificates found in {:?}", ca_path))
+            }
+            Ok(Ok(_)) => {}
+        }
+    }
+
+    /// Checks that the state file and the notification outbox next to it
+    /// can be written
+    fn check_state_file(&mut self, state_file: &Path) {
+        let dir = match state_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        match fs::metadata(dir) {
+            Err(e) => {
+                self.error(format!(
+                    "State directory {:?} cannot be accessed: {}",
+                    dir, e
+                ));
+                return;
+            }
+            Ok(ref meta) if !meta.is_dir() => {
+                self.error(format!("State directory {:?} is not a directory", dir));
+                return;
+            }
+            Ok(ref meta) if meta.permissions().mode() & 0o1002 == 0o002 => {
+                self.warning(format!("State directory {:?} is writable by everyone", dir))
+            }
+            Ok(_) => {}
+        }
+
+        if !writable(dir) {
+            self.error(format!(
+                "State directory {:?} is not writable by the current user",
+                dir
+            ));
+        }
+        if state_file.exists() && !writable(state_file) {
+            self.error(format!(
+                "State file {:?} is not writable by the current user",
+                state_file
+            ));
+        }
+    }
+}
+
+/// Checks the daemon's configuration as merged from command line,
+/// environment and config file, prints all problems and fails if any of
+/// them is an error
+pub fn handle_check(opt: &Opt, merged: Result<()>) -> Result<()> {
+    let mut report = Report::default();
+    if let Err(e) = merged {
+        report.error(e.to_string());
+    }
+    for problem in opt.problems() {
+        report.error(problem.trim_end_matches('!').to_string());
+    }
+
+    let mut appkeys: Vec<_> = opt.appkeys.iter().collect();
+    appkeys.sort();
+    for (appkey, path) in appkeys {
+        report.check_executable(&format!("Appkey '{}':", appkey), path);
+    }
+
+    let mut hooks: Vec<(String, &Path)> = Vec::new();
+    for &(name, hook) in &[("pre", &opt.hooks.pre), ("post", &opt.hooks.post)] {
+        if let Some(path) = hook {
+            hooks.push((format!("Global {} hook", name), path));
+        }
+    }
+    let mut appkey_hooks: Vec<_> = opt.appkey_hooks.iter().collect();
+    appkey_hooks.sort_by(|a, b| a.0.cmp(b.0));
+    for (appkey, options) in appkey_hooks {
+        for &(name, hook) in &[("pre", &options.pre), ("post", &options.post)] {
+            if let Some(path) = hook {
+                hooks.push((format!("{} hook of appkey '{}'", name, appkey), path));
+            }
+        }
+    }
+    for (what, path) in hooks {
+        report.check_executable(&what, path);
+    }
+
+    if let Some(ref ca) = opt.ca {
+        report.check_ca(ca);
+    }
+    if let OptCommand::Daemon {
+        cert: Some(ref cert),
+        key: Some(ref key),
+        ..
+    } = opt.cmd
+    {
+        report.check_certificate(cert, key);
+    }
+
+    if let Some(ref state_file) = opt.state_file {
+        report.check_state_file(state_file);
+    }
+
+    // without an SMTP relay, mails to 'mailto:' targets are handed to sendmail
+    if let OptCommand::Daemon {
+        smtp_relay: None,
+        ref sendmail,
+        ..
+    } = opt.cmd
+    {
+        let sendmail = sendmail
+            .as_deref()
+            .unwrap_or_else(|| Path::new(mail::DEFAULT_SENDMAIL));
+        if !sendmail.exists() {
+            report.warning(format!(
+                "Sendmail binary {:?} not found, notification mails cannot be sent",
+                sendmail
+            ));
+        }
+    }
+
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+    if report.errors.is_empty() {
+        println!(
+            "Configuration OK ({} appkeys, {} warnings)",
+            opt.appkeys.len(),
+            report.warnings.len()
+        );
+        Ok(())
+    } else {
+        Err(ClientError::Config(format!(
+            "Found {} errors and {} warnings in the configuration",
+            report.errors.len(),
+            report.warnings.len()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::x509::X509NameBuilder;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("qmanager-check-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// Writes a self-signed certificate valid between the given offsets
+    /// from now, in days, and its key
+    fn certificate(dir: &Path, not_before: i64, not_after: i64) -> (PathBuf, PathBuf) {
+        let key = key();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let time = |days: i64| Asn1Time::from_unix((now + days * 86400) as libc::time_t).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&time(not_before)).unwrap();
+        cert.set_not_after(&time(not_after)).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn executables() {
+        let dir = temp_dir("exec");
+        let script = dir.join("gwas");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        let mut report = Report::default();
+
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+        report.check_executable("Appkey 'gwas':", &script);
+        report.check_executable("Appkey 'dir':", &dir);
+        report.check_executable("Appkey 'gone':", &dir.join("gone"));
+        assert_eq!(report.errors.len(), 3);
+        assert!(report.errors[0].ends_with("is not executable"));
+        assert!(report.errors[1].ends_with("is not a file"));
+        assert!(report.errors[2].contains("cannot be accessed"));
+
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+        let mut report = Report::default();
+        report.check_executable("Appkey 'gwas':", &script);
+        assert!(report.errors.is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn state_files() {
+        let dir = temp_dir("state");
+        let mut report = Report::default();
+        report.check_state_file(&dir.join("gone").join("state.json"));
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("State directory"));
+        assert!(report.errors[0].contains("cannot be accessed"));
+
+        let file = dir.join("state.json");
+        fs::write(&file, "").unwrap();
+        let mut report = Report::default();
+        report.check_state_file(&file.join("state.json"));
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].ends_with("is not a directory"));
+
+        let mut report = Report::default();
+        report.check_state_file(&file);
+        assert!(report.errors.is_empty() && report.warnings.is_empty());
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        report.check_state_file(&file);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].ends_with("is writable by everyone"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn certificates() {
+        let fixture = (
+            Path::new("resources/test/cert.pem"),
+            Path::new("resources/test/key.pem"),
+        );
+        let mut report = Report::default();
+        report.check_certificate(fixture.0, fixture.1);
+        assert!(report.errors.is_empty() && report.warnings.is_empty());
+
+        let dir = temp_dir("cert");
+        let (cert, key) = certificate(&dir, -1, 365);
+        let mut report = Report::default();
+        report.check_certificate(&cert, &key);
+        assert!(report.errors.is_empty() && report.warnings.is_empty());
+
+        // a key of another certificate
+        report.check_certificate(fixture.0, &key);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("does not belong to certificate"));
+
+        let mut report = Report::default();
+        report.check_certificate(&cert, &dir.join("gone.pem"));
+        report.check_certificate(&key, &key);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].starts_with("Cannot read private key"));
+        assert!(report.errors[1].starts_with("Invalid certificate"));
+
+        let mut report = Report::default();
+        certificate(&dir, -10, 10);
+        report.check_certificate(&cert, &key);
+        assert!(report.errors.is_empty());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("expires in"));
+
+        let mut report = Report::default();
+        certificate(&dir, -10, -1);
+        report.check_certificate(&cert, &key);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("has expired on"));
+
+        let mut report = Report::default();
+        certificate(&dir, 1, 365);
+        report.check_certificate(&cert, &key);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("is not valid before"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ca_certificates() {
+        let mut report = Report::default();
+        report.check_ca(Path::new("resources/test/cert.pem"));
+        assert!(report.errors.is_empty());
+
+        report.check_ca(Path::new("resources/test/key.pem"));
+        report.check_ca(Path::new("resources/test/gone.pem"));
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[1].starts_with("Cannot read CA certificate"));
+    }
+}
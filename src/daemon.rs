@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -20,7 +20,6 @@
  * SOFTWARE.
  **/
 
-
 use std::collections::HashMap;
 /// daemon.rs
 ///
@@ -60,25 +59,132 @@ use std::collections::HashMap;
 /// queue is empty again where it blocks on the variable again.
 // std
 use std::error::Error;
-use std::io::Result;
+use std::io::{self, ErrorKind, Read, Result};
 use std::net::SocketAddr;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::process::{Command, Output, Stdio};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 // crates
 use daemonize::Daemonize;
 use reqwest::Url;
-use serde_json;
+use serde_json::{self, Value};
 use systemd::daemon;
 use tiny_http::{Server, SslConfig};
 
 // modules
-use job_queue::{FailReason, Job, JobQueue, JobState, QueueState};
-use protocol::{Request, Response};
+use audit::AuditLog;
+use events::{EventBus, EventKind, OutputStream};
+use hooks::Hooks;
+use job_queue::{FailReason, Job, JobEvent, JobQueue, JobState, QueueState, Submission};
+use listing;
+use mail::{MailTransport, Mailer};
+use notify::{self, Notifier, NotifyMethod, Webhook};
+use protocol::{
+    ApiError, ErrorCode, Request, RequestEnvelope, Response, ResponseEnvelope, ServerInfo,
+    MAX_WAIT_TIMEOUT, PROTOCOL_VERSION,
+};
+use rest;
 use state::State;
+use unix_socket::{self, MAX_BODY_SIZE};
+
+/// Settings of the optional Unix domain socket listener
+pub struct SocketOptions {
+    /// Path of the socket file
+    pub path: PathBuf,
+
+    /// File mode of the socket file
+    pub mode: u32,
+
+    /// Group that the socket file should be owned by
+    pub group: Option<String>,
+}
+
+/// A TCP address the daemon accepts client connections on
+pub struct ListenerOptions {
+    /// IP address and port to bind to
+    pub address: SocketAddr,
+
+    /// Whether SSL/TLS is used on this address
+    pub tls: bool,
+}
+
+/// Everything the daemon needs to know to set itself up
+pub struct DaemonOptions {
+    /// TCP addresses to listen on
+    pub listeners: Vec<ListenerOptions>,
+
+    /// PID file location, ignored when running in foreground
+    pub pidfile: Option<PathBuf>,
+
+    /// PEM-encoded SSL certificate
+    pub cert: Option<Vec<u8>>,
+
+    /// PEM-encoded private key of the SSL certificate
+    pub key: Option<Vec<u8>>,
+
+    /// Do not detach from the terminal
+    pub foreground: bool,
+
+    /// Log requests and responses
+    pub dump_protocol: bool,
+
+    /// Application keys and their executables
+    pub appkeys: HashMap<String, PathBuf>,
+
+    /// Commands to run before and after jobs
+    pub hooks: Hooks,
+
+    /// URL to call once a job without notification targets has finished
+    pub notify_url: Option<String>,
+
+    /// How to call the notification URL
+    pub notify_method: NotifyMethod,
+
+    /// Shared secret to sign notification calls with
+    pub notify_secret: Option<String>,
+
+    /// Number of delivery attempts before a notification is given up
+    pub notify_retries: u32,
+
+    /// How to send mails to 'mailto:' notification targets
+    pub mail_transport: MailTransport,
+
+    /// Sender address of notification mails
+    pub mail_from: String,
+
+    /// Optional local listener
+    pub socket: Option<SocketOptions>,
+
+    /// File to record state-changing requests in
+    pub audit_log: Option<PathBuf>,
+}
+
+/// The origin of a client request
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// Local user name, only known for Unix socket connections
+    pub user: Option<String>,
+
+    /// Remote address or, for local connections, the socket path
+    pub address: String,
+}
+
+/// Shared state handed to every client request handler
+#[derive(Clone)]
+struct Context {
+    queue: Arc<(Mutex<JobQueue>, Condvar)>,
+    state: Arc<Mutex<State>>,
+    audit: Option<Arc<AuditLog>>,
+    notifier: Notifier,
+    events: EventBus,
+    appkeys: Arc<HashMap<String, PathBuf>>,
+    dump_protocol: bool,
+}
 
 /// Detaches the current process from the terminal and the current task
 /// session. Optionally takes a path to a file where the pid of the
@@ -95,155 +201,491 @@ fn daemonize(pidfile: Option<PathBuf>) -> Result<()> {
     Ok(())
 }
 
-/// Sets up a HTTP or HTTPS server, depending on whether both `cert`and `key`
-/// are given. Listens on the given TCP `port` on all available IP addresses
+/// Sets up a HTTP or HTTPS server on the given address, depending on whether
+/// `tls` is requested. HTTPS requires both `cert` and `key` to be given.
 fn spawn_https(
-    tcp_port: u16,
-    cert: Option<Vec<u8>>,
-    key: Option<Vec<u8>>,
+    bind_address: SocketAddr,
+    tls: bool,
+    cert: Option<&Vec<u8>>,
+    key: Option<&Vec<u8>>,
 ) -> std::result::Result<Server, Box<dyn Error + Sync + Send>> {
-    let bind_address = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], tcp_port));
-
-    if cert.is_some() ^ key.is_some() {
-        panic!("You must either provide an SSL certificate AND private key or none of them.");
+    if !tls {
+        return Server::http(bind_address);
     }
 
-    // decide on whether http or https should be used
-    match cert {
-        Some(c) => {
+    match (cert, key) {
+        (Some(c), Some(k)) => {
             let ssl_config = SslConfig {
-                certificate: c,
-                private_key: key.unwrap(),
+                certificate: c.clone(),
+                private_key: k.clone(),
             };
             Server::https(bind_address, ssl_config)
         }
-        None => Server::http(bind_address),
+        _ => panic!(
+            "You must provide an SSL certificate AND private key to listen on {} with SSL/TLS.",
+            bind_address
+        ),
     }
 }
 
-/// Handles a single HTTP request sent by a single client.
-/// Translates the JSON block to a Request, evaluates the
-/// request (i.e. adds a job to the queue) and returns
-/// a JSON result to the client.
-fn handle_client(
-    mut httprequest: tiny_http::Request,
-    q_mutex: Arc<(Mutex<JobQueue>, Condvar)>,
-    dump_protocol: bool,
-    state: Arc<Mutex<State>>,
-) {
-    let (ref q_mutex, ref cvar) = *q_mutex;
-
-    let mut s = String::from("");
-    httprequest.as_reader().read_to_string(&mut s).unwrap();
+/// HTTP status code and response of a failed request
+fn failure(error: ApiError) -> (u16, Response) {
+    (error.status(), Response::Error(error))
+}
 
-    if dump_protocol {
-        debug!("[handle_client] Got data: {}", &s);
-    }
-    let request = serde_json::from_str(&s);
+/// Evaluates a single request (i.e. adds a job to the queue) and returns the
+/// HTTP status code and the response object.
+fn evaluate_request(request: &Request, caller: &Caller, ctx: &Context) -> (u16, Response) {
+    let (ref q_mutex, ref cvar) = *ctx.queue;
+    let state = &ctx.state;
 
-    debug!("[handle_client] Processing request: {:?}", request);
+    match *request {
+        Request::Hello => (
+            200,
+            Response::ServerInfo(ServerInfo::current(ctx.appkeys.keys())),
+        ),
 
-    let (status_code, response_s) = match request {
-        Ok(Request::GetQueuedJobs) => {
+        Request::GetQueuedJobs => {
             let q = q_mutex.lock().unwrap();
             let items = q.iter_queued().cloned().collect();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::GetJobs(items)).unwrap(),
-            )
+            (200, Response::GetJobs(items))
         }
 
-        Ok(Request::GetQueueState) => {
+        Request::GetQueueState => {
             let q = q_mutex.lock().unwrap();
-            let state = q.get_state();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::QueueState(state)).unwrap(),
-            )
+            (200, Response::QueueState(q.get_state()))
         }
 
-        Ok(Request::SetQueueState(new_state)) => {
+        Request::SetQueueState(new_state) => {
             let mut q = q_mutex.lock().unwrap();
+            let old_state = q.get_state();
             q.set_state(new_state);
+            if q.get_state() != old_state {
+                ctx.events.publish(EventKind::QueueState {
+                    state: q.get_state(),
+                });
+            }
             cvar.notify_one();
             let state = state.lock().unwrap();
             state.save(&q).expect("Could not write program state");
-            (
-                200,
-                serde_json::to_string_pretty(&Response::QueueState(new_state)).unwrap(),
-            )
+            (200, Response::QueueState(new_state))
         }
 
-        Ok(Request::GetFinishedJobs) => {
+        Request::GetFinishedJobs => {
             let q = q_mutex.lock().unwrap();
             let items = q.iter_finished().cloned().collect();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::GetJobs(items)).unwrap(),
-            )
+            (200, Response::GetJobs(items))
+        }
+
+        Request::ListJobs(ref filter) => {
+            let q = q_mutex.lock().unwrap();
+            match listing::list(&q, filter) {
+                Ok(page) => (200, Response::JobPage(page)),
+                Err(e) => failure(ApiError::new(ErrorCode::InvalidArgument, e)),
+            }
+        }
+
+        Request::GetJob { id, stdout, stderr } => {
+            let q = q_mutex.lock().unwrap();
+            match q.get(id) {
+                Some(job) => {
+                    let mut job = job.clone();
+                    if !stdout {
+                        job.stdout.clear();
+                    }
+                    if !stderr {
+                        job.stderr.clear();
+                    }
+                    (200, Response::GetJob(job))
+                }
+                None => failure(no_such_job(id)),
+            }
+        }
+
+        Request::WaitJob { id, timeout } => {
+            let deadline = Instant::now() + Duration::from_secs(timeout.min(MAX_WAIT_TIMEOUT));
+
+            // any change of the job after taking the cursor is an event
+            // after it, so none can be missed between checking and waiting
+            let mut cursor = ctx.events.last_id();
+            loop {
+                // only the state is checked on every wake-up, the job and its
+                // output are copied once it is returned
+                let now = Instant::now();
+                match q_mutex.lock().unwrap().get(id) {
+                    None => return failure(no_such_job(id)),
+                    Some(job)
+                        if JobEvent::of_state(&job.state).is_terminal() || now >= deadline =>
+                    {
+                        return (200, Response::GetJob(job.clone()))
+                    }
+                    Some(_) => (),
+                }
+                for event in ctx.events.wait(cursor, deadline - now) {
+                    cursor = event.id;
+                }
+            }
         }
 
-        Ok(Request::RemoveJob(id)) => {
+        Request::RemoveJob(id) => {
             let mut q = q_mutex.lock().unwrap();
             let s = q.remove(id);
             let state = state.lock().unwrap();
             state.save(&q).expect("Could not write program state");
             match s {
-                Ok(job) => (
-                    200,
-                    serde_json::to_string_pretty(&Response::GetJob(job)).unwrap(),
-                ),
-                Err(FailReason::NoSuchJob) => (
-                    422,
-                    serde_json::to_string_pretty(&Response::Error("No such job".to_string()))
-                        .unwrap(),
-                ),
-                Err(FailReason::WrongJobState) => (
-                    422,
-                    serde_json::to_string_pretty(&Response::Error(
-                        "Job is currently running and cannot be removed".to_string(),
-                    ))
-                    .unwrap(),
+                Ok(job) => {
+                    ctx.events.publish(EventKind::JobRemoved { id });
+                    (200, Response::GetJob(job))
+                }
+                Err(FailReason::NoSuchJob) => failure(no_such_job(id)),
+                Err(FailReason::WrongJobState) => failure(
+                    ApiError::new(
+                        ErrorCode::WrongJobState,
+                        "Job is currently running and cannot be removed",
+                    )
+                    .with_details(json!({ "id": id, "state": JobState::Running })),
                 ),
+                Err(FailReason::Os(e)) => {
+                    failure(ApiError::new(ErrorCode::Internal, e.to_string()))
+                }
             }
         }
 
-        Ok(Request::KillJob(id)) => {
+        Request::KillJob(id) => {
             let mut q = q_mutex.lock().unwrap();
             match q.send_sigterm(id) {
-                Ok(_) => (200, serde_json::to_string_pretty(&Response::Ok).unwrap()),
-                _ => (
-                    422,
-                    serde_json::to_string_pretty(&Response::Error(
-                        "Job is currently not running.".to_string(),
-                    ))
-                    .unwrap(),
-                ),
+                Ok(_) => (200, Response::Ok),
+                Err(FailReason::NoSuchJob) => failure(no_such_job(id)),
+                Err(FailReason::WrongJobState) => {
+                    let job = q.get(id).expect("job exists");
+                    let message = if job.state == JobState::Running {
+                        "Job is starting and cannot be killed until its pre hooks have finished."
+                    } else {
+                        "Job is currently not running."
+                    };
+                    failure(
+                        ApiError::new(ErrorCode::WrongJobState, message)
+                            .with_details(json!({ "id": id, "state": job.state })),
+                    )
+                }
+                Err(FailReason::Os(e)) => failure(ApiError::new(
+                    ErrorCode::Internal,
+                    format!("Could not kill job: {}", e),
+                )),
             }
         }
 
-        Ok(Request::SubmitJob(cmdline)) => {
+        Request::HoldJob(id) | Request::ReleaseJob(id) | Request::MoveJob { id, .. } => {
             let mut q = q_mutex.lock().unwrap();
-            let id = q.submit(cmdline);
-            let state = state.lock().unwrap();
-            state.save(&q).expect("Could not write program state");
-            cvar.notify_one();
+            let result = match *request {
+                Request::HoldJob(_) => q.hold(id, true),
+                Request::ReleaseJob(_) => q.hold(id, false),
+                Request::MoveJob { position, .. } => q.move_job(id, position),
+                _ => unreachable!(),
+            };
+            match result {
+                Ok(job) => {
+                    ctx.events
+                        .publish(EventKind::JobChanged { job: job.clone() });
+                    cvar.notify_one();
+                    let state = state.lock().unwrap();
+                    state.save(&q).expect("Could not write program state");
+                    (200, Response::GetJob(job))
+                }
+                Err(FailReason::NoSuchJob) => failure(no_such_job(id)),
+                Err(FailReason::WrongJobState) => {
+                    let job_state = q.get(id).map(|j| j.state.clone());
+                    failure(
+                        ApiError::new(ErrorCode::WrongJobState, "Job is not queued")
+                            .with_details(json!({ "id": id, "state": job_state })),
+                    )
+                }
+                Err(FailReason::Os(e)) => {
+                    failure(ApiError::new(ErrorCode::Internal, e.to_string()))
+                }
+            }
+        }
+
+        Request::GetNotifications => (200, Response::Notifications(ctx.notifier.list())),
+
+        Request::RetryNotifications(id) => {
+            let replayed = ctx.notifier.retry(id);
+            match id {
+                Some(id) if replayed.is_empty() => failure(
+                    ApiError::new(
+                        ErrorCode::NoSuchNotification,
+                        format!("No such notification: {}", id),
+                    )
+                    .with_details(json!({ "id": id })),
+                ),
+                _ => (200, Response::Notifications(replayed)),
+            }
+        }
+
+        Request::SubmitJob(ref submission) => {
+            if let Err(e) = validate_submission(submission, ctx) {
+                return failure(e);
+            }
+            let ids = enqueue(std::slice::from_ref(submission), caller, ctx);
+            (200, Response::SubmitJob(ids[0]))
+        }
+
+        Request::SubmitBatch(ref submissions) => {
+            if submissions.is_empty() {
+                return failure(ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    "Empty batch, nothing to submit",
+                ));
+            }
+
+            // all or nothing: check every submission before queueing any
+            let errors: Vec<(usize, ApiError)> = submissions
+                .iter()
+                .enumerate()
+                .filter_map(|(i, s)| validate_submission(s, ctx).err().map(|e| (i, e)))
+                .collect();
+            if let Some(&(index, ref first)) = errors.first() {
+                let mut message = format!("Invalid submission #{}: {}", index + 1, first.message);
+                if errors.len() > 1 {
+                    message += &format!(" (and {} more)", errors.len() - 1);
+                }
+                let details: Vec<Value> = errors
+                    .iter()
+                    .map(|(i, e)| {
+                        json!({ "index": i, "code": e.code, "message": e.message, "details": e.details })
+                    })
+                    .collect();
+                return failure(
+                    ApiError::new(first.code, message).with_details(json!({ "errors": details })),
+                );
+            }
+
             (
                 200,
-                serde_json::to_string_pretty(&Response::SubmitJob(id)).unwrap(),
+                Response::SubmitBatch(enqueue(submissions, caller, ctx)),
             )
         }
+    }
+}
 
-        Err(e) => {
-            if e.is_io() {
-                (500, e.to_string().to_owned())
-            } else {
-                (400, e.to_string().to_owned())
-            }
+/// Checks that the daemon is able to run a submitted job
+fn validate_submission(
+    submission: &Submission,
+    ctx: &Context,
+) -> std::result::Result<(), ApiError> {
+    let appkey = submission.cmdline.split_whitespace().next().unwrap_or("");
+    if !ctx.appkeys.contains_key(appkey) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidAppkey,
+            format!("Invalid appkey '{}'", appkey),
+        )
+        .with_details(json!({ "appkey": appkey })));
+    }
+
+    match submission
+        .notify
+        .iter()
+        .find_map(|t| notify::validate_target(t).err())
+    {
+        Some(e) => Err(ApiError::new(ErrorCode::InvalidArgument, e)),
+        None => Ok(()),
+    }
+}
+
+/// Queues validated submissions in the given order under a single lock of
+/// the queue, so that no job of a batch starts before all of them are
+/// queued and stored. Returns the IDs of the new jobs.
+fn enqueue(submissions: &[Submission], caller: &Caller, ctx: &Context) -> Vec<u64> {
+    let (ref q_mutex, ref cvar) = *ctx.queue;
+    let mut q = q_mutex.lock().unwrap();
+    let ids: Vec<u64> = submissions
+        .iter()
+        .map(|s| q.submit(s.clone(), caller.user.clone()))
+        .collect();
+    let jobs: Vec<Job> = ids.iter().filter_map(|id| q.get(*id).cloned()).collect();
+    let state = ctx.state.lock().unwrap();
+    state.save(&q).expect("Could not write program state");
+
+    // publish before the queue may start the jobs
+    for job in &jobs {
+        ctx.events
+            .publish(EventKind::JobSubmitted { job: job.clone() });
+    }
+    cvar.notify_one();
+
+    // the notifier persists its outbox through the program state
+    drop(state);
+    drop(q);
+    for job in &jobs {
+        ctx.notifier.dispatch(JobEvent::Queued, job);
+    }
+    ids
+}
+
+/// Error of a request referring to an unknown job
+fn no_such_job(id: u64) -> ApiError {
+    ApiError::new(ErrorCode::NoSuchJob, "No such job").with_details(json!({ "id": id }))
+}
+
+/// Name of a JSON-encoded request, i.e. 'SubmitJob' for '{"SubmitJob": ...}'
+fn request_name(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Object(o) if o.len() == 1 => o.keys().next().map(|k| k.as_str()),
+        _ => None,
+    }
+}
+
+/// Evaluates a request. State-changing requests are recorded in the audit
+/// log, if one is configured.
+fn handle_request(request: Request, caller: &Caller, ctx: &Context) -> (u16, Response) {
+    debug!("[handle_client] Processing request: {:?}", request);
+    let (status_code, response) = evaluate_request(&request, caller, ctx);
+
+    if request.is_mutating() {
+        if let Some(ref audit) = ctx.audit {
+            audit.record(caller, &request, status_code, &response);
         }
+    }
+    (status_code, response)
+}
+
+/// Decodes and evaluates a request. Requests the daemon does not know are
+/// answered with an Unsupported response, known requests that cannot be
+/// decoded with a MalformedRequest error.
+fn dispatch_request(value: Value, caller: &Caller, ctx: &Context) -> (u16, Response) {
+    match request_name(&value) {
+        Some(name) if !Request::NAMES.contains(&name) => (
+            ErrorCode::UnsupportedRequest.status(),
+            Response::Unsupported(format!(
+                "Request '{}' is not supported by qmanager {} (protocol version {})",
+                name,
+                crate_version!(),
+                PROTOCOL_VERSION
+            )),
+        ),
+        _ => match serde_json::from_value::<Request>(value) {
+            Ok(request) => handle_request(request, caller, ctx),
+            Err(e) => failure(malformed(e)),
+        },
+    }
+}
+
+/// Error of a request that could not be decoded
+fn malformed(e: serde_json::Error) -> ApiError {
+    ApiError::new(
+        ErrorCode::MalformedRequest,
+        format!("Malformed request: {}", e),
+    )
+}
+
+/// Status code and body of the answer to a request whose body cannot be
+/// read, because it is too large, not UTF-8 or the connection failed
+fn read_error(e: &io::Error) -> (u16, String) {
+    let (status_code, code) = match e.kind() {
+        ErrorKind::FileTooLarge => (413, ErrorCode::MalformedRequest),
+        ErrorKind::InvalidData => (400, ErrorCode::MalformedRequest),
+        _ => (500, ErrorCode::Internal),
+    };
+    let response = Response::Error(ApiError::new(code, e.to_string()));
+    let envelope = ResponseEnvelope::new(response.encode(PROTOCOL_VERSION));
+    (
+        status_code,
+        serde_json::to_string_pretty(&envelope).unwrap(),
+    )
+}
+
+/// Reads the body of an HTTP request, which must be UTF-8 and may not be
+/// larger than MAX_BODY_SIZE. Bodies of announced excess length are not
+/// read at all.
+fn read_body(httprequest: &mut tiny_http::Request) -> io::Result<String> {
+    let too_large = || {
+        io::Error::new(
+            ErrorKind::FileTooLarge,
+            format!("Request body exceeds the limit of {} bytes", MAX_BODY_SIZE),
+        )
     };
+    if httprequest.body_length().is_some_and(|n| n > MAX_BODY_SIZE) {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    Read::take(httprequest.as_reader(), MAX_BODY_SIZE as u64 + 1).read_to_end(&mut body)?;
+    if body.len() > MAX_BODY_SIZE {
+        return Err(too_large());
+    }
+    String::from_utf8(body).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+}
 
-    if dump_protocol {
+/// Processes a single HTTP request sent by a client and returns the HTTP
+/// status code, content type and response. JSON requests posted to '/' are
+/// decoded and evaluated. Requests wrapped in a versioned envelope are
+/// answered with a response envelope, bare requests of older clients with a
+/// bare response. All other calls are handled by the REST API.
+fn process_request(
+    method: &str,
+    path: &str,
+    s: &str,
+    caller: &Caller,
+    ctx: &Context,
+) -> (u16, &'static str, String) {
+    if ctx.dump_protocol {
+        debug!(
+            "[handle_client] Got {} {} from {}: {}",
+            method, path, caller.address, s
+        );
+    }
+
+    let legacy = method == "POST" && (path == "/" || path.starts_with("/?"));
+
+    let (status_code, content_type, response_s) = if !legacy {
+        let (status_code, body) =
+            rest::handle(method, path, s, &mut |r| handle_request(r, caller, ctx));
+        (status_code, "application/json", body)
+    } else {
+        // Requests of clients that predate versioning are answered with
+        // bare responses, errors as bare messages. Bodies that are not JSON
+        // at all are not sent by any client version, so the answer to those
+        // is an envelope.
+        let (status_code, response, version) = match serde_json::from_str::<Value>(s) {
+            Ok(ref value) if value.get("version").is_some() => {
+                match serde_json::from_value::<RequestEnvelope<Value>>(value.clone()) {
+                    Ok(envelope) => {
+                        let (status_code, response) =
+                            dispatch_request(envelope.request, caller, ctx);
+                        (status_code, response, Some(envelope.version))
+                    }
+                    Err(e) => {
+                        let (status_code, response) = failure(malformed(e));
+                        (status_code, response, Some(PROTOCOL_VERSION))
+                    }
+                }
+            }
+
+            Ok(value) => {
+                let (status_code, response) = dispatch_request(value, caller, ctx);
+                (status_code, response, None)
+            }
+
+            Err(e) => {
+                let (status_code, response) = if e.is_io() {
+                    failure(ApiError::new(ErrorCode::Internal, e.to_string()))
+                } else {
+                    failure(malformed(e))
+                };
+                (status_code, response, Some(PROTOCOL_VERSION))
+            }
+        };
+
+        let body = match version {
+            Some(v) => serde_json::to_string_pretty(&ResponseEnvelope::new(response.encode(v))),
+            // bare responses are understood by every client version
+            None => serde_json::to_string_pretty(&response.encode(0)),
+        };
+        (status_code, "application/json", body.unwrap())
+    };
+
+    if ctx.dump_protocol {
         debug!(
             "[handle_client] Returning {} response: {}",
             status_code, &response_s
@@ -255,49 +697,155 @@ fn handle_client(
         );
     }
 
-    let mut response = tiny_http::Response::from_string(response_s).with_status_code(status_code);
+    (status_code, content_type, response_s)
+}
 
-    if status_code == 200 {
-        response.add_header(
-            tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
-        );
-    } else {
-        response.add_header(
-            tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap(),
-        );
-    }
+/// Handles a single HTTP request sent by a single client.
+/// Translates the JSON block to a Request, evaluates the
+/// request and returns a JSON result to the client.
+fn handle_client(mut httprequest: tiny_http::Request, ctx: &Context) {
+    let caller = Caller {
+        user: None,
+        address: httprequest.remote_addr().to_string(),
+    };
+    let body = read_body(&mut httprequest);
+
+    let method = httprequest.method().as_str().to_owned();
+    let path = httprequest.url().to_owned();
+    let header = |name: &'static str| {
+        httprequest
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str().to_owned())
+    };
+    let subscription = rest::event_subscription(
+        &method,
+        &path,
+        header("Accept").as_deref(),
+        header("Last-Event-ID").as_deref(),
+    );
+
+    let (status_code, content_type, response_s) = match subscription {
+        Some(Ok(subscription)) => {
+            stream_events(&caller.address, || {
+                ctx.events
+                    .stream(&mut httprequest.into_writer(), subscription)
+            });
+            return;
+        }
+        Some(Err((status_code, body))) => (status_code, "application/json", body),
+        None => match body {
+            Ok(s) => process_request(&method, &path, &s, &caller, ctx),
+            Err(e) => {
+                warn!("Could not read request from {}: {}", caller.address, e);
+                let (status_code, body) = read_error(&e);
+                (status_code, "application/json", body)
+            }
+        },
+    };
+
+    let mut response = tiny_http::Response::from_string(response_s).with_status_code(status_code);
+    response.add_header(
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap(),
+    );
 
     if let Err(err) = httprequest.respond(response) {
         eprintln!("Failed to send response to client: {:?}", err);
     }
 }
 
-/// Calls the notification URL for the given job
-fn run_notify_command(job: Job, url: &Url) -> Result<()> {
-    let mut url = url.clone();
-    url.set_query(Some(&format!("jobid={}", job.id)));
-    let s = url.as_str().to_string();
-
-    match reqwest::get(url) {
+/// Handles a single request received on the Unix socket. The caller is
+/// identified by the credentials of the connecting process, so that the
+/// local user becomes the owner of submitted jobs.
+fn handle_local_client(stream: UnixStream, socket_path: &str, ctx: &Context) {
+    let peer = match unix_socket::peer_user(&stream) {
+        Ok(p) => p,
         Err(e) => {
-            error!("Failed to call notify url {:#?}: {}", s, e.to_string());
-            Ok(())
-        }
-        Ok(ref r) if r.status().is_success() => {
-            debug!(
-                "Notification call to {:#?} succeeded. Response: {}",
-                s,
-                r.status().as_str()
+            error!(
+                "Could not determine peer credentials, dropping connection: {}",
+                e
             );
-            Ok(())
+            return;
         }
-        Ok(r) => {
-            debug!(
-                "Notification call to {:#?} failed. Response: {}",
-                s,
-                r.status().as_str()
-            );
-            Ok(())
+    };
+
+    let message = match unix_socket::read_request(&stream) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == ErrorKind::FileTooLarge => {
+            warn!("Rejecting request from local user {}: {}", peer.uid, e);
+            let (status_code, body) = read_error(e);
+            let _ = unix_socket::write_response(&stream, status_code, "application/json", &body);
+            return;
+        }
+        Err(e) => {
+            warn!("Could not read request from local user {}: {}", peer.uid, e);
+            return;
+        }
+    };
+
+    debug!(
+        "Local request {} {} from uid {} (pid {})",
+        message.method(),
+        message.path(),
+        peer.uid,
+        peer.pid
+    );
+
+    let uid = peer.uid;
+    let caller = Caller {
+        // fall back to the numerical uid for users without a passwd entry
+        user: Some(peer.name.unwrap_or_else(|| uid.to_string())),
+        address: socket_path.to_owned(),
+    };
+
+    let subscription = rest::event_subscription(
+        message.method(),
+        message.path(),
+        message.header("Accept"),
+        message.header("Last-Event-ID"),
+    );
+
+    let (status_code, content_type, response_s) = match subscription {
+        Some(Ok(subscription)) => {
+            stream_events(&caller.address, || {
+                ctx.events.stream(&mut &stream, subscription)
+            });
+            return;
+        }
+        Some(Err((status_code, body))) => (status_code, "application/json", body),
+        None => process_request(
+            message.method(),
+            message.path(),
+            &message.body,
+            &caller,
+            ctx,
+        ),
+    };
+
+    if let Err(err) = unix_socket::write_response(&stream, status_code, content_type, &response_s) {
+        warn!("Failed to send response to local client: {}", err);
+    }
+}
+
+/// Streams events to a client until it disconnects
+fn stream_events<F: FnOnce() -> Result<()>>(address: &str, stream: F) {
+    debug!("Streaming events to {}", address);
+    if let Err(e) = stream() {
+        debug!("Event stream to {} closed: {}", address, e);
+    }
+}
+
+/// Accepts connections on the Unix socket and processes each of them in a
+/// thread of its own
+fn run_local_listener(listener: UnixListener, socket_path: String, ctx: Context) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let (socket_path, ctx) = (socket_path.clone(), ctx.clone());
+                thread::spawn(move || handle_local_client(stream, &socket_path, &ctx));
+            }
+            Err(e) => warn!("Failed to accept local connection: {}", e),
         }
     }
 }
@@ -312,22 +860,24 @@ fn run_notify_command(job: Job, url: &Url) -> Result<()> {
 /// 1.1 If the thread is woken up, it checks again for an available job. If
 ///     there is none, it returns to sleep. If there is, proceed to (2).
 ///
-/// 2. Mark the job as `Running` and execute it
+/// 2. Mark the job as `Running`, run its pre hooks and execute it. If a pre
+///    hook fails, the job is not executed but marked as `Failed`.
 ///
-/// 3. Collect the return value, stdout and stderr of the job
+/// 3. Collect the return value, stdout and stderr of the job, publishing the
+///    output as it arrives
 ///
-/// 4. Call the notification handler
+/// 4. Run the job's post hooks and call the notification handler
 ///
 /// 5. Mark the job as `Finished` and return to (1).
 fn run_queue(
     q_mutex: &Arc<(Mutex<JobQueue>, Condvar)>,
-    notify_url: Option<String>,
+    notifier: Notifier,
+    events: EventBus,
     appkeys: HashMap<String, PathBuf>,
+    hooks: Hooks,
 ) -> ! {
     let (ref q_mutex, ref cvar) = **q_mutex;
 
-    let notify_url = notify_url.map(|s| Url::parse(&s).unwrap());
-
     // main loop
     loop {
         let mut job: Option<Job> = None;
@@ -371,24 +921,53 @@ fn run_queue(
             cmdline_remainder
         );
 
+        // Run the pre hooks, stopping at the first one that fails
+        let pre_hook_error = hooks
+            .pre(appkey)
+            .into_iter()
+            .find_map(|hook| hook.run(&job).err());
+
         // Spawn the process, collect stdout, stderr and pid.
         // Continues once the job is terminated (one way or another).
-        let cmd = Command::new("sh")
-            .arg("-c")
-            .arg(cmdline_wrapper)
-            .current_dir("/")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .and_then(|child| {
-                {
-                    let mut q = q_mutex.lock().unwrap();
-                    q.assign_pid(job.id, child.id());
-                }
-                child.wait_with_output()
-            });
+        let cmd = match pre_hook_error {
+            Some(e) => Err(e),
+            None => {
+                notifier.dispatch(JobEvent::Started, &job);
+                events.publish(EventKind::JobStarted { job: job.clone() });
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(cmdline_wrapper)
+                    .current_dir("/")
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .and_then(|mut child| {
+                        {
+                            let mut q = q_mutex.lock().unwrap();
+                            q.assign_pid(job.id, child.id());
+                        }
+                        let stdout = child.stdout.take().expect("stdout is piped");
+                        let stderr = child.stderr.take().expect("stderr is piped");
+                        let (stdout_events, stderr_events) = (events.clone(), events.clone());
+                        let id = job.id;
+                        let stdout = thread::spawn(move || {
+                            stdout_events.forward_output(stdout, id, OutputStream::Stdout)
+                        });
+                        let stderr = thread::spawn(move || {
+                            stderr_events.forward_output(stderr, id, OutputStream::Stderr)
+                        });
+                        let status = child.wait()?;
+                        Ok(Output {
+                            status,
+                            stdout: stdout.join().unwrap_or_default(),
+                            stderr: stderr.join().unwrap_or_default(),
+                        })
+                    })
+            }
+        };
 
         // Collect status of finished job and forward status to the queue
+        let queue_state = q_mutex.lock().unwrap().get_state();
         let job = match cmd {
             // Job was successfully launched. This does not mean that the
             // process itself was successful.
@@ -429,56 +1008,101 @@ fn run_queue(
             }
         };
 
-        // Notify the server of job completion regardless of the result
+        // Run the post hooks and notify the server of job completion
+        // regardless of the result
         if let Some(j) = job {
-            if notify_url.is_some() {
-                let id = j.id;
-                if let Err(e) = run_notify_command(j, notify_url.as_ref().unwrap()) {
-                    error!(
-                        "Failed to run notify command for job {}: {}",
-                        id,
-                        e.to_string()
-                    );
+            for hook in hooks.post(appkey) {
+                if let Err(e) = hook.run(&j) {
+                    error!("[queue runner] Post hook failed for job {}: {}", j.id, e);
                 }
             }
+            notifier.dispatch(JobEvent::of_state(&j.state), &j);
+
+            let mut job = j;
+            job.stdout.clear();
+            job.stderr.clear();
+            events.publish(EventKind::JobFinished { job });
+        }
+
+        // the queue stops after the job if it was asked to
+        let new_queue_state = q_mutex.lock().unwrap().get_state();
+        if new_queue_state != queue_state {
+            events.publish(EventKind::QueueState {
+                state: new_queue_state,
+            });
         }
     }
 }
 
-pub fn handle(
-    tcp_port: u16,
-    pidfile: Option<PathBuf>,
-    cert: Option<Vec<u8>>,
-    key: Option<Vec<u8>>,
-    foreground: bool,
-    dump_protocol: bool,
-    appkeys: HashMap<String, PathBuf>,
-    notify_url: Option<String>,
-    state: State,
-) -> Result<()> {
-    if !foreground {
-        daemonize(pidfile)?;
+/// Sets up the daemon according to the given options and processes client
+/// requests until the daemon is terminated.
+pub fn handle(opts: DaemonOptions, state: State) -> Result<()> {
+    if !opts.foreground {
+        daemonize(opts.pidfile)?;
     }
 
-    let httpd = match spawn_https(tcp_port, cert, key) {
-        Ok(s) => s,
-        Err(e) => {
-            error!(
-                "Could not set up listening socket on port {}: {}",
-                tcp_port,
-                e.to_string()
-            );
-            panic!(
-                "Could not set up listening socket on port {}: {}",
-                tcp_port,
-                e.to_string()
-            )
+    let mut servers = Vec::new();
+    for listener in &opts.listeners {
+        match spawn_https(
+            listener.address,
+            listener.tls,
+            opts.cert.as_ref(),
+            opts.key.as_ref(),
+        ) {
+            Ok(s) => {
+                info!(
+                    "Listening on {} ({})",
+                    listener.address,
+                    if listener.tls { "HTTPS" } else { "HTTP" }
+                );
+                servers.push((s, listener.address));
+            }
+            Err(e) => {
+                error!(
+                    "Could not set up listening socket on {}: {}",
+                    listener.address, e
+                );
+                panic!(
+                    "Could not set up listening socket on {}: {}",
+                    listener.address, e
+                )
+            }
         }
+    }
+
+    let local_listener = match opts.socket {
+        Some(ref socket) => {
+            match unix_socket::bind(&socket.path, socket.mode, socket.group.as_deref()) {
+                Ok(l) => {
+                    info!("Listening on local socket {:?}", socket.path);
+                    Some((l, socket.path.to_string_lossy().to_string()))
+                }
+                Err(e) => {
+                    error!("Could not set up local socket {:?}: {}", socket.path, e);
+                    panic!("Could not set up local socket {:?}: {}", socket.path, e)
+                }
+            }
+        }
+        None => None,
+    };
+
+    let audit = match opts.audit_log {
+        Some(ref path) => match AuditLog::open(path) {
+            Ok(log) => {
+                info!("Recording state-changing requests in {:?}", path);
+                Some(Arc::new(log))
+            }
+            Err(e) => {
+                error!("Could not open audit log {:?}: {}", path, e);
+                panic!("Could not open audit log {:?}: {}", path, e)
+            }
+        },
+        None => None,
     };
 
     daemon::notify(false, [(daemon::STATE_READY, "1")].iter())?;
     info!("Daemon version {} ready.", crate_version!());
-    info!("Application keys available: {:?}", appkeys.keys());
+    info!("Application keys available: {:?}", opts.appkeys.keys());
 
     let job_queue = Arc::new((Mutex::new(state.load_queue()), Condvar::new()));
 
@@ -495,32 +1119,97 @@ pub fn handle(
         }
     }
 
+    // set up the program state to be shared among threads,
+    // namely the signal handler (ought to save state on SIGTERM),
+    // the notification outbox and the client request handlers
+    let outbox = state.load_outbox();
+    let state = Arc::new(Mutex::new(state));
+
+    // spawn notification delivery, picking up undelivered notifications
+    // from the previous run
+    let notify_url = opts.notify_url.map(|s| Url::parse(&s).unwrap());
+    let notifier = Notifier::new(outbox, Arc::clone(&state), notify_url);
+    let delivery_notifier = notifier.clone();
+    let webhook = Webhook::new(opts.notify_method, opts.notify_secret);
+    let mailer = Mailer::new(opts.mail_transport, opts.mail_from);
+    let notify_retries = opts.notify_retries;
+    thread::Builder::new()
+        .name("Notifier".to_owned())
+        .spawn(move || delivery_notifier.run(webhook, mailer, notify_retries))
+        .unwrap();
+
     // spawn queue runner
     let queue_runner_q = job_queue.clone();
+    let queue_runner_notifier = notifier.clone();
+    let events = EventBus::new();
+    let queue_runner_events = events.clone();
+    let appkeys = opts.appkeys;
+    let known_appkeys = Arc::new(appkeys.clone());
+    let hooks = opts.hooks;
     let queue_runner = thread::Builder::new()
         .name("Queue Runner".to_owned())
-        .spawn(move || run_queue(&queue_runner_q, notify_url, appkeys))
+        .spawn(move || {
+            run_queue(
+                &queue_runner_q,
+                queue_runner_notifier,
+                queue_runner_events,
+                appkeys,
+                hooks,
+            )
+        })
         .unwrap();
 
-    // set up the program state to be shared among threads,
-    // namely the signal handler (ought to save state on SIGTERM)
-    // and the current thread, handling client requests
-    let state = Arc::new(Mutex::new(state));
-
     // spawn signal handler to collect SIGTERM signals sent by systemd unit
     // create clones before spawning, otherwise the "originals" would be moved into the closure
     let sig_q = Arc::clone(&job_queue);
     let sig_state = Arc::clone(&state);
     let signal_handler = setup_signal_handler(sig_q, sig_state);
 
-    // handle incoming TCP connections
-    for request in httpd.incoming_requests() {
-        debug!("Request: {:?}", request);
+    let ctx = Context {
+        queue: job_queue,
+        state,
+        audit,
+        notifier,
+        events,
+        appkeys: known_appkeys,
+        dump_protocol: opts.dump_protocol,
+    };
 
-        handle_client(request, job_queue.clone(), dump_protocol, state.clone());
+    // handle incoming local connections
+    if let Some((listener, path)) = local_listener {
+        let local_ctx = ctx.clone();
+        thread::Builder::new()
+            .name("Local Listener".to_owned())
+            .spawn(move || run_local_listener(listener, path, local_ctx))
+            .unwrap();
     }
 
+    // handle incoming TCP connections, one thread per listening address,
+    // all of them working on the same queue
+    let tcp_listeners: Vec<_> = servers
+        .into_iter()
+        .map(|(httpd, address)| {
+            let tcp_ctx = ctx.clone();
+            thread::Builder::new()
+                .name(format!("Listener {}", address))
+                .spawn(move || {
+                    for request in httpd.incoming_requests() {
+                        debug!("Request: {:?}", request);
+
+                        // requests may take long, i.e. waiting for a job or
+                        // streaming events, so each runs in a thread of its own
+                        let ctx = tcp_ctx.clone();
+                        thread::spawn(move || handle_client(request, &ctx));
+                    }
+                })
+                .unwrap()
+        })
+        .collect();
+
     // collect threads in case of program termination
+    for listener in tcp_listeners {
+        listener.join().unwrap();
+    }
     queue_runner.join().unwrap();
     signal_handler.join().unwrap();
     Ok(())
@@ -532,23 +1221,283 @@ fn setup_signal_handler(
     job_queue: Arc<(Mutex<JobQueue>, Condvar)>,
     state: Arc<Mutex<State>>,
 ) -> std::thread::JoinHandle<()> {
-    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGTERM]).unwrap();
+    let signals = signal_hook::iterator::Signals::new([signal_hook::SIGTERM]).unwrap();
 
     thread::Builder::new()
         .name("Signal Handler".to_owned())
         .spawn(move || {
             for signal in signals.forever() {
-                match signal {
-                    signal_hook::SIGTERM => {
-                        info!("Caught SIGTERM, initiating state saving");
-                        let state = state.lock().unwrap();
-                        let q = job_queue.0.lock().unwrap();
-                        state.save(&q).expect("Could not write program state");
-                        std::process::exit(0);
-                    }
-                    _ => unreachable!(),
+                if signal == signal_hook::SIGTERM {
+                    info!("Caught SIGTERM, initiating state saving");
+                    let state = state.lock().unwrap();
+                    let q = job_queue.0.lock().unwrap();
+                    state.save(&q).expect("Could not write program state");
+                    std::process::exit(0);
                 }
             }
         })
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hooks::{Hook, HookSet};
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::net::{Shutdown, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process;
+
+    /// A fresh directory for the test of the given name
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("qmanager-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Context of a daemon keeping its state in the given directory, with
+    /// the appkey 'sleep'
+    fn context(dir: &Path) -> Context {
+        let mut appkeys = HashMap::new();
+        appkeys.insert("sleep".to_string(), PathBuf::from("/bin/sleep"));
+        let state = State::from(dir.join("state.json"));
+        let outbox = state.load_outbox();
+        let state = Arc::new(Mutex::new(state));
+        Context {
+            queue: Arc::new((Mutex::new(JobQueue::new(0)), Condvar::new())),
+            state: Arc::clone(&state),
+            audit: None,
+            notifier: Notifier::new(outbox, state, None),
+            events: EventBus::new(),
+            appkeys: Arc::new(appkeys),
+            dump_protocol: false,
+        }
+    }
+
+    fn caller() -> Caller {
+        Caller {
+            user: None,
+            address: "test".to_string(),
+        }
+    }
+
+    /// Polls the job with the given ID until the condition holds
+    fn wait_for<F: Fn(&Job) -> bool>(ctx: &Context, id: u64, condition: F) -> Job {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let job = ctx.queue.0.lock().unwrap().get(id).cloned().unwrap();
+            if condition(&job) {
+                return job;
+            }
+            assert!(Instant::now() < deadline, "timed out waiting for {:?}", job);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn kill_during_pre_hook() {
+        let dir = temp_dir("kill");
+        let hook = dir.join("pre-hook");
+        fs::write(&hook, "#!/bin/sh\nsleep 1\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let hooks = Hooks {
+            global: HookSet {
+                pre: Some(Hook {
+                    path: hook,
+                    timeout: Duration::from_secs(10),
+                }),
+                post: None,
+            },
+            appkeys: HashMap::new(),
+        };
+
+        let ctx = context(&dir);
+        let (queue, notifier, events) =
+            (ctx.queue.clone(), ctx.notifier.clone(), ctx.events.clone());
+        let appkeys = (*ctx.appkeys).clone();
+        thread::spawn(move || run_queue(&queue, notifier, events, appkeys, hooks));
+
+        let submission = Submission {
+            cmdline: "sleep 30".to_string(),
+            notify: Vec::new(),
+            tags: Vec::new(),
+        };
+        let (status, _) = evaluate_request(&Request::SubmitJob(submission), &caller(), &ctx);
+        assert_eq!(status, 200);
+
+        // the pre hook is running, there is no process to kill yet
+        wait_for(&ctx, 1, |j| j.state == JobState::Running);
+        match evaluate_request(&Request::KillJob(1), &caller(), &ctx) {
+            (409, Response::Error(e)) => assert_eq!(e.code, ErrorCode::WrongJobState),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        // the queue is still usable and the job can be killed once started
+        wait_for(&ctx, 1, |j| j.pid.is_some());
+        let (status, _) = evaluate_request(&Request::KillJob(1), &caller(), &ctx);
+        assert_eq!(status, 200);
+        let job = wait_for(&ctx, 1, |j| j.finished.is_some());
+        assert_eq!(job.state, JobState::Killed(15));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failing_pre_hook_fails_job() {
+        let dir = temp_dir("pre-hook");
+        let hook = dir.join("pre-hook");
+        fs::write(
+            &hook,
+            "#!/bin/sh\necho 'no licence for sleep' >&2\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut appkeys = HashMap::new();
+        appkeys.insert(
+            "sleep".to_string(),
+            HookSet {
+                pre: Some(Hook {
+                    path: hook,
+                    timeout: Duration::from_secs(10),
+                }),
+                post: None,
+            },
+        );
+        let hooks = Hooks {
+            global: HookSet::default(),
+            appkeys,
+        };
+
+        let ctx = context(&dir);
+        let (queue, notifier, events) =
+            (ctx.queue.clone(), ctx.notifier.clone(), ctx.events.clone());
+        let appkeys = (*ctx.appkeys).clone();
+        thread::spawn(move || run_queue(&queue, notifier, events, appkeys, hooks));
+
+        let submission = Submission {
+            cmdline: "sleep 30".to_string(),
+            notify: Vec::new(),
+            tags: Vec::new(),
+        };
+        evaluate_request(&Request::SubmitJob(submission), &caller(), &ctx);
+        let job = wait_for(&ctx, 1, |j| j.finished.is_some());
+        assert_eq!(
+            job.state,
+            JobState::Failed("no licence for sleep".to_string())
+        );
+        assert!(job.pid.is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn wait_for_job() {
+        let dir = temp_dir("wait");
+        let ctx = context(&dir);
+        let submit = |cmdline: &str| {
+            let submission = Submission {
+                cmdline: cmdline.to_string(),
+                notify: Vec::new(),
+                tags: Vec::new(),
+            };
+            evaluate_request(&Request::SubmitJob(submission), &caller(), &ctx)
+        };
+        let wait =
+            |id, timeout| evaluate_request(&Request::WaitJob { id, timeout }, &caller(), &ctx);
+        submit("sleep 0.3");
+        submit("sleep 0");
+
+        // the queue is not running yet, so the wait times out
+        match wait(1, 0) {
+            (200, Response::GetJob(job)) => assert_eq!(job.state, JobState::Queued),
+            other => panic!("unexpected response {:?}", other),
+        }
+        match wait(3, 10) {
+            (404, Response::Error(e)) => assert_eq!(e.code, ErrorCode::NoSuchJob),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let (queue, notifier, events) =
+            (ctx.queue.clone(), ctx.notifier.clone(), ctx.events.clone());
+        let appkeys = (*ctx.appkeys).clone();
+        thread::spawn(move || run_queue(&queue, notifier, events, appkeys, Hooks::default()));
+
+        // woken up by the output and state changes of both jobs
+        let start = Instant::now();
+        match wait(2, 10) {
+            (200, Response::GetJob(job)) => assert_eq!(job.state, JobState::Terminated(0)),
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Sends the given raw request to a TCP server and returns the result
+    /// of reading its body
+    fn read_tcp_body(request: &'static [u8]) -> io::Result<String> {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let _ = stream.write_all(request);
+            let _ = stream.shutdown(Shutdown::Write);
+        });
+        let mut httprequest = server.recv().unwrap();
+        let body = read_body(&mut httprequest);
+        drop(httprequest);
+        client.join().unwrap();
+        body
+    }
+
+    #[test]
+    fn tcp_request_bodies() {
+        let body = read_tcp_body(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n\"Hello\"\r\n");
+        assert_eq!(body.unwrap(), "\"Hello\"\r\n");
+
+        let e = read_tcp_body(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\n\xff\xfe\x00\x01")
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(read_error(&e).0, 400);
+
+        let e =
+            read_tcp_body(b"POST / HTTP/1.1\r\nContent-Length: 16777217\r\n\r\n{}").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FileTooLarge);
+        let (status_code, body) = read_error(&e);
+        assert_eq!(status_code, 413);
+        assert!(body.contains("MalformedRequest"), "{}", body);
+
+        let e = io::Error::new(ErrorKind::ConnectionReset, "reset");
+        assert_eq!(read_error(&e).0, 500);
+    }
+
+    #[test]
+    fn unsupported_and_malformed_requests() {
+        let dir = temp_dir("dispatch");
+        let ctx = context(&dir);
+        let dispatch = |value: Value| dispatch_request(value, &caller(), &ctx);
+
+        match dispatch(json!({ "Frobnicate": 1 })) {
+            (501, Response::Unsupported(_)) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+        let malformed = vec![
+            json!({ "SetQueueState": "Foo" }),
+            json!({ "ListJobs": { "states": ["bogus"] } }),
+            json!({ "SubmitJob": { "cmdline": "sleep 1", "notify": [{ "url": "x", "events": ["bogus"] }] } }),
+            json!({ "KillJob": "one" }),
+            json!(42),
+        ];
+        for value in malformed {
+            match dispatch(value.clone()) {
+                (400, Response::Error(ref e)) if e.code == ErrorCode::MalformedRequest => {}
+                other => panic!("unexpected response to {}: {:?}", value, other),
+            }
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,563 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * events.rs
+ *
+ * Live stream of job and queue state changes for dashboards and other
+ * long-lived clients, sent as Server-Sent Events or JSON Lines. Events are
+ * numbered, and the most recent ones are retained so that clients can
+ * reconnect with the number of the last event they have seen and continue
+ * without missing any.
+ **/
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::Result;
+use std::str;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use job_queue::{Job, QueueState};
+
+/// Number of events retained for reconnecting clients
+const EVENT_BACKLOG: usize = 4096;
+
+/// Interval in which idle streams are sent a heartbeat, so that clients
+/// and the daemon notice broken connections
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Maximum number of bytes of job output read into a single event
+const OUTPUT_CHUNK_SIZE: usize = 4096;
+
+/// The output stream of a job
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// What happened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EventKind {
+    /// A job has been added to the queue
+    JobSubmitted { job: Job },
+
+    /// A job has been started
+    JobStarted { job: Job },
+
+    /// A job has terminated, was killed or could not be run. Its output is
+    /// not included, as it has been sent as Output events.
+    JobFinished { job: Job },
+
+    /// A job has been removed from the queue or the list of finished jobs
+    JobRemoved { id: u64 },
+
+    /// A queued job has been held, released or moved within the queue
+    JobChanged { job: Job },
+
+    /// A running job has written to stdout or stderr
+    Output {
+        id: u64,
+        stream: OutputStream,
+        data: String,
+    },
+
+    /// The queue state has changed
+    QueueState { state: QueueState },
+
+    /// Events after the given one are no longer retained. The client should
+    /// fetch the current state instead.
+    EventsLost { after: u64 },
+}
+
+impl EventKind {
+    /// Name of the event, used as SSE event type
+    fn name(&self) -> &'static str {
+        match self {
+            EventKind::JobSubmitted { .. } => "JobSubmitted",
+            EventKind::JobStarted { .. } => "JobStarted",
+            EventKind::JobFinished { .. } => "JobFinished",
+            EventKind::JobRemoved { .. } => "JobRemoved",
+            EventKind::JobChanged { .. } => "JobChanged",
+            EventKind::Output { .. } => "Output",
+            EventKind::QueueState { .. } => "QueueState",
+            EventKind::EventsLost { .. } => "EventsLost",
+        }
+    }
+
+    /// ID of the job the event is about, if any
+    fn job_id(&self) -> Option<u64> {
+        match self {
+            EventKind::JobSubmitted { job }
+            | EventKind::JobStarted { job }
+            | EventKind::JobFinished { job }
+            | EventKind::JobChanged { job } => Some(job.id),
+            EventKind::JobRemoved { id } | EventKind::Output { id, .. } => Some(*id),
+            EventKind::QueueState { .. } | EventKind::EventsLost { .. } => None,
+        }
+    }
+}
+
+/// A numbered event
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    /// Number of the event, also used as resume cursor
+    pub id: u64,
+
+    /// Time of the event, RFC 3339 in UTC
+    pub time: String,
+
+    /// What happened
+    pub event: EventKind,
+}
+
+impl Event {
+    fn new(id: u64, event: EventKind) -> Event {
+        Event {
+            id,
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+        }
+    }
+}
+
+/// Encoding of an event stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    /// Server-Sent Events (text/event-stream)
+    Sse,
+
+    /// One JSON object per line
+    JsonLines,
+}
+
+impl StreamFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::Sse => "text/event-stream",
+            StreamFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    fn encode(self, event: &Event) -> String {
+        let json = serde_json::to_string(event).unwrap();
+        match self {
+            StreamFormat::Sse => format!(
+                "id: {}\nevent: {}\ndata: {}\n\n",
+                event.id,
+                event.event.name(),
+                json
+            ),
+            StreamFormat::JsonLines => format!("{}\n", json),
+        }
+    }
+
+    fn heartbeat(self) -> &'static str {
+        match self {
+            StreamFormat::Sse => ": heartbeat\n\n",
+            StreamFormat::JsonLines => "\n",
+        }
+    }
+}
+
+/// What a client asked to receive
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    /// Only events about these jobs, all events if empty
+    pub jobs: Vec<u64>,
+
+    /// Continue after this event, only new events if not given
+    pub cursor: Option<u64>,
+
+    /// Encoding of the stream
+    pub format: StreamFormat,
+}
+
+/// The retained events
+struct EventLog {
+    events: VecDeque<Event>,
+    next_id: u64,
+}
+
+impl EventLog {
+    /// Events after the cursor. Starts with an EventsLost event if some of
+    /// them are no longer retained or the cursor is from an earlier run of
+    /// the daemon.
+    fn since(&self, cursor: u64) -> Vec<Event> {
+        let first = self.events.front().map_or(self.next_id, |e| e.id);
+        let mut events = Vec::new();
+        if cursor.saturating_add(1) < first || cursor >= self.next_id {
+            events.push(Event::new(
+                first - 1,
+                EventKind::EventsLost { after: cursor },
+            ));
+        }
+        events.extend(self.events.iter().filter(|e| e.id > cursor).cloned());
+        events
+    }
+}
+
+/// Distributes events to all subscribers
+#[derive(Clone)]
+pub struct EventBus {
+    log: Arc<(Mutex<EventLog>, Condvar)>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+impl EventBus {
+    /// Creates an empty bus. Event numbers start at the current time in
+    /// microseconds, so that they keep increasing across daemon restarts.
+    pub fn new() -> EventBus {
+        let first_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(1);
+        let log = EventLog {
+            events: VecDeque::new(),
+            next_id: first_id,
+        };
+        EventBus {
+            log: Arc::new((Mutex::new(log), Condvar::new())),
+        }
+    }
+
+    /// Numbers the event, retains it and wakes up all subscribers
+    pub fn publish(&self, event: EventKind) {
+        let (ref log, ref cvar) = *self.log;
+        let mut log = log.lock().unwrap();
+        let event = Event::new(log.next_id, event);
+        log.next_id += 1;
+        if log.events.len() == EVENT_BACKLOG {
+            log.events.pop_front();
+        }
+        log.events.push_back(event);
+        cvar.notify_all();
+    }
+
+    /// Number of the latest event
+    pub fn last_id(&self) -> u64 {
+        self.log.0.lock().unwrap().next_id - 1
+    }
+
+    /// Events after the cursor, waiting up to the timeout for new ones
+    pub fn wait(&self, cursor: u64, timeout: Duration) -> Vec<Event> {
+        let (ref log, ref cvar) = *self.log;
+        let mut log = log.lock().unwrap();
+        if log.next_id == cursor.saturating_add(1) {
+            log = cvar.wait_timeout(log, timeout).unwrap().0;
+        }
+        log.since(cursor)
+    }
+
+    /// Sends the events of the subscription as chunked HTTP response until
+    /// the client disconnects
+    pub fn stream<W: Write>(&self, out: &mut W, subscription: Subscription) -> Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            subscription.format.content_type()
+        )?;
+        out.flush()?;
+
+        let mut cursor = subscription.cursor.unwrap_or_else(|| self.last_id());
+        let mut last_write = Instant::now();
+        loop {
+            let mut chunk = String::new();
+            for event in self.wait(cursor, HEARTBEAT_INTERVAL) {
+                cursor = event.id;
+                let wanted = match event.event.job_id() {
+                    Some(id) => subscription.jobs.is_empty() || subscription.jobs.contains(&id),
+                    None => true,
+                };
+                if wanted {
+                    chunk.push_str(&subscription.format.encode(&event));
+                }
+            }
+            if chunk.is_empty() {
+                if last_write.elapsed() < HEARTBEAT_INTERVAL {
+                    continue;
+                }
+                chunk.push_str(subscription.format.heartbeat());
+            }
+
+            write!(out, "{:x}\r\n{}\r\n", chunk.len(), chunk)?;
+            out.flush()?;
+            last_write = Instant::now();
+        }
+    }
+
+    /// Reads a job's stdout or stderr to its end and publishes the output as
+    /// it arrives. Returns the complete output.
+    pub fn forward_output<R: Read>(&self, mut reader: R, id: u64, stream: OutputStream) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut pending = Vec::new();
+        let mut buf = [0; OUTPUT_CHUNK_SIZE];
+
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            output.extend_from_slice(&buf[..n]);
+            pending.extend_from_slice(&buf[..n]);
+
+            // hold back a character split between two reads
+            let complete = match str::from_utf8(&pending) {
+                Ok(_) => pending.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(_) => pending.len(),
+            };
+            if complete > 0 {
+                let data = String::from_utf8_lossy(&pending[..complete]).to_string();
+                pending.drain(..complete);
+                self.publish(EventKind::Output { id, stream, data });
+            }
+        }
+
+        if !pending.is_empty() {
+            let data = String::from_utf8_lossy(&pending).to_string();
+            self.publish(EventKind::Output { id, stream, data });
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::thread;
+
+    fn output(id: u64, data: &str) -> EventKind {
+        EventKind::Output {
+            id,
+            stream: OutputStream::Stdout,
+            data: data.to_string(),
+        }
+    }
+
+    fn since(bus: &EventBus, cursor: u64) -> Vec<Event> {
+        bus.log.0.lock().unwrap().since(cursor)
+    }
+
+    /// Data of the Output events, or the cursor of an EventsLost event
+    fn summary(events: &[Event]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| match e.event {
+                EventKind::Output { ref data, .. } => data.clone(),
+                EventKind::EventsLost { after } => format!("lost after {}", after),
+                ref other => other.name().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resumes_after_cursor() {
+        let bus = EventBus::new();
+        let start = bus.last_id();
+        assert!(since(&bus, start).is_empty());
+
+        for data in &["a", "b", "c"] {
+            bus.publish(output(1, data));
+        }
+        assert_eq!(bus.last_id(), start + 3);
+        assert_eq!(summary(&since(&bus, start)), vec!["a", "b", "c"]);
+        assert_eq!(summary(&since(&bus, start + 2)), vec!["c"]);
+        assert!(since(&bus, start + 3).is_empty());
+        assert_eq!(since(&bus, start + 1)[0].id, start + 2);
+    }
+
+    #[test]
+    fn cursor_older_than_backlog() {
+        let bus = EventBus::new();
+        let start = bus.last_id();
+        for i in 0..EVENT_BACKLOG + 5 {
+            bus.publish(output(1, &i.to_string()));
+        }
+
+        // the first five events are no longer retained
+        let first = start + 6;
+        let events = since(&bus, start + 1);
+        assert_eq!(events.len(), EVENT_BACKLOG + 1);
+        assert_eq!(events[0].id, first - 1);
+        assert_eq!(
+            summary(&events[..2]),
+            vec![format!("lost after {}", start + 1), "5".to_string()]
+        );
+        assert_eq!(events[1].id, first);
+
+        // the oldest retained event directly follows the cursor
+        let events = since(&bus, first - 1);
+        assert_eq!(events.len(), EVENT_BACKLOG);
+        assert_eq!(summary(&events[..1]), vec!["5"]);
+    }
+
+    #[test]
+    fn cursor_from_other_run() {
+        let bus = EventBus::new();
+        let start = bus.last_id();
+
+        // an earlier run, before any event of this one
+        let events = since(&bus, 5);
+        assert_eq!(summary(&events), vec!["lost after 5"]);
+        assert_eq!(events[0].id, start);
+
+        // a later run, i.e. a client of a daemon whose clock went back
+        bus.publish(output(1, "a"));
+        let events = since(&bus, start + 100);
+        assert_eq!(
+            summary(&events),
+            vec![format!("lost after {}", start + 100)]
+        );
+        assert_eq!(events[0].id, start);
+    }
+
+    #[test]
+    fn waits_for_events() {
+        let bus = EventBus::new();
+        let start = bus.last_id();
+        let timeout = Duration::from_millis(50);
+        assert!(bus.wait(start, timeout).is_empty());
+
+        let publisher = bus.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            publisher.publish(output(1, "late"));
+        });
+        let events = bus.wait(start, Duration::from_secs(10));
+        assert_eq!(summary(&events), vec!["late"]);
+        handle.join().unwrap();
+
+        // retained events are returned without waiting
+        assert_eq!(
+            summary(&bus.wait(start, Duration::from_secs(10))),
+            vec!["late"]
+        );
+    }
+
+    /// Collects everything written and fails once the given number of
+    /// chunks has been flushed, ending the stream
+    struct Capture {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.flushes = self.flushes.saturating_sub(1);
+            if self.flushes == 0 {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streams_events_of_jobs() {
+        let bus = EventBus::new();
+        let start = bus.last_id();
+        bus.publish(output(1, "one"));
+        bus.publish(output(2, "two"));
+        bus.publish(EventKind::QueueState {
+            state: QueueState::Stopping,
+        });
+        bus.publish(EventKind::JobRemoved { id: 1 });
+
+        // the header and a single chunk
+        let mut out = Capture {
+            data: Vec::new(),
+            flushes: 2,
+        };
+        let subscription = Subscription {
+            jobs: vec![1],
+            cursor: Some(start),
+            format: StreamFormat::JsonLines,
+        };
+        assert!(bus.stream(&mut out, subscription).is_err());
+
+        let text = String::from_utf8(out.data).unwrap();
+        let (head, body) = text.split_at(text.find("\r\n\r\n").unwrap() + 4);
+        assert!(
+            head.contains("Content-Type: application/x-ndjson\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{}", head);
+
+        let (size, chunk) = body.split_at(body.find("\r\n").unwrap());
+        let chunk = &chunk[2..chunk.len() - 2];
+        assert_eq!(usize::from_str_radix(size, 16).unwrap(), chunk.len());
+        let events: Vec<Event> = chunk
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(summary(&events), vec!["one", "QueueState", "JobRemoved"]);
+        assert_eq!(events[2].id, start + 4);
+    }
+
+    /// Returns the given pieces in as many reads
+    struct Pieces(VecDeque<&'static [u8]>);
+
+    impl Read for Pieces {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match self.0.pop_front() {
+                Some(piece) => {
+                    buf[..piece.len()].copy_from_slice(piece);
+                    Ok(piece.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn output_keeps_characters_whole() {
+        let bus = EventBus::new();
+        let start = bus.last_id();
+
+        // 'ä' is 0xc3 0xa4, '€' is 0xe2 0x82 0xac
+        let pieces = vec![&b"a\xc3"[..], b"\xa4b\xe2", b"\x82", b"\xac!\xe2"];
+        let output = bus.forward_output(Pieces(pieces.into()), 1, OutputStream::Stdout);
+        assert_eq!(output, b"a\xc3\xa4b\xe2\x82\xac!\xe2".to_vec());
+
+        // an incomplete character at the end is sent as replacement
+        assert_eq!(
+            summary(&since(&bus, start)),
+            vec!["a", "äb", "€!", "\u{fffd}"]
+        );
+    }
+}
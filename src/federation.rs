@@ -0,0 +1,476 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * federation.rs
+ *
+ * Client commands spanning the daemons of all server profiles. Requests are
+ * sent to all daemons concurrently and the answers are merged into a single
+ * listing, tagged with the profile name. A daemon that cannot be reached or
+ * fails otherwise is reported in a warning row instead of failing the whole
+ * command.
+ **/
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::thread;
+
+use qmanager::client::QmanagerClient;
+use qmanager::clierror::{ClientError, Result};
+use qmanager::job_queue::{Job, QueueState};
+use qmanager::listing::JobFilter;
+use qmanager::protocol::{ApiError, ErrorCode};
+
+use output::{self, JobRecord, OutputFormat};
+use profiles::{ClientConfig, Profile};
+
+/// A job ID, optionally qualified with the profile of its daemon, i.e.
+/// 'gwas1:42'
+#[derive(Debug, Clone)]
+pub struct JobRef {
+    pub host: Option<String>,
+    pub id: u64,
+}
+
+impl FromStr for JobRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (host, id) = match s.rfind(':') {
+            Some(i) => (Some(s[..i].to_string()), &s[i + 1..]),
+            None => (None, s),
+        };
+        let id = id
+            .parse()
+            .map_err(|_| format!("Invalid job ID '{}', expected i.e. 'host:42' or '42'", s))?;
+        Ok(JobRef { host, id })
+    }
+}
+
+/// Jobs of one daemon
+struct HostJobs {
+    queue_state: Option<QueueState>,
+    jobs: Vec<Job>,
+
+    /// Number of matching jobs, on all pages
+    total: usize,
+}
+
+/// A daemon's part of a listing as printed in JSON and YAML
+#[derive(Serialize)]
+struct HostDocument {
+    host: String,
+    queue_state: Option<QueueState>,
+    jobs: Vec<JobRecord>,
+    total: usize,
+
+    /// Why the daemon's jobs are missing
+    error: Option<String>,
+}
+
+/// The profiles to query, all of them unless a single one is given by
+/// name or host name
+fn select_profiles(config: &ClientConfig, host: Option<&str>) -> Result<BTreeMap<String, Profile>> {
+    if config.profiles.is_empty() {
+        return Err(ClientError::Config(format!(
+            "No server profiles configured in {:?}",
+            config.path
+        )));
+    }
+
+    let host = match host {
+        Some(host) => host,
+        None => return Ok(config.profiles.clone()),
+    };
+    let selected: BTreeMap<String, Profile> = config
+        .profiles
+        .iter()
+        .filter(|(name, profile)| {
+            name.eq_ignore_ascii_case(host) || profile.host.as_deref() == Some(host)
+        })
+        .map(|(name, profile)| (name.clone(), profile.clone()))
+        .collect();
+    if selected.is_empty() {
+        return Err(ClientError::Config(format!(
+            "Unknown host '{}', expected one of {}",
+            host,
+            config
+                .profiles
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        )));
+    }
+    Ok(selected)
+}
+
+/// Sends requests to the daemons of all given profiles concurrently and
+/// returns their answers by profile name
+fn fan_out<T, F>(
+    profiles: &BTreeMap<String, Profile>,
+    dump_protocol: bool,
+    request: F,
+) -> Vec<(String, Result<T>)>
+where
+    T: Send,
+    F: Fn(&QmanagerClient) -> Result<T> + Sync,
+{
+    let request = &request;
+    thread::scope(|scope| {
+        let threads: Vec<_> = profiles
+            .iter()
+            .map(|(name, profile)| {
+                let thread = scope.spawn(move || {
+                    profile
+                        .connect(dump_protocol)
+                        .and_then(|client| request(&client))
+                });
+                (name.clone(), thread)
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|(name, thread)| (name, thread.join().unwrap()))
+            .collect()
+    })
+}
+
+/// Prints the merged results of all daemons. Fails with the error of the
+/// first daemon if none has answered.
+fn print_results(
+    results: Vec<(String, Result<HostJobs>)>,
+    with_output: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    match format {
+        OutputFormat::Table => {
+            let mut header = vec!["HOST"];
+            header.extend_from_slice(output::JOB_TABLE_HEADER);
+            let mut rows = Vec::new();
+            for (host, result) in &results {
+                match result {
+                    Ok(host_jobs) => rows.extend(host_jobs.jobs.iter().map(|job| {
+                        let mut row = vec![host.clone()];
+                        row.extend(output::job_table_row(job));
+                        row
+                    })),
+                    Err(e) => rows.push(vec![
+                        host.clone(),
+                        "-".to_string(),
+                        format!("warning: {}", e),
+                    ]),
+                }
+            }
+            output::print_table(&header, &rows);
+
+            if with_output {
+                let jobs = results
+                    .iter()
+                    .filter_map(|(host, r)| r.as_ref().ok().map(|h| (host, &h.jobs)));
+                for (host, jobs) in jobs {
+                    for job in jobs {
+                        println!(
+                            "\n--- {} job #{} stdout ---\n{}",
+                            host,
+                            job.id,
+                            job.stdout.trim_end()
+                        );
+                        println!(
+                            "\n--- {} job #{} stderr ---\n{}",
+                            host,
+                            job.id,
+                            job.stderr.trim_end()
+                        );
+                    }
+                }
+            }
+        }
+        OutputFormat::Csv => {
+            let mut records = Vec::new();
+            for (host, result) in &results {
+                match result {
+                    Ok(host_jobs) => records.extend(
+                        host_jobs
+                            .jobs
+                            .iter()
+                            .map(|job| (host.as_str(), JobRecord::new(job, with_output))),
+                    ),
+                    Err(e) => eprintln!("warning: {}: {}", host, e),
+                }
+            }
+            output::print_host_job_csv(&records);
+        }
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let documents: Vec<HostDocument> = results
+                .iter()
+                .map(|(host, result)| match result {
+                    Ok(host_jobs) => HostDocument {
+                        host: host.clone(),
+                        queue_state: host_jobs.queue_state,
+                        jobs: host_jobs
+                            .jobs
+                            .iter()
+                            .map(|job| JobRecord::new(job, with_output))
+                            .collect(),
+                        total: host_jobs.total,
+                        error: None,
+                    },
+                    Err(e) => HostDocument {
+                        host: host.clone(),
+                        queue_state: None,
+                        jobs: Vec::new(),
+                        total: 0,
+                        error: Some(e.to_string()),
+                    },
+                })
+                .collect();
+            output::print_document(format, &json!({ "hosts": documents }));
+        }
+    }
+
+    if results.iter().all(|(_, r)| r.is_err()) {
+        if let Some((_, Err(e))) = results.into_iter().next() {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Lists the jobs of all daemons matching the filter
+pub fn handle_status(
+    config: &ClientConfig,
+    filter: JobFilter,
+    dump_protocol: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let profiles = select_profiles(config, None)?;
+    let with_output = filter.with_output;
+    let results = fan_out(&profiles, dump_protocol, |client| {
+        let queue_state = client.queue_state()?;
+        let page = client.list(filter.clone())?;
+        Ok(HostJobs {
+            queue_state: Some(queue_state),
+            jobs: page.jobs,
+            total: page.total,
+        })
+    });
+    print_results(results, with_output, format)
+}
+
+/// Looks up a job on all daemons or on the given one. Daemons that do not
+/// know the job are left out.
+pub fn handle_find(
+    config: &ClientConfig,
+    job: JobRef,
+    dump_protocol: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let profiles = select_profiles(config, job.host.as_deref())?;
+    let results: Vec<(String, Result<HostJobs>)> = fan_out(&profiles, dump_protocol, |client| {
+        match client.get_job(job.id, false, false) {
+            Ok(found) => Ok(Some(found)),
+            Err(ClientError::Api { ref error, .. }) if error.code == ErrorCode::NoSuchJob => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    })
+    .into_iter()
+    .filter_map(|(host, result)| match result {
+        Ok(Some(found)) => Some((
+            host,
+            Ok(HostJobs {
+                queue_state: None,
+                jobs: vec![found],
+                total: 1,
+            }),
+        )),
+        Ok(None) => None,
+        Err(e) => Some((host, Err(e))),
+    })
+    .collect();
+
+    if results.is_empty() {
+        return Err(ClientError::api(
+            &format!("Could not find job {}", job.id),
+            ApiError::new(ErrorCode::NoSuchJob, "No such job on any daemon"),
+        ));
+    }
+    print_results(results, false, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+
+    use qmanager::job_queue::JobState;
+
+    fn profile(host: Option<&str>, socket: Option<&str>) -> Profile {
+        Profile {
+            host: host.map(str::to_string),
+            socket: socket.map(PathBuf::from),
+            ..Profile::default()
+        }
+    }
+
+    fn config(profiles: Vec<(&str, Profile)>) -> ClientConfig {
+        ClientConfig {
+            default: None,
+            profiles: profiles
+                .into_iter()
+                .map(|(name, profile)| (name.to_string(), profile))
+                .collect(),
+            path: PathBuf::from("client.toml"),
+        }
+    }
+
+    fn job(id: u64) -> Job {
+        Job {
+            id,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: None,
+            finished: None,
+            stderr: String::new(),
+            stdout: String::new(),
+            state: JobState::Queued,
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    #[test]
+    fn parses_job_refs() {
+        let job: JobRef = "42".parse().unwrap();
+        assert_eq!((job.host, job.id), (None, 42));
+        let job: JobRef = "gwas1:42".parse().unwrap();
+        assert_eq!((job.host.as_deref(), job.id), (Some("gwas1"), 42));
+        let job: JobRef = "gwas1.example.org:7".parse().unwrap();
+        assert_eq!(job.host.as_deref(), Some("gwas1.example.org"));
+
+        for invalid in &["", "gwas1", "gwas1:", "gwas1:-1", "gwas1:42:x"] {
+            let e = invalid.parse::<JobRef>().unwrap_err();
+            assert!(e.starts_with("Invalid job ID"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn selects_profiles_by_name_or_host() {
+        let config = config(vec![
+            ("gwas1", profile(Some("gwas1.example.org"), None)),
+            ("gwas2", profile(Some("gwas2.example.org"), None)),
+            ("local", profile(None, Some("/run/qmanager/qmanager.sock"))),
+        ]);
+
+        let all = select_profiles(&config, None).unwrap();
+        assert_eq!(
+            all.keys().collect::<Vec<_>>(),
+            vec!["gwas1", "gwas2", "local"]
+        );
+        let by_name = select_profiles(&config, Some("GWAS2")).unwrap();
+        assert_eq!(by_name.keys().collect::<Vec<_>>(), vec!["gwas2"]);
+        let by_host = select_profiles(&config, Some("gwas1.example.org")).unwrap();
+        assert_eq!(by_host.keys().collect::<Vec<_>>(), vec!["gwas1"]);
+
+        match select_profiles(&config, Some("gwas3")) {
+            Err(ClientError::Config(msg)) => {
+                assert_eq!(
+                    msg,
+                    "Unknown host 'gwas3', expected one of gwas1, gwas2, local"
+                )
+            }
+            other => panic!("unexpected result {:?}", other.map(|p| p.len())),
+        }
+        match select_profiles(&ClientConfig::default(), None) {
+            Err(ClientError::Config(msg)) => assert!(msg.starts_with("No server profiles")),
+            other => panic!("unexpected result {:?}", other.map(|p| p.len())),
+        }
+    }
+
+    #[test]
+    fn fails_only_if_all_daemons_fail() {
+        let failed = || -> Result<HostJobs> {
+            Err(ClientError::Connect {
+                address: "gwas1.example.org:1337".to_string(),
+                reason: "Connection refused".to_string(),
+            })
+        };
+        let answered = || -> Result<HostJobs> {
+            Ok(HostJobs {
+                queue_state: Some(QueueState::Running),
+                jobs: vec![job(1), job(2)],
+                total: 2,
+            })
+        };
+
+        for format in &[OutputFormat::Table, OutputFormat::Csv, OutputFormat::Json] {
+            let some = vec![
+                ("gwas1".to_string(), failed()),
+                ("gwas2".to_string(), answered()),
+            ];
+            assert!(print_results(some, false, *format).is_ok());
+
+            let all = vec![
+                ("gwas1".to_string(), failed()),
+                (
+                    "gwas2".to_string(),
+                    Err(ClientError::Protocol("garbage".to_string())),
+                ),
+            ];
+            match print_results(all, false, *format) {
+                Err(ClientError::Connect { address, .. }) => {
+                    assert_eq!(address, "gwas1.example.org:1337")
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn unreachable_daemons() {
+        let socket =
+            std::env::temp_dir().join(format!("qmanager-gone-{}.sock", std::process::id()));
+        let config = config(vec![
+            ("gwas1", profile(None, socket.to_str())),
+            // neither a CA certificate nor 'insecure'
+            ("gwas2", profile(Some("gwas2.example.org"), None)),
+        ]);
+        let profiles = select_profiles(&config, None).unwrap();
+        let results = fan_out(&profiles, false, |client| client.queue_state());
+        assert_eq!(results.len(), 2);
+        assert!(
+            matches!(results[0], (ref host, Err(ClientError::Connect { .. })) if host == "gwas1")
+        );
+        assert!(matches!(results[1], (ref host, Err(ClientError::Config(_))) if host == "gwas2"));
+
+        let job = JobRef { host: None, id: 42 };
+        assert!(matches!(
+            handle_find(&config, job, false, OutputFormat::Table),
+            Err(ClientError::Connect { .. })
+        ));
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * hooks.rs
+ *
+ * Server-side commands that are run before a job is started and after it
+ * has finished, either for all jobs or for jobs of a specific appkey. Each
+ * hook receives the job as JSON on stdin. A failing pre hook prevents the
+ * job from being started, failing post hooks are only logged.
+ **/
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use job_queue::Job;
+
+/// Time a hook may take before it is killed
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval in which a running hook is checked for termination
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A single hook executable
+#[derive(Debug, Clone)]
+pub struct Hook {
+    /// Executable to run
+    pub path: PathBuf,
+
+    /// Time after which the hook is killed and considered failed
+    pub timeout: Duration,
+}
+
+/// The hooks configured for all jobs or for the jobs of an appkey
+#[derive(Debug, Clone, Default)]
+pub struct HookSet {
+    /// Run before the job is started
+    pub pre: Option<Hook>,
+
+    /// Run after the job has finished
+    pub post: Option<Hook>,
+}
+
+/// All hooks known to the daemon
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// Hooks run for every job
+    pub global: HookSet,
+
+    /// Hooks run for jobs of the given appkeys only
+    pub appkeys: HashMap<String, HookSet>,
+}
+
+impl Hooks {
+    /// Pre hooks for a job of the given appkey, the global one first
+    pub fn pre(&self, appkey: &str) -> Vec<&Hook> {
+        let own = self.appkeys.get(appkey).and_then(|h| h.pre.as_ref());
+        self.global.pre.iter().chain(own).collect()
+    }
+
+    /// Post hooks for a job of the given appkey, the global one last
+    pub fn post(&self, appkey: &str) -> Vec<&Hook> {
+        let own = self.appkeys.get(appkey).and_then(|h| h.post.as_ref());
+        own.into_iter().chain(self.global.post.iter()).collect()
+    }
+}
+
+impl Hook {
+    /// Runs the hook with the job as JSON on stdin. Fails if the hook
+    /// cannot be started, exceeds its timeout or exits unsuccessfully. In
+    /// the latter case, the error message is the hook's stderr output.
+    pub fn run(&self, job: &Job) -> Result<()> {
+        let input = serde_json::to_vec(job)?;
+
+        let mut child = Command::new(&self.path)
+            .current_dir("/")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::new(e.kind(), format!("Cannot run hook {:?}: {}", self.path, e)))?;
+
+        // feed stdin and drain stderr in the background, so that a hook
+        // that does not read its input or writes a lot cannot block us
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let (written_tx, written) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+            let _ = written_tx.send(());
+        });
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let (output_tx, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut s = Vec::new();
+            let _ = stderr.read_to_end(&mut s);
+            let _ = output_tx.send(String::from_utf8_lossy(&s).trim().to_string());
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "Hook {:?} timed out after {}",
+                        self.path,
+                        humantime::format_duration(self.timeout)
+                    ),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        // processes the hook left running in the background may hold on to
+        // its stdin and stderr, so these are given up on at the deadline
+        let remaining = || deadline.saturating_duration_since(Instant::now());
+        if written.recv_timeout(remaining()).is_err() {
+            warn!("Hook {:?} did not read all of its input", self.path);
+        }
+        let stderr = output.recv_timeout(remaining()).unwrap_or_else(|_| {
+            warn!("Hook {:?} left its stderr open after exiting", self.path);
+            String::new()
+        });
+
+        if status.success() {
+            Ok(())
+        } else if stderr.is_empty() {
+            Err(Error::other(format!(
+                "Hook {:?} exited with {}",
+                self.path, status
+            )))
+        } else {
+            Err(Error::other(stderr))
+        }
+    }
+}
+
+/// Parses a hook timeout given either in seconds or as a human-readable
+/// duration such as '5min'
+pub fn parse_timeout(s: &str) -> std::result::Result<Duration, String> {
+    s.parse::<u64>()
+        .map(Duration::from_secs)
+        .or_else(|_| humantime::parse_duration(s))
+        .map_err(|_| format!("Invalid hook timeout '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use std::time::UNIX_EPOCH;
+
+    use job_queue::JobState;
+
+    fn job() -> Job {
+        Job {
+            id: 7,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: None,
+            finished: None,
+            stderr: String::new(),
+            stdout: String::new(),
+            state: JobState::Running,
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: vec!["cohort-a".to_string()],
+            held: false,
+        }
+    }
+
+    /// Writes an executable shell script for the test of the given name
+    fn hook(name: &str, script: &str, timeout: Duration) -> Hook {
+        let path = env::temp_dir().join(format!("qmanager-hook-{}-{}", name, process::id()));
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        Hook { path, timeout }
+    }
+
+    #[test]
+    fn receives_job_on_stdin() {
+        let output = env::temp_dir().join(format!("qmanager-hook-stdin-{}.json", process::id()));
+        let hook = hook(
+            "input",
+            &format!("cat > {}", output.display()),
+            DEFAULT_HOOK_TIMEOUT,
+        );
+        hook.run(&job()).unwrap();
+
+        let input: Job = serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(input.id, 7);
+        assert_eq!(input.cmdline, "gwas --chr 1");
+        assert_eq!(input.tags, vec!["cohort-a"]);
+
+        let _ = fs::remove_file(&output);
+        let _ = fs::remove_file(&hook.path);
+    }
+
+    #[test]
+    fn fails_with_stderr() {
+        let hook = hook(
+            "fail",
+            "echo 'quota exceeded' >&2\nexit 3",
+            DEFAULT_HOOK_TIMEOUT,
+        );
+        let e = hook.run(&job()).unwrap_err();
+        assert_eq!(e.to_string(), "quota exceeded");
+
+        let silent = self::hook("silent", "exit 3", DEFAULT_HOOK_TIMEOUT);
+        let e = silent.run(&job()).unwrap_err();
+        assert!(e.to_string().contains("exited with"), "{}", e);
+
+        let _ = fs::remove_file(&hook.path);
+        let _ = fs::remove_file(&silent.path);
+    }
+
+    #[test]
+    fn killed_after_timeout() {
+        let hook = hook("timeout", "exec sleep 30", Duration::from_millis(300));
+        let start = Instant::now();
+        let e = hook.run(&job()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let _ = fs::remove_file(&hook.path);
+    }
+
+    #[test]
+    fn background_process_does_not_block() {
+        // the background process inherits stdin and stderr
+        let hook = hook(
+            "background",
+            "sleep 30 &\nexit 0",
+            Duration::from_millis(500),
+        );
+        let start = Instant::now();
+        hook.run(&job()).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let _ = fs::remove_file(&hook.path);
+    }
+}
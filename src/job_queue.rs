@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -20,8 +20,10 @@
  * SOFTWARE.
  **/
 
+use std::fmt;
 use std::io::{Error, ErrorKind};
 use std::process::Command;
+use std::str::FromStr;
 use std::time::SystemTime;
 
 /// The current state of a single job
@@ -43,13 +45,177 @@ pub enum JobState {
     Failed(String),
 }
 
+/// Something that happened to a job and that can be notified of
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum JobEvent {
+    /// the job has been added to the queue
+    Queued,
+
+    /// the job has been started
+    Started,
+
+    /// the job has terminated with exit code 0
+    Succeeded,
+
+    /// the job has terminated with a non-zero exit code or could not be run
+    Failed,
+
+    /// the job has been killed by a signal
+    Killed,
+}
+
+impl JobEvent {
+    /// Events that targets are notified of if they do not ask for specific ones
+    pub const DEFAULT: [JobEvent; 3] = [JobEvent::Succeeded, JobEvent::Failed, JobEvent::Killed];
+
+    /// The event that corresponds to a job reaching the given state
+    pub fn of_state(state: &JobState) -> JobEvent {
+        match state {
+            JobState::Queued => JobEvent::Queued,
+            JobState::Running => JobEvent::Started,
+            JobState::Terminated(0) => JobEvent::Succeeded,
+            JobState::Terminated(_) | JobState::Failed(_) => JobEvent::Failed,
+            JobState::Killed(_) => JobEvent::Killed,
+        }
+    }
+
+    /// Whether the event marks the end of a job
+    pub fn is_terminal(self) -> bool {
+        !matches!(self, JobEvent::Queued | JobEvent::Started)
+    }
+}
+
+impl fmt::Display for JobEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            JobEvent::Queued => "queued",
+            JobEvent::Started => "started",
+            JobEvent::Succeeded => "succeeded",
+            JobEvent::Failed => "failed",
+            JobEvent::Killed => "killed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for JobEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "queued" => Ok(JobEvent::Queued),
+            "started" => Ok(JobEvent::Started),
+            "succeeded" => Ok(JobEvent::Succeeded),
+            "failed" => Ok(JobEvent::Failed),
+            "killed" => Ok(JobEvent::Killed),
+            _ => Err(format!(
+                "Invalid event '{}', expected one of queued, started, succeeded, failed, killed",
+                s
+            )),
+        }
+    }
+}
+
+/// A URL to be notified of certain events of a job
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct NotifyTarget {
+    /// URL to call
+    pub url: String,
+
+    /// Events to call the URL for. If empty, the URL is called once the
+    /// job has finished, regardless of its result.
+    #[serde(default)]
+    pub events: Vec<JobEvent>,
+}
+
+impl NotifyTarget {
+    /// Whether the target wants to be notified of the given event
+    pub fn wants(&self, event: JobEvent) -> bool {
+        if self.events.is_empty() {
+            JobEvent::DEFAULT.contains(&event)
+        } else {
+            self.events.contains(&event)
+        }
+    }
+}
+
+/// A job as submitted by a client. Older clients submit the bare command
+/// line, which is still accepted and produced whenever possible.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "SubmissionRepr", into = "SubmissionRepr")]
+pub struct Submission {
+    /// Command to be executed, starting with the appkey
+    pub cmdline: String,
+
+    /// Targets to notify of job events. If empty, the daemon's notify URL
+    /// is called once the job has finished.
+    pub notify: Vec<NotifyTarget>,
+
+    /// Free-form labels to find the job by
+    pub tags: Vec<String>,
+}
+
+/// Wire format of a submission
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum SubmissionRepr {
+    Cmdline(String),
+    Full {
+        cmdline: String,
+        #[serde(default)]
+        notify: Vec<NotifyTarget>,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+}
+
+impl From<SubmissionRepr> for Submission {
+    fn from(r: SubmissionRepr) -> Self {
+        match r {
+            SubmissionRepr::Cmdline(cmdline) => Submission {
+                cmdline,
+                notify: Vec::new(),
+                tags: Vec::new(),
+            },
+            SubmissionRepr::Full {
+                cmdline,
+                notify,
+                tags,
+            } => Submission {
+                cmdline,
+                notify,
+                tags,
+            },
+        }
+    }
+}
+
+impl From<Submission> for SubmissionRepr {
+    fn from(s: Submission) -> Self {
+        if s.notify.is_empty() && s.tags.is_empty() {
+            SubmissionRepr::Cmdline(s.cmdline)
+        } else {
+            SubmissionRepr::Full {
+                cmdline: s.cmdline,
+                notify: s.notify,
+                tags: s.tags,
+            }
+        }
+    }
+}
+
 /// The reason a job-specific command could not be processed
+#[derive(Debug)]
 pub enum FailReason {
     /// The job is in the wrong state (i.e. removing a running job)
     WrongJobState,
 
     /// There is no such job
     NoSuchJob,
+
+    /// The operating system failed to carry out the command
+    Os(Error),
 }
 
 /// The state of the job queue
@@ -94,6 +260,22 @@ pub struct Job {
 
     /// PID of the process (only if running or finished)
     pub pid: Option<u32>,
+
+    /// Local user that submitted the job (only known for Unix socket clients)
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    /// Targets to notify of job events
+    #[serde(default)]
+    pub notify: Vec<NotifyTarget>,
+
+    /// Free-form labels given at submission
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Held jobs stay queued, but are skipped by the scheduler
+    #[serde(default)]
+    pub held: bool,
 }
 
 /// The Job Queue itself
@@ -147,7 +329,9 @@ impl JobQueue {
             new_state,
             self.queue.len()
         );
-        if new_state == QueueState::Stopping && self.queue.is_empty() {
+        if new_state == QueueState::Stopping
+            && !self.queue.iter().any(|j| j.state == JobState::Running)
+        {
             new_state = QueueState::Stopped;
         }
         self.state = new_state;
@@ -167,13 +351,13 @@ impl JobQueue {
                         panic!("Cannot manually set a job to Running, Terminated or Killed state")
                     }
                     JobState::Queued => {
-                        self.queue.first_mut().map(|j| {
+                        if let Some(j) = self.queue.first_mut() {
                             j.started = None;
                             j.state = JobState::Queued;
                             j.pid = None;
                             j.stderr = String::from("");
                             j.stdout = String::from("");
-                        });
+                        }
                     }
                     JobState::Failed(s) => {
                         self.finish(JobState::Failed(s), "".to_owned(), "".to_owned());
@@ -183,11 +367,20 @@ impl JobQueue {
         }
     }
 
-    /// Submits a new job to the queue and returns the assigned ID
-    pub fn submit(&mut self, cmdline: String) -> u64 {
+    /// Returns the queued or finished job with the given ID
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.queue
+            .iter()
+            .chain(self.finished.iter())
+            .find(|j| j.id == id)
+    }
+
+    /// Submits a new job on behalf of the given owner to the queue and
+    /// returns the assigned ID
+    pub fn submit(&mut self, submission: Submission, owner: Option<String>) -> u64 {
         let job = Job {
             id: self.last_id + 1,
-            cmdline,
+            cmdline: submission.cmdline,
             scheduled: SystemTime::now(),
             started: None,
             finished: None,
@@ -195,6 +388,10 @@ impl JobQueue {
             stdout: String::from(""),
             state: JobState::Queued,
             pid: None,
+            owner,
+            notify: submission.notify,
+            tags: submission.tags,
+            held: false,
         };
 
         self.last_id += 1;
@@ -202,54 +399,98 @@ impl JobQueue {
         self.last_id
     }
 
-    /// Returns the topmost job of the "queued" queue if available.
-    /// The job is expected to be executed.
+    /// Returns the topmost job of the "queued" queue that is not held, if
+    /// available. The job is expected to be executed and is moved to the
+    /// front of the queue, where the running job is kept.
     pub fn schedule(&mut self) -> Option<Job> {
-        if self.state == QueueState::Running {
-            self.queue.first_mut().map(|j| {
-                j.started = Some(SystemTime::now());
-                j.state = JobState::Running;
-                j.clone()
-            })
-        } else {
-            None
+        if self.state != QueueState::Running {
+            return None;
         }
+
+        let index = self.queue.iter().position(|j| !j.held)?;
+        let mut j = self.queue.remove(index);
+        j.started = Some(SystemTime::now());
+        j.state = JobState::Running;
+        self.queue.insert(0, j.clone());
+        Some(j)
     }
 
-    /// Sends SIGTERM to the associated pid of the given job ID
-    pub fn send_sigterm(&mut self, jobid: u64) -> Result<(), Error> {
-        debug!("[job queue] Trying to kill job {}", jobid);
+    /// Holds or releases the queued job with the given ID. Running and
+    /// finished jobs cannot be held.
+    pub fn hold(&mut self, id: u64, held: bool) -> Result<Job, FailReason> {
+        match self.queue.iter_mut().find(|j| j.id == id) {
+            Some(j) if j.state == JobState::Queued => {
+                j.held = held;
+                Ok(j.clone())
+            }
+            Some(_) => Err(FailReason::WrongJobState),
+            None if self.finished.iter().any(|j| j.id == id) => Err(FailReason::WrongJobState),
+            None => Err(FailReason::NoSuchJob),
+        }
+    }
 
-        // find the currently running job
-        match self
+    /// Moves the queued job with the given ID to the given position among
+    /// the waiting jobs, 0 being the next one to run. Positions past the end
+    /// move the job to the end of the queue.
+    pub fn move_job(&mut self, id: u64, position: usize) -> Result<Job, FailReason> {
+        let index = match self.queue.iter().position(|j| j.id == id) {
+            Some(index) if self.queue[index].state == JobState::Queued => index,
+            Some(_) => return Err(FailReason::WrongJobState),
+            None if self.finished.iter().any(|j| j.id == id) => {
+                return Err(FailReason::WrongJobState)
+            }
+            None => return Err(FailReason::NoSuchJob),
+        };
+
+        let job = self.queue.remove(index);
+        let running = self
             .queue
             .iter()
-            .find(|j| j.state == JobState::Running && j.id == jobid)
-        {
-            Some(job) => {
-                // It is okay to panic here, as failure to execute /bin/kill is a serious bug
-                let status = Command::new("/bin/kill")
-                    .arg("-SIGTERM")
-                    .arg(job.pid.unwrap().to_string())
-                    .status()
-                    .expect("Failed to execute kill command");
-
-                if let Some(code) = status.code() {
-                    if code == 0 {
-                        Ok(())
-                    } else {
-                        error!("Could not kill job. Exit code: {}", code);
-                        Err(Error::from_raw_os_error(code))
-                    }
-                } else {
-                    error!("kill didn't leave an exit code");
-                    Err(Error::from(ErrorKind::Other))
-                }
-            }
+            .take_while(|j| j.state == JobState::Running)
+            .count();
+        let target = (running + position).min(self.queue.len());
+        self.queue.insert(target, job.clone());
+        Ok(job)
+    }
 
+    /// Sends SIGTERM to the associated pid of the given job ID. Fails with
+    /// WrongJobState if the job is not running or is still starting, i.e.
+    /// its pre hooks are running and there is no process to kill yet.
+    pub fn send_sigterm(&mut self, jobid: u64) -> Result<(), FailReason> {
+        debug!("[job queue] Trying to kill job {}", jobid);
+
+        // find the currently running job
+        let job = match self.queue.iter().find(|j| j.id == jobid) {
+            Some(job) => job,
+            None if self.finished.iter().any(|j| j.id == jobid) => {
+                return Err(FailReason::WrongJobState)
+            }
             None => {
                 warn!("Could not find job");
-                Err(Error::from(ErrorKind::InvalidInput))
+                return Err(FailReason::NoSuchJob);
+            }
+        };
+        let pid = match (&job.state, job.pid) {
+            (JobState::Running, Some(pid)) => pid,
+            _ => return Err(FailReason::WrongJobState),
+        };
+
+        // It is okay to panic here, as failure to execute /bin/kill is a serious bug
+        let status = Command::new("/bin/kill")
+            .arg("-SIGTERM")
+            .arg(pid.to_string())
+            .status()
+            .expect("Failed to execute kill command");
+
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(code) => {
+                error!("Could not kill job. Exit code: {}", code);
+                Err(FailReason::Os(Error::from_raw_os_error(code)))
+            }
+            None => {
+                error!("kill didn't leave an exit code");
+                Err(FailReason::Os(Error::from(ErrorKind::Other)))
             }
         }
     }
@@ -331,3 +572,113 @@ impl JobQueue {
         Err(FailReason::NoSuchJob)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A running queue with the given number of submitted jobs
+    fn queue(n: usize) -> JobQueue {
+        let mut queue = JobQueue::new(0);
+        for i in 0..n {
+            let submission = Submission {
+                cmdline: format!("echo {}", i),
+                notify: Vec::new(),
+                tags: Vec::new(),
+            };
+            queue.submit(submission, None);
+        }
+        queue
+    }
+
+    fn ids(queue: &JobQueue) -> Vec<u64> {
+        queue.iter_queued().map(|j| j.id).collect()
+    }
+
+    #[test]
+    fn schedule_skips_held_jobs() {
+        let mut queue = queue(3);
+        assert!(queue.hold(1, true).unwrap().held);
+
+        let job = queue.schedule().unwrap();
+        assert_eq!(job.id, 2);
+        assert_eq!(job.state, JobState::Running);
+        assert_eq!(ids(&queue), vec![2, 1, 3]);
+
+        queue.finish(JobState::Terminated(0), String::new(), String::new());
+        assert_eq!(queue.schedule().unwrap().id, 3);
+        queue.finish(JobState::Terminated(0), String::new(), String::new());
+        assert!(queue.schedule().is_none());
+
+        assert!(!queue.hold(1, false).unwrap().held);
+        assert_eq!(queue.schedule().unwrap().id, 1);
+    }
+
+    #[test]
+    fn hold_needs_queued_job() {
+        let mut queue = queue(3);
+        queue.schedule();
+        queue.finish(JobState::Terminated(0), String::new(), String::new());
+        queue.schedule();
+
+        // job 1 has finished, job 2 is running
+        assert!(matches!(
+            queue.hold(1, true),
+            Err(FailReason::WrongJobState)
+        ));
+        assert!(matches!(
+            queue.hold(2, true),
+            Err(FailReason::WrongJobState)
+        ));
+        assert!(matches!(queue.hold(4, true), Err(FailReason::NoSuchJob)));
+        assert!(queue.hold(3, true).is_ok());
+    }
+
+    #[test]
+    fn move_job_keeps_running_job_first() {
+        let mut queue = queue(4);
+        queue.schedule();
+
+        queue.move_job(4, 0).unwrap();
+        assert_eq!(ids(&queue), vec![1, 4, 2, 3]);
+
+        queue.move_job(4, 99).unwrap();
+        assert_eq!(ids(&queue), vec![1, 2, 3, 4]);
+
+        queue.move_job(2, 1).unwrap();
+        assert_eq!(ids(&queue), vec![1, 3, 2, 4]);
+
+        assert!(matches!(
+            queue.move_job(1, 2),
+            Err(FailReason::WrongJobState)
+        ));
+        assert!(matches!(queue.move_job(5, 0), Err(FailReason::NoSuchJob)));
+    }
+
+    #[test]
+    fn sigterm_needs_process() {
+        let mut queue = queue(2);
+        queue.schedule();
+
+        // the pre hooks of job 1 are still running
+        assert!(matches!(
+            queue.send_sigterm(1),
+            Err(FailReason::WrongJobState)
+        ));
+        assert!(matches!(
+            queue.send_sigterm(2),
+            Err(FailReason::WrongJobState)
+        ));
+        assert!(matches!(queue.send_sigterm(3), Err(FailReason::NoSuchJob)));
+
+        queue.finish(
+            JobState::Failed("pre hook".to_string()),
+            String::new(),
+            String::new(),
+        );
+        assert!(matches!(
+            queue.send_sigterm(1),
+            Err(FailReason::WrongJobState)
+        ));
+    }
+}
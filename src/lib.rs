@@ -0,0 +1,66 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * lib.rs
+ *
+ * The qmanager library: the daemon, the protocol spoken between clients and
+ * the daemon, and a client for embedding qmanager in Rust programs. The
+ * qmanager binary is a command line interface on top of it.
+ **/
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate log;
+extern crate daemonize;
+extern crate hex;
+extern crate hmac;
+extern crate humantime;
+extern crate nix;
+extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate signal_hook;
+extern crate systemd;
+extern crate tiny_http;
+extern crate url;
+extern crate users;
+
+pub mod audit;
+pub mod client;
+pub mod clierror;
+pub mod daemon;
+pub mod events;
+pub mod hooks;
+pub mod job_queue;
+pub mod listing;
+pub mod mail;
+pub mod notify;
+pub mod outbox;
+pub mod protocol;
+pub mod rest;
+pub mod state;
+pub mod unix_socket;
@@ -0,0 +1,543 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * listing.rs
+ *
+ * Selects, sorts and pages through the jobs of the queue for list requests,
+ * so that clients do not need to download every job ever run to show a few.
+ * Pages can be requested by offset or by a cursor, which remains stable while
+ * jobs are added or removed.
+ **/
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use job_queue::{Job, JobQueue, JobState};
+
+/// A group of job states to list
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFilter {
+    /// waiting for execution
+    Queued,
+
+    /// currently executed
+    Running,
+
+    /// terminated in any way
+    Finished,
+
+    /// terminated with exit code 0
+    Succeeded,
+
+    /// terminated with a non-zero exit code or could not be run
+    Failed,
+
+    /// killed by a signal
+    Killed,
+}
+
+impl StateFilter {
+    /// Whether a job in the given state belongs to the group
+    pub fn matches(self, state: &JobState) -> bool {
+        match (self, state) {
+            (StateFilter::Queued, JobState::Queued) => true,
+            (StateFilter::Running, JobState::Running) => true,
+            (StateFilter::Finished, JobState::Queued)
+            | (StateFilter::Finished, JobState::Running) => false,
+            (StateFilter::Finished, _) => true,
+            (StateFilter::Succeeded, JobState::Terminated(0)) => true,
+            (StateFilter::Failed, JobState::Terminated(code)) => *code != 0,
+            (StateFilter::Failed, JobState::Failed(_)) => true,
+            (StateFilter::Killed, JobState::Killed(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for StateFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "queued" => Ok(StateFilter::Queued),
+            "running" => Ok(StateFilter::Running),
+            "finished" => Ok(StateFilter::Finished),
+            "succeeded" => Ok(StateFilter::Succeeded),
+            "failed" => Ok(StateFilter::Failed),
+            "killed" => Ok(StateFilter::Killed),
+            _ => Err(format!(
+                "Invalid state '{}', expected one of queued, running, finished, succeeded, failed, killed",
+                s
+            )),
+        }
+    }
+}
+
+/// The order in which jobs are listed
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    /// by job ID, i.e. in order of submission
+    #[default]
+    Id,
+
+    /// by time of execution start, jobs that have not been started last
+    Started,
+
+    /// by time of termination, jobs that have not finished last
+    Finished,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "id" => Ok(SortKey::Id),
+            "started" => Ok(SortKey::Started),
+            "finished" => Ok(SortKey::Finished),
+            _ => Err(format!(
+                "Invalid sort key '{}', expected one of id, started, finished",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            SortKey::Id => "id",
+            SortKey::Started => "started",
+            SortKey::Finished => "finished",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Which jobs to list and how. All criteria must be met by a job to be
+/// listed. Empty criteria match all jobs.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct JobFilter {
+    /// Jobs in any of these states
+    pub states: Vec<StateFilter>,
+
+    /// Jobs of this appkey
+    pub appkey: Option<String>,
+
+    /// Jobs submitted by this local user
+    pub owner: Option<String>,
+
+    /// Jobs carrying all of these tags
+    pub tags: Vec<String>,
+
+    /// Jobs scheduled at or after this time
+    pub scheduled_after: Option<SystemTime>,
+
+    /// Jobs scheduled before this time
+    pub scheduled_before: Option<SystemTime>,
+
+    /// Jobs finished at or after this time
+    pub finished_after: Option<SystemTime>,
+
+    /// Jobs finished before this time
+    pub finished_before: Option<SystemTime>,
+
+    /// Sort order
+    pub sort: SortKey,
+
+    /// Reverse the sort order
+    pub descending: bool,
+
+    /// Number of jobs to skip, after the cursor if one is given
+    pub offset: usize,
+
+    /// Maximum number of jobs to return
+    pub limit: Option<usize>,
+
+    /// Continue after the last job of a previous page
+    pub cursor: Option<String>,
+
+    /// Include stdout and stderr of the jobs
+    pub with_output: bool,
+}
+
+/// A page of listed jobs
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobPage {
+    /// The jobs on this page
+    pub jobs: Vec<Job>,
+
+    /// Number of jobs matching the filter, on all pages
+    pub total: usize,
+
+    /// Cursor to request the next page with, if there is one
+    pub next_cursor: Option<String>,
+}
+
+/// Position of a job in the sort order. Jobs without the sorted-by
+/// timestamp are sorted last.
+fn sort_position(job: &Job, key: SortKey) -> (u128, u64) {
+    let nanos = |t: Option<SystemTime>| {
+        t.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(u128::MAX)
+    };
+
+    match key {
+        SortKey::Id => (0, job.id),
+        SortKey::Started => (nanos(job.started), job.id),
+        SortKey::Finished => (nanos(job.finished), job.id),
+    }
+}
+
+/// Encodes a sort position as an opaque cursor
+fn encode_cursor(key: SortKey, position: (u128, u64)) -> String {
+    format!("{}-{}-{}", key, position.0, position.1)
+}
+
+/// Decodes a cursor created for the given sort order
+fn decode_cursor(key: SortKey, cursor: &str) -> Result<(u128, u64), String> {
+    let invalid = || format!("Invalid cursor '{}'", cursor);
+    let mut parts = cursor.splitn(3, '-');
+
+    if parts.next() != Some(&key.to_string()) {
+        return Err(format!(
+            "Cursor '{}' does not belong to a listing sorted by {}",
+            cursor, key
+        ));
+    }
+    let value = parts
+        .next()
+        .and_then(|v| v.parse::<u128>().ok())
+        .ok_or_else(invalid)?;
+    let id = parts
+        .next()
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(invalid)?;
+    Ok((value, id))
+}
+
+impl JobFilter {
+    /// Whether the job meets all criteria
+    pub fn matches(&self, job: &Job) -> bool {
+        let appkey = job.cmdline.split_whitespace().next().unwrap_or("");
+        let at_or_after = |t: Option<SystemTime>, bound: Option<SystemTime>| match bound {
+            Some(b) => t.is_some_and(|t| t >= b),
+            None => true,
+        };
+        let before = |t: Option<SystemTime>, bound: Option<SystemTime>| match bound {
+            Some(b) => t.is_some_and(|t| t < b),
+            None => true,
+        };
+
+        (self.states.is_empty() || self.states.iter().any(|s| s.matches(&job.state)))
+            && self.appkey.as_ref().is_none_or(|a| a == appkey)
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|o| job.owner.as_ref() == Some(o))
+            && self.tags.iter().all(|t| job.tags.contains(t))
+            && at_or_after(Some(job.scheduled), self.scheduled_after)
+            && before(Some(job.scheduled), self.scheduled_before)
+            && at_or_after(job.finished, self.finished_after)
+            && before(job.finished, self.finished_before)
+    }
+}
+
+/// Returns the page of jobs of the queue selected by the filter
+pub fn list(queue: &JobQueue, filter: &JobFilter) -> Result<JobPage, String> {
+    let cursor = filter
+        .cursor
+        .as_ref()
+        .map(|c| decode_cursor(filter.sort, c))
+        .transpose()?;
+
+    let mut jobs: Vec<&Job> = queue
+        .iter_queued()
+        .chain(queue.iter_finished())
+        .filter(|j| filter.matches(j))
+        .collect();
+    let total = jobs.len();
+
+    jobs.sort_by_key(|j| sort_position(j, filter.sort));
+    if filter.descending {
+        jobs.reverse();
+    }
+
+    // skip everything up to and including the cursor position
+    if let Some(cursor) = cursor {
+        jobs.retain(|j| {
+            let position = sort_position(j, filter.sort);
+            if filter.descending {
+                position < cursor
+            } else {
+                position > cursor
+            }
+        });
+    }
+
+    let remaining = jobs.len().saturating_sub(filter.offset);
+    let limit = filter.limit.unwrap_or(remaining);
+    let page: Vec<Job> = jobs
+        .into_iter()
+        .skip(filter.offset)
+        .take(limit)
+        .map(|j| {
+            let mut job = j.clone();
+            if !filter.with_output {
+                job.stdout.clear();
+                job.stderr.clear();
+            }
+            job
+        })
+        .collect();
+
+    let next_cursor = match page.last() {
+        Some(last) if remaining > page.len() => {
+            Some(encode_cursor(filter.sort, sort_position(last, filter.sort)))
+        }
+        _ => None,
+    };
+
+    Ok(JobPage {
+        jobs: page,
+        total,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use job_queue::Submission;
+
+    /// A queue of jobs 1 to n, of which the first `finished` have terminated
+    fn queue(n: usize, finished: usize) -> JobQueue {
+        let mut q = JobQueue::new(0);
+        for i in 1..=n {
+            q.submit(
+                Submission {
+                    cmdline: format!("echo {}", i),
+                    notify: Vec::new(),
+                    tags: Vec::new(),
+                },
+                None,
+            );
+        }
+        for _ in 0..finished {
+            q.schedule().unwrap();
+            q.finish(JobState::Terminated(0), String::new(), String::new());
+        }
+        q
+    }
+
+    fn ids(page: &JobPage) -> Vec<u64> {
+        page.jobs.iter().map(|j| j.id).collect()
+    }
+
+    #[test]
+    fn cursor_is_stable() {
+        let mut q = queue(5, 0);
+        let mut filter = JobFilter {
+            limit: Some(2),
+            ..JobFilter::default()
+        };
+        let page = list(&q, &filter).unwrap();
+        assert_eq!(ids(&page), vec![1, 2]);
+        assert_eq!(page.total, 5);
+
+        // removing a listed job and adding one does not shift the next page
+        q.remove(1).unwrap();
+        q.submit(
+            Submission {
+                cmdline: "echo 6".to_string(),
+                notify: Vec::new(),
+                tags: Vec::new(),
+            },
+            None,
+        );
+        filter.cursor = page.next_cursor;
+        let page = list(&q, &filter).unwrap();
+        assert_eq!(ids(&page), vec![3, 4]);
+
+        filter.cursor = page.next_cursor;
+        let page = list(&q, &filter).unwrap();
+        assert_eq!(ids(&page), vec![5, 6]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn descending_with_cursor() {
+        let q = queue(5, 2);
+        let mut filter = JobFilter {
+            descending: true,
+            limit: Some(2),
+            ..JobFilter::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = list(&q, &filter).unwrap();
+            pages.push(ids(&page));
+            match page.next_cursor {
+                Some(cursor) => filter.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![vec![5, 4], vec![3, 2], vec![1]]);
+    }
+
+    #[test]
+    fn offset_and_limit() {
+        let q = queue(5, 0);
+        let page = list(
+            &q,
+            &JobFilter {
+                offset: 1,
+                limit: Some(2),
+                ..JobFilter::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(&page), vec![2, 3]);
+        assert_eq!(page.total, 5);
+        assert!(page.next_cursor.is_some());
+
+        let page = list(
+            &q,
+            &JobFilter {
+                offset: 3,
+                limit: Some(5),
+                ..JobFilter::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(&page), vec![4, 5]);
+        assert_eq!(page.next_cursor, None);
+
+        // offset counts from the cursor
+        let page = list(
+            &q,
+            &JobFilter {
+                offset: 1,
+                cursor: Some(encode_cursor(SortKey::Id, (0, 2))),
+                ..JobFilter::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(&page), vec![4, 5]);
+    }
+
+    #[test]
+    fn sorted_by_finished() {
+        let q = queue(4, 2);
+        let page = list(
+            &q,
+            &JobFilter {
+                sort: SortKey::Finished,
+                ..JobFilter::default()
+            },
+        )
+        .unwrap();
+        // unfinished jobs last, by ID
+        assert_eq!(ids(&page), vec![1, 2, 3, 4]);
+        assert!(page.jobs[3].finished.is_none());
+    }
+
+    #[test]
+    fn rejects_foreign_cursors() {
+        let q = queue(3, 0);
+        let page = list(
+            &q,
+            &JobFilter {
+                limit: Some(1),
+                ..JobFilter::default()
+            },
+        )
+        .unwrap();
+
+        let e = list(
+            &q,
+            &JobFilter {
+                sort: SortKey::Started,
+                cursor: page.next_cursor,
+                ..JobFilter::default()
+            },
+        )
+        .unwrap_err();
+        assert!(
+            e.contains("does not belong to a listing sorted by started"),
+            "{}",
+            e
+        );
+
+        for cursor in &["id-x-1", "id-1", "nonsense"] {
+            let filter = JobFilter {
+                cursor: Some(cursor.to_string()),
+                ..JobFilter::default()
+            };
+            assert!(list(&q, &filter).is_err(), "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn state_filters() {
+        let states = [
+            JobState::Queued,
+            JobState::Running,
+            JobState::Terminated(0),
+            JobState::Terminated(3),
+            JobState::Failed("no such file".to_string()),
+            JobState::Killed(9),
+        ];
+        let matching = |filter: StateFilter| -> Vec<bool> {
+            states.iter().map(|s| filter.matches(s)).collect()
+        };
+        assert_eq!(
+            matching(StateFilter::Queued),
+            [true, false, false, false, false, false]
+        );
+        assert_eq!(
+            matching(StateFilter::Running),
+            [false, true, false, false, false, false]
+        );
+        assert_eq!(
+            matching(StateFilter::Finished),
+            [false, false, true, true, true, true]
+        );
+        assert_eq!(
+            matching(StateFilter::Succeeded),
+            [false, false, true, false, false, false]
+        );
+        assert_eq!(
+            matching(StateFilter::Failed),
+            [false, false, false, true, true, false]
+        );
+        assert_eq!(
+            matching(StateFilter::Killed),
+            [false, false, false, false, false, true]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * mail.rs
+ *
+ * Sends job notifications by email. Notification targets with a 'mailto:'
+ * URL are delivered through the same outbox as web hooks. The message is
+ * either piped to a local sendmail binary or handed to an SMTP relay.
+ **/
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::Url;
+use url::percent_encoding::percent_decode;
+
+use job_queue::{Job, JobState};
+
+/// Default location of the sendmail binary
+pub const DEFAULT_SENDMAIL: &str = "/usr/sbin/sendmail";
+
+/// Default sender address of notification mails
+pub const DEFAULT_MAIL_FROM: &str = "qmanager@localhost";
+
+/// Number of trailing stderr lines included in a notification mail
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Timeout for each step of an SMTP conversation
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How notification mails leave the host
+#[derive(Debug, Clone)]
+pub enum MailTransport {
+    /// Pipe the message to the given sendmail-compatible binary
+    Sendmail(PathBuf),
+
+    /// Submit the message to the SMTP relay at the given 'host:port'
+    Smtp(String),
+}
+
+/// Composes and sends notification mails
+pub struct Mailer {
+    transport: MailTransport,
+    from: String,
+}
+
+impl Mailer {
+    /// Sets up a mailer sending from the given address
+    pub fn new(transport: MailTransport, from: String) -> Mailer {
+        Mailer { transport, from }
+    }
+
+    /// Mails the recipients of the given 'mailto:' URL about the given event
+    /// for the given job
+    pub fn send(&self, url: &Url, event: &str, job: &Job) -> Result<()> {
+        let to = recipients(url)?;
+        let message = compose(&self.from, &to, event, job);
+
+        match self.transport {
+            MailTransport::Sendmail(ref path) => sendmail(path, &to, &message),
+            MailTransport::Smtp(ref relay) => smtp(relay, &self.from, &to, &message),
+        }
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "Failed to mail {} about job {}: {}",
+                    to.join(", "),
+                    job.id,
+                    e
+                ),
+            )
+        })?;
+
+        debug!("Mailed {} about job {} ({})", to.join(", "), job.id, event);
+        Ok(())
+    }
+}
+
+/// Extracts the list of recipients from a 'mailto:' URL
+pub fn recipients(url: &Url) -> Result<Vec<String>> {
+    let path = percent_decode(url.path().as_bytes())
+        .decode_utf8()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+
+    let to: Vec<String> = path
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    if to.is_empty() || to.iter().any(|a| !is_address(a)) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid mail recipients in '{}'", url),
+        ));
+    }
+    Ok(to)
+}
+
+/// Rough plausibility check for a mail address that also keeps line breaks
+/// and angle brackets out of the SMTP conversation and mail headers
+fn is_address(s: &str) -> bool {
+    let mut parts = s.splitn(2, '@');
+    let local = parts.next().unwrap_or("");
+    let domain = parts.next().unwrap_or("");
+    !local.is_empty()
+        && !domain.is_empty()
+        && !s
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "<>()[],;:\"".contains(c))
+}
+
+/// Renders the notification mail, with header and body separated by an
+/// empty line and lines separated by '\n'
+fn compose(from: &str, to: &[String], event: &str, job: &Job) -> String {
+    let appkey = job.cmdline.split_whitespace().next().unwrap_or("");
+    let state = match job.state {
+        JobState::Queued => "queued".to_string(),
+        JobState::Running => "running".to_string(),
+        JobState::Terminated(code) => format!("terminated with exit code {}", code),
+        JobState::Killed(signal) => format!("killed by signal {}", signal),
+        JobState::Failed(ref reason) => format!("failed: {}", reason),
+    };
+    let duration = match (job.started, job.finished) {
+        (Some(start), Some(end)) => end
+            .duration_since(start)
+            .map(|d| humantime::format_duration(Duration::from_secs(d.as_secs())).to_string())
+            .unwrap_or_else(|_| "-".to_string()),
+        _ => "-".to_string(),
+    };
+
+    let mut message = format!(
+        "From: qmanager <{from}>\n\
+         To: {to}\n\
+         Subject: [qmanager] Job #{id} {event}: {appkey}\n\
+         Date: {date}\n\
+         MIME-Version: 1.0\n\
+         Content-Type: text/plain; charset=utf-8\n\
+         Content-Transfer-Encoding: 8bit\n\
+         \n\
+         Job:       #{id}\n\
+         Appkey:    {appkey}\n\
+         Command:   {cmdline}\n\
+         State:     {state}\n\
+         Duration:  {duration}\n",
+        from = from,
+        to = to.join(", "),
+        id = job.id,
+        event = event,
+        appkey = appkey,
+        date = rfc2822_date(SystemTime::now()),
+        cmdline = job.cmdline,
+        state = state,
+        duration = duration,
+    );
+
+    let stderr: Vec<&str> = job.stderr.lines().collect();
+    if !stderr.is_empty() {
+        let skip = stderr.len().saturating_sub(STDERR_TAIL_LINES);
+        message.push_str(&format!(
+            "\nLast {} line(s) of stderr:\n\n",
+            stderr.len() - skip
+        ));
+        for line in &stderr[skip..] {
+            message.push_str(line);
+            message.push('\n');
+        }
+    }
+
+    message
+}
+
+/// Formats a point in time as required by the 'Date' mail header,
+/// i.e. 'Sun, 18 Oct 2026 12:00:00 +0000'
+fn rfc2822_date(t: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // RFC 3339 is 'YYYY-MM-DDTHH:MM:SSZ'
+    let rfc3339 = humantime::format_rfc3339_seconds(t).to_string();
+    let days = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+    let month = rfc3339[5..7].parse::<usize>().unwrap_or(1);
+
+    format!(
+        "{}, {} {} {} {} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        &rfc3339[8..10],
+        MONTHS[month - 1],
+        &rfc3339[0..4],
+        &rfc3339[11..19]
+    )
+}
+
+/// Pipes the message to sendmail
+fn sendmail(path: &Path, to: &[String], message: &str) -> Result<()> {
+    let mut child = Command::new(path)
+        .arg("-i")
+        .arg("--")
+        .args(to)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("Cannot run {:?}: {}", path, e)))?;
+
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(message.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "{:?} exited with {}: {}",
+            path,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Reads a (possibly multi-line) SMTP reply and checks its code
+fn expect<R: BufRead>(reader: &mut R, expected: &[u16]) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        let code = line.get(0..3).and_then(|c| c.parse::<u16>().ok());
+        let last = line.as_bytes().get(3) != Some(&b'-');
+
+        match code {
+            Some(code) if last && expected.contains(&code) => return Ok(()),
+            Some(_) if last => {
+                return Err(Error::other(format!(
+                    "Mail relay replied '{}'",
+                    line.trim_end()
+                )))
+            }
+            Some(_) => continue,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed reply from mail relay: '{}'", line.trim_end()),
+                ))
+            }
+        }
+    }
+}
+
+/// Submits the message to an SMTP relay. No authentication or encryption
+/// is performed, so this is meant for a relay on the local host or network.
+fn smtp(relay: &str, from: &str, to: &[String], message: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(relay)?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let helo = from.rsplit('@').next().unwrap_or("localhost");
+
+    expect(&mut reader, &[220])?;
+    write!(stream, "HELO {}\r\n", helo)?;
+    expect(&mut reader, &[250])?;
+    write!(stream, "MAIL FROM:<{}>\r\n", from)?;
+    expect(&mut reader, &[250])?;
+    for rcpt in to {
+        write!(stream, "RCPT TO:<{}>\r\n", rcpt)?;
+        expect(&mut reader, &[250, 251])?;
+    }
+    write!(stream, "DATA\r\n")?;
+    expect(&mut reader, &[354])?;
+
+    // lines starting with a dot are escaped by doubling it
+    let mut data = String::with_capacity(message.len() + 64);
+    for line in message.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+    stream.write_all(data.as_bytes())?;
+    expect(&mut reader, &[250])?;
+
+    write!(stream, "QUIT\r\n")?;
+    let _ = expect(&mut reader, &[221]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn job() -> Job {
+        Job {
+            id: 7,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: Some(UNIX_EPOCH + Duration::from_secs(100)),
+            finished: Some(UNIX_EPOCH + Duration::from_secs(190)),
+            stderr: "loading\n.hidden\nsegfault\n".to_string(),
+            stdout: String::new(),
+            state: JobState::Terminated(139),
+            pid: Some(4711),
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    /// Accepts a single SMTP session, answering every command with the
+    /// reply given for its verb, and returns everything the client sent
+    fn fake_relay(rcpt_reply: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut transcript = String::new();
+            let mut in_data = false;
+
+            stream.write_all(b"220 fake ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                transcript.push_str(&line);
+
+                let reply = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    "250 queued\r\n"
+                } else if line.starts_with("RCPT") {
+                    rcpt_reply
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    "354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    "250-fake\r\n250 ok\r\n"
+                };
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            transcript
+        });
+
+        (address, handle)
+    }
+
+    #[test]
+    fn smtp_delivers_message() {
+        let (relay, handle) = fake_relay("250 ok\r\n");
+        let mailer = Mailer::new(MailTransport::Smtp(relay), "qm@example.org".to_string());
+        let url = Url::parse("mailto:alice@example.org,bob%40example.org").unwrap();
+
+        mailer.send(&url, "failed", &job()).unwrap();
+        let transcript = handle.join().unwrap();
+
+        assert!(transcript.contains("MAIL FROM:<qm@example.org>\r\n"));
+        assert!(transcript.contains("RCPT TO:<alice@example.org>\r\n"));
+        assert!(transcript.contains("RCPT TO:<bob@example.org>\r\n"));
+        assert!(transcript.contains("Subject: [qmanager] Job #7 failed: gwas\r\n"));
+        assert!(transcript.contains("State:     terminated with exit code 139\r\n"));
+        assert!(transcript.contains("Duration:  1m 30s\r\n"));
+        assert!(transcript.contains("\r\n..hidden\r\nsegfault\r\n.\r\n"));
+    }
+
+    #[test]
+    fn smtp_reports_rejected_recipient() {
+        let (relay, _handle) = fake_relay("550 no such user\r\n");
+        let mailer = Mailer::new(MailTransport::Smtp(relay), DEFAULT_MAIL_FROM.to_string());
+        let url = Url::parse("mailto:nobody@example.org").unwrap();
+
+        let err = mailer.send(&url, "failed", &job()).unwrap_err();
+        assert!(err.to_string().contains("550 no such user"));
+    }
+
+    #[test]
+    fn rejects_invalid_recipients() {
+        for url in &["mailto:", "mailto:alice", "mailto:a@b%0D%0ARCPT%20TO:x@y"] {
+            assert!(recipients(&Url::parse(url).unwrap()).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn formats_mail_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_792_324_800);
+        assert_eq!(rfc2822_date(t), "Sun, 18 Oct 2026 12:00:00 +0000");
+    }
+}
@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -26,43 +26,57 @@ extern crate serde_derive;
 extern crate clap;
 #[macro_use]
 extern crate log;
+extern crate chrono;
 extern crate config;
-extern crate daemonize;
 extern crate humantime;
 extern crate nix;
-extern crate reqwest;
+extern crate openssl;
+extern crate qmanager;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
-extern crate signal_hook;
 extern crate simplelog;
 extern crate structopt;
 extern crate syslog;
-extern crate systemd;
-extern crate tiny_http;
+extern crate yaml_rust;
 
+mod cli;
 mod clicommands;
 mod cliopts;
-mod daemon;
-mod job_queue;
-mod protocol;
-mod state;
+mod completions;
+mod config_check;
+mod federation;
+mod manifest;
+mod output;
+mod profiles;
+mod top;
 
+use std::collections::HashMap;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::io::Result;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
+use cli::*;
 use cliopts::*;
-use job_queue::QueueState;
-use state::State;
+use profiles::{ClientConfig, Profile};
+use qmanager::clierror::{ClientError, Result};
+use qmanager::daemon::{self, DaemonOptions, ListenerOptions, SocketOptions};
+use qmanager::hooks::{self, Hook, HookSet, Hooks};
+use qmanager::job_queue::{NotifyTarget, QueueState, Submission};
+use qmanager::mail::{self, MailTransport};
+use qmanager::notify::{self, NotifyMethod};
+use qmanager::state::State;
+use qmanager::unix_socket;
 
-use reqwest::{Client, Url};
 use structopt::StructOpt;
 use syslog::Facility;
 
 /// Reads a whole file into a byte vector
-fn slurp_file(filename: &PathBuf) -> Result<Vec<u8>> {
+fn slurp_file(filename: &PathBuf) -> io::Result<Vec<u8>> {
     let mut f = File::open(filename)?;
     let mut buf = Vec::new();
 
@@ -70,43 +84,109 @@ fn slurp_file(filename: &PathBuf) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
-/// Loads SSL certificates, if any, and sets up corresponding Client and Url objects
-fn create_client(
-    insecure: bool,
-    ca: Option<PathBuf>,
-    host: &str,
-    port: u16,
-) -> Result<(Client, Url)> {
-    let client = if insecure {
-        reqwest::Client::new()
-    } else {
-        let mut buf = Vec::new();
-        File::open(ca.unwrap())?.read_to_end(&mut buf).unwrap();
-        let pkcs12 = reqwest::Certificate::from_pem(&buf).unwrap();
-        reqwest::Client::builder()
-            .add_root_certificate(pkcs12)
-            .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true)
-            .build()
-            .unwrap()
+/// Sets up the configured hooks. Hooks without a timeout of their own use
+/// the global timeout or, if that is not given either, the default one.
+fn create_hooks(global: HookOptions, appkeys: HashMap<String, HookOptions>) -> Hooks {
+    // timeouts have already been validated
+    let timeout_of = |o: &HookOptions, fallback: Duration| {
+        o.timeout
+            .as_ref()
+            .map(|t| hooks::parse_timeout(t).unwrap())
+            .unwrap_or(fallback)
+    };
+    let global_timeout = timeout_of(&global, hooks::DEFAULT_HOOK_TIMEOUT);
+
+    let hook_set = |o: HookOptions| {
+        let timeout = timeout_of(&o, global_timeout);
+        HookSet {
+            pre: o.pre.map(|path| Hook { path, timeout }),
+            post: o.post.map(|path| Hook { path, timeout }),
+        }
     };
 
-    let url = reqwest::Url::parse(&format!("http://{}:{}/", host, port)).unwrap();
+    Hooks {
+        global: hook_set(global),
+        appkeys: appkeys
+            .into_iter()
+            .map(|(appkey, o)| (appkey, hook_set(o)))
+            .collect(),
+    }
+}
 
-    Ok((client, url))
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_status());
+    }
 }
 
-fn main() -> Result<()> {
+/// Runs the daemon or a client command as given on the command line
+fn run() -> Result<()> {
     // Load command line args add config defaults for those not specified
     let mut opt = Opt::from_args();
+
+    // completion scripts and the manual page do not need any configuration
+    match opt.cmd {
+        OptCommand::Completions { shell } => {
+            completions::print_script(shell);
+            return Ok(());
+        }
+        OptCommand::Manpage {} => return Ok(completions::print_manpage()?),
+        _ => {}
+    }
+
+    // 'config check' loads the configuration exactly as the daemon would
+    let config_check = opt.is_config_check();
+    if config_check {
+        opt.cmd = OptCommand::daemon();
+    }
+
+    // Server profiles of the client configuration file (client only)
+    let client_config = match opt
+        .client_config
+        .clone()
+        .or_else(ClientConfig::default_path)
+    {
+        Some(path) if !opt.is_daemon() => ClientConfig::load(&path)?,
+        _ => ClientConfig::default(),
+    };
+    // Config keys given as environment variables take precedence over both
+    // the server profile and the configuration file
+    let overrides = cliopts::environment_overrides();
+    let mut env_config = config::Config::default();
+    cliopts::apply_environment(&mut env_config, &overrides)?;
+    let profile = client_config.select(opt.profile.as_deref())?;
+
+    // The default configuration file is optional, i.e. for clients using
+    // profiles or configured by environment variables only
+    let config_file = opt
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
     let mut config = config::Config::default();
-    config
-        .merge(config::File::new(
-            opt.config.to_str().unwrap(),
-            config::FileFormat::Toml,
-        ))
-        .expect("Failed to read configuration file!");
-    opt.merge_config(config);
+    if opt.config.is_some() || config_file.exists() {
+        config
+            .merge(config::File::new(
+                config_file.to_str().unwrap(),
+                config::FileFormat::Toml,
+            ))
+            .map_err(|e| {
+                ClientError::Config(format!(
+                    "Cannot read configuration file {:?}: {}",
+                    config_file, e
+                ))
+            })?;
+    }
+    // without a profile, the connection settings come from the
+    // configuration file
+    let mut profile = profile.unwrap_or_else(|| Profile::from_config(&config));
+    profile.override_with(&env_config);
+    cliopts::apply_environment(&mut config, &overrides)?;
+    let merged = opt.merge_config(config, profile);
+    if config_check {
+        return config_check::handle_check(&opt, merged);
+    }
+    merged?;
 
     // Check general option usefulness
     opt.verify()?;
@@ -152,8 +232,16 @@ fn main() -> Result<()> {
         }
     }
 
-    // Set up program state configuration file
-    let state = State::from(opt.state_file.unwrap());
+    // Connection settings merged from command line, profile and config
+    // file (client only)
+    let connection = Profile {
+        host: Some(opt.host.clone()),
+        port: Some(opt.port),
+        ca: opt.ca.clone(),
+        insecure: opt.insecure,
+        socket: opt.socket.clone(),
+        token: opt.token.clone(),
+    };
 
     // Handle subcommands
     match opt.cmd {
@@ -163,63 +251,211 @@ fn main() -> Result<()> {
             pidfile,
             foreground,
             notify_url,
+            socket_mode,
+            socket_group,
+            audit_log,
+            notify_method,
+            notify_secret,
+            notify_retries,
+            sendmail,
+            smtp_relay,
+            mail_from,
         } => {
-            let cert = cert.and_then(|s| Some(slurp_file(&s))).transpose()?;
-            let key = key.and_then(|s| Some(slurp_file(&s))).transpose()?;
+            let cert = cert.map(|s| slurp_file(&s)).transpose()?;
+            let key = key.map(|s| slurp_file(&s)).transpose()?;
+
+            // the socket mode has already been validated
+            let socket = opt.socket.map(|path| SocketOptions {
+                path,
+                mode: socket_mode
+                    .map(|m| u32::from_str_radix(&m, 8).unwrap())
+                    .unwrap_or(unix_socket::DEFAULT_SOCKET_MODE),
+                group: socket_group,
+            });
+
+            // an SMTP relay takes precedence over the local sendmail binary
+            let mail_transport = match smtp_relay {
+                Some(relay) => MailTransport::Smtp(relay),
+                None => MailTransport::Sendmail(
+                    sendmail.unwrap_or_else(|| PathBuf::from(mail::DEFAULT_SENDMAIL)),
+                ),
+            };
+
+            // listen on all addresses if nothing specific is configured
+            let insecure = opt.insecure;
+            let listeners = if opt.listen.is_empty() {
+                vec![ListenerOptions {
+                    address: SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], opt.port)),
+                    tls: !insecure,
+                }]
+            } else {
+                // listen addresses have already been validated
+                opt.listen
+                    .iter()
+                    .map(|l| ListenerOptions {
+                        address: l.address.parse().unwrap(),
+                        tls: l.tls.unwrap_or(!insecure),
+                    })
+                    .collect()
+            };
 
             daemon::handle(
-                opt.port,
-                pidfile,
-                cert,
-                key,
-                foreground,
-                opt.dump_json,
-                opt.appkeys,
-                notify_url,
-                state,
+                DaemonOptions {
+                    listeners,
+                    pidfile,
+                    cert,
+                    key,
+                    foreground,
+                    dump_protocol: opt.dump_json,
+                    appkeys: opt.appkeys,
+                    hooks: create_hooks(opt.hooks, opt.appkey_hooks),
+                    notify_url,
+                    // the notification method has already been validated
+                    notify_method: notify_method
+                        .map(|m| m.parse().unwrap())
+                        .unwrap_or(NotifyMethod::Get),
+                    notify_secret,
+                    notify_retries: notify_retries.unwrap_or(notify::DEFAULT_NOTIFY_RETRIES),
+                    mail_transport,
+                    mail_from: mail_from.unwrap_or_else(|| mail::DEFAULT_MAIL_FROM.to_string()),
+                    socket,
+                    audit_log,
+                },
+                State::from(opt.state_file.unwrap()),
             )
+            .map_err(ClientError::from)
         }
 
         OptCommand::Stop {} => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_set_queue_status(&client, url, QueueState::Stopping, opt.dump_json)
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_set_queue_status(&client, QueueState::Stopping)
         }
         OptCommand::Start {} => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_set_queue_status(&client, url, QueueState::Running, opt.dump_json)
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_set_queue_status(&client, QueueState::Running)
         }
-        OptCommand::Status {} => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_queue_status(&client, url, opt.dump_json)
+        OptCommand::Status {
+            list,
+            all_profiles: true,
+            output,
+        } => federation::handle_status(
+            &client_config,
+            list.filter().unwrap_or_default(),
+            opt.dump_json,
+            output,
+        ),
+
+        OptCommand::Status { list, output, .. } => {
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_queue_status(&client, list.filter(), output)
+        }
+
+        OptCommand::Find { job_id, output } => {
+            federation::handle_find(&client_config, job_id, opt.dump_json, output)
+        }
+
+        OptCommand::Submit {
+            cmdline,
+            from,
+            notify,
+            notify_on,
+            tag,
+            wait,
+            timeout,
+            output,
+        } => {
+            let client = connection.connect(opt.dump_json)?;
+
+            // the command-line options apply to all jobs of a manifest that
+            // do not set them themselves
+            if let Some(path) = from {
+                let fallback = manifest::Entry {
+                    tags: Some(tag).filter(|t| !t.is_empty()),
+                    notify: Some(notify).filter(|n| !n.is_empty()),
+                    notify_on: Some(notify_on).filter(|e| !e.is_empty()),
+                    ..manifest::Entry::default()
+                };
+                let submissions = manifest::Manifest::load(&path)?.submissions(&fallback)?;
+                return clicommands::handle_submit_batch(&client, submissions, output);
+            }
+
+            let submission = Submission {
+                cmdline: cmdline.unwrap(),
+                notify: notify
+                    .into_iter()
+                    .map(|url| NotifyTarget {
+                        url,
+                        events: notify_on.clone(),
+                    })
+                    .collect(),
+                tags: tag,
+            };
+            let id = clicommands::handle_submit(&client, submission, wait, output)?;
+            if wait {
+                let timeout = timeout.map(Into::into);
+                let status = clicommands::handle_wait(&client, id, timeout)?;
+                std::process::exit(status);
+            }
+            Ok(())
         }
 
-        OptCommand::Submit { cmdline } => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_submit(&client, url, &cmdline, opt.dump_json)
+        OptCommand::Wait { job_id, timeout } => {
+            let client = connection.connect(opt.dump_json)?;
+            let timeout = timeout.map(Into::into);
+            let status = clicommands::handle_wait(&client, job_id, timeout)?;
+            std::process::exit(status);
         }
 
-        OptCommand::Remove { job_id } => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_remove(&client, url, job_id, opt.dump_json).and_then(|job| {
-                println!("{:?}", job);
-                Ok(())
-            })
+        OptCommand::Remove { job_id, output } => {
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_remove(&client, job_id, output)
         }
 
-        OptCommand::Kill { job_id } => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_kill(&client, url, job_id, opt.dump_json).and_then(|job| {
-                println!("{:?}", job);
-                Ok(())
-            })
+        OptCommand::Kill { job_id, output } => {
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_kill(&client, job_id, output)
+        }
+
+        OptCommand::Notifications { retry, retry_all } => {
+            let client = connection.connect(opt.dump_json)?;
+            if retry.is_some() || retry_all {
+                clicommands::handle_retry_notifications(&client, retry)
+            } else {
+                clicommands::handle_notifications(&client)
+            }
+        }
+
+        OptCommand::Show {
+            job_id,
+            no_stdout,
+            no_stderr,
+            output,
+        } => {
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_show(&client, job_id, !no_stdout, !no_stderr, output)
+        }
+
+        OptCommand::ServerInfo {} => {
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_server_info(&client)
+        }
+
+        OptCommand::Top {} => {
+            let client = connection.connect(opt.dump_json)?;
+            top::run(&client)
         }
 
         OptCommand::Cleanup { max_age } => {
-            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_cleanup(&client, url, max_age, opt.dump_json).and_then(|n| {
-                println!("{} jobs removed.", n);
-                Ok(())
-            })
+            let client = connection.connect(opt.dump_json)?;
+            clicommands::handle_cleanup(&client, max_age)
+        }
+        OptCommand::Complete { what } => {
+            let client = connection.connect(opt.dump_json)?;
+            completions::handle_complete(&client, &what)
+        }
+
+        OptCommand::Config { .. } | OptCommand::Completions { .. } | OptCommand::Manpage {} => {
+            unreachable!("handled before reading the configuration")
         }
     }
 }
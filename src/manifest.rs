@@ -0,0 +1,402 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * manifest.rs
+ *
+ * Manifests listing many jobs for 'submit --from', in TOML, JSON or CSV.
+ * TOML and JSON manifests hold optional shared defaults and the jobs:
+ *
+ *   [defaults]
+ *   cmdline = "gwas --cohort a"
+ *   tags = ["cohort-a"]
+ *   notify = ["mailto:me@example.org"]
+ *   notify-on = ["failed"]
+ *
+ *   [[jobs]]
+ *   args = "--chr 1"
+ *
+ *   [[jobs]]
+ *   cmdline = "plink --chr X"
+ *   tags = ["chrX"]
+ *
+ * CSV manifests have a header naming the columns and one job per row, with
+ * lists separated by ';'. Each setting of a job is taken from the job, else
+ * from the defaults, else from the command line. A job's command line is
+ * its cmdline followed by its args.
+ **/
+use std::fs;
+use std::path::Path;
+
+use config::{self, Config};
+use qmanager::clierror::{ClientError, Result};
+use qmanager::job_queue::{JobEvent, NotifyTarget, Submission};
+
+/// Settings of a job or the defaults of all jobs
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Entry {
+    /// Command line, starting with the appkey
+    pub cmdline: Option<String>,
+
+    /// Arguments appended to the command line
+    pub args: Option<String>,
+
+    /// Tags to attach to the job
+    pub tags: Option<Vec<String>>,
+
+    /// URLs to notify of job events
+    pub notify: Option<Vec<String>>,
+
+    /// Events to notify of
+    pub notify_on: Option<Vec<JobEvent>>,
+}
+
+impl Entry {
+    /// Fills in the settings missing in this entry from the given defaults
+    fn or(self, defaults: &Entry) -> Entry {
+        Entry {
+            cmdline: self.cmdline.or_else(|| defaults.cmdline.clone()),
+            args: self.args.or_else(|| defaults.args.clone()),
+            tags: self.tags.or_else(|| defaults.tags.clone()),
+            notify: self.notify.or_else(|| defaults.notify.clone()),
+            notify_on: self.notify_on.or_else(|| defaults.notify_on.clone()),
+        }
+    }
+}
+
+/// The jobs of a manifest file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// Settings of all jobs that do not set them themselves
+    pub defaults: Entry,
+
+    /// The jobs, in the order they are to be queued
+    pub jobs: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Reads a manifest, telling the format by the file extension
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let invalid =
+            |e: String| ClientError::Config(format!("Cannot read manifest {:?}: {}", path, e));
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        let format = match extension.as_deref() {
+            Some("toml") => config::FileFormat::Toml,
+            Some("json") => config::FileFormat::Json,
+            Some("csv") => {
+                let text = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+                return parse_csv(&text).map_err(invalid);
+            }
+            _ => return Err(invalid("expected a .toml, .json or .csv file".to_string())),
+        };
+
+        let mut conf = Config::default();
+        conf.merge(config::File::new(path.to_str().unwrap(), format))
+            .map_err(|e| invalid(e.to_string()))?;
+        conf.try_into().map_err(|e| invalid(e.to_string()))
+    }
+
+    /// The submissions of all jobs, with the settings missing in both the
+    /// job and the manifest's defaults taken from the given ones. Fails if
+    /// any job has no command line.
+    pub fn submissions(self, fallback: &Entry) -> Result<Vec<Submission>> {
+        if self.jobs.is_empty() {
+            return Err(ClientError::Config(
+                "The manifest does not list any jobs".to_string(),
+            ));
+        }
+
+        let defaults = self.defaults.or(fallback);
+        let mut submissions = Vec::new();
+        let mut problems = Vec::new();
+        for (i, job) in self.jobs.into_iter().enumerate() {
+            match submission(job.or(&defaults)) {
+                Ok(s) => submissions.push(s),
+                Err(e) => problems.push(format!("job #{}: {}", i + 1, e)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(ClientError::Config(format!(
+                "Invalid manifest: {}",
+                problems.join("; ")
+            )));
+        }
+        Ok(submissions)
+    }
+}
+
+/// The submission of a job whose settings are complete
+fn submission(job: Entry) -> std::result::Result<Submission, String> {
+    let cmdline = match (job.cmdline, job.args) {
+        (Some(cmdline), Some(args)) => format!("{} {}", cmdline, args),
+        (Some(cmdline), None) => cmdline,
+        (None, _) => return Err("no cmdline".to_string()),
+    };
+    let events = job.notify_on.unwrap_or_default();
+    Ok(Submission {
+        cmdline,
+        notify: job
+            .notify
+            .unwrap_or_default()
+            .into_iter()
+            .map(|url| NotifyTarget {
+                url,
+                events: events.clone(),
+            })
+            .collect(),
+        tags: job.tags.unwrap_or_default(),
+    })
+}
+
+/// Reads a CSV manifest. Empty cells leave the setting to the defaults.
+fn parse_csv(text: &str) -> std::result::Result<Manifest, String> {
+    let mut records = csv_records(text)?.into_iter();
+    let header: Vec<String> = match records.next() {
+        Some(header) => header.iter().map(|c| c.trim().to_lowercase()).collect(),
+        None => return Err("missing header".to_string()),
+    };
+    if let Some(column) = header
+        .iter()
+        .find(|c| !["cmdline", "args", "tags", "notify", "notify-on"].contains(&c.as_str()))
+    {
+        return Err(format!(
+            "unknown column '{}', expected cmdline, args, tags, notify or notify-on",
+            column
+        ));
+    }
+
+    let mut manifest = Manifest::default();
+    for (i, record) in records.enumerate() {
+        if record.len() != header.len() {
+            return Err(format!(
+                "row {} has {} columns instead of {}",
+                i + 2,
+                record.len(),
+                header.len()
+            ));
+        }
+        let mut job = Entry::default();
+        for (column, cell) in header.iter().zip(record) {
+            let cell = cell.trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let list = || cell.split(';').map(|v| v.trim().to_string()).collect();
+            match column.as_str() {
+                "cmdline" => job.cmdline = Some(cell.to_string()),
+                "args" => job.args = Some(cell.to_string()),
+                "tags" => job.tags = Some(list()),
+                "notify" => job.notify = Some(list()),
+                _ => {
+                    let events = cell
+                        .split(';')
+                        .map(|e| e.trim().parse())
+                        .collect::<std::result::Result<Vec<JobEvent>, String>>()
+                        .map_err(|e| format!("row {}: {}", i + 2, e))?;
+                    job.notify_on = Some(events);
+                }
+            }
+        }
+        manifest.jobs.push(job);
+    }
+    Ok(manifest)
+}
+
+/// Splits CSV text into records of cells. Cells may be quoted with '"' to
+/// contain commas, line breaks and doubled quotes. Empty lines are skipped.
+fn csv_records(text: &str) -> std::result::Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cell.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if cell.trim().is_empty() => {
+                cell.clear();
+                quoted = true;
+            }
+            ',' if !quoted => record.push(std::mem::take(&mut cell)),
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut cell));
+                if record.iter().any(|c| !c.trim().is_empty()) {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            }
+            '\r' if !quoted => {}
+            c => cell.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted cell".to_string());
+    }
+    record.push(cell);
+    if record.iter().any(|c| !c.trim().is_empty()) {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(text: &str) -> Vec<Vec<String>> {
+        csv_records(text).unwrap()
+    }
+
+    fn entry(cmdline: Option<&str>, args: Option<&str>, tags: Option<&[&str]>) -> Entry {
+        Entry {
+            cmdline: cmdline.map(String::from),
+            args: args.map(String::from),
+            tags: tags.map(|t| t.iter().map(|s| s.to_string()).collect()),
+            ..Entry::default()
+        }
+    }
+
+    fn config_error(result: Result<Vec<Submission>>) -> String {
+        match result {
+            Err(ClientError::Config(e)) => e,
+            other => panic!("expected a configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn splits_records() {
+        assert_eq!(
+            records("a,b\r\n\"x, y\",\"say \"\"hi\"\"\"\r\n\n,\n"),
+            vec![vec!["a", "b"], vec!["x, y", "say \"hi\""]]
+        );
+        assert_eq!(
+            records("a,\"multi\nline\"\nb,c"),
+            vec![vec!["a", "multi\nline"], vec!["b", "c"]]
+        );
+        assert!(records("").is_empty());
+        assert!(csv_records("a,\"open\n").is_err());
+    }
+
+    #[test]
+    fn parses_csv() {
+        let manifest = parse_csv(
+            "CmdLine,args,tags,notify,notify-on\n\
+             gwas,--chr 1,a; b,mailto:me@example.org,failed;killed\n\
+             ,--chr 2,,,\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.jobs.len(), 2);
+
+        let first = &manifest.jobs[0];
+        assert_eq!(first.cmdline.as_deref(), Some("gwas"));
+        assert_eq!(first.args.as_deref(), Some("--chr 1"));
+        assert_eq!(first.tags, Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(
+            first.notify,
+            Some(vec!["mailto:me@example.org".to_string()])
+        );
+        assert_eq!(
+            first.notify_on,
+            Some(vec![JobEvent::Failed, JobEvent::Killed])
+        );
+
+        // empty cells are left to the defaults
+        let second = &manifest.jobs[1];
+        assert!(second.cmdline.is_none() && second.tags.is_none() && second.notify_on.is_none());
+        assert_eq!(second.args.as_deref(), Some("--chr 2"));
+    }
+
+    #[test]
+    fn rejects_invalid_csv() {
+        let e = parse_csv("cmdline,args\ngwas\n").unwrap_err();
+        assert!(e.contains("row 2 has 1 columns instead of 2"), "{}", e);
+
+        let e = parse_csv("cmdline,priority\ngwas,1\n").unwrap_err();
+        assert!(e.contains("unknown column 'priority'"), "{}", e);
+
+        let e = parse_csv("cmdline,notify-on\ngwas,sometimes\n").unwrap_err();
+        assert!(e.starts_with("row 2:"), "{}", e);
+
+        assert_eq!(parse_csv("\n\n").unwrap_err(), "missing header");
+    }
+
+    #[test]
+    fn settings_precedence() {
+        let manifest = Manifest {
+            defaults: entry(Some("gwas"), None, Some(&["cohort"])),
+            jobs: vec![
+                entry(None, Some("--chr 1"), None),
+                entry(Some("plink"), None, Some(&["chrX"])),
+            ],
+        };
+        let fallback = entry(Some("ignored"), Some("--fallback"), Some(&["cli"]));
+        let submissions = manifest.submissions(&fallback).unwrap();
+
+        assert_eq!(submissions[0].cmdline, "gwas --chr 1");
+        assert_eq!(submissions[0].tags, vec!["cohort"]);
+        assert_eq!(submissions[1].cmdline, "plink --fallback");
+        assert_eq!(submissions[1].tags, vec!["chrX"]);
+    }
+
+    #[test]
+    fn notify_targets() {
+        let mut job = entry(Some("gwas"), None, None);
+        job.notify = Some(vec!["a".to_string(), "b".to_string()]);
+        let manifest = Manifest {
+            defaults: Entry {
+                notify_on: Some(vec![JobEvent::Failed]),
+                ..Entry::default()
+            },
+            jobs: vec![job],
+        };
+        let submissions = manifest.submissions(&Entry::default()).unwrap();
+
+        assert_eq!(submissions[0].notify.len(), 2);
+        assert_eq!(submissions[0].notify[1].url, "b");
+        assert_eq!(submissions[0].notify[1].events, vec![JobEvent::Failed]);
+    }
+
+    #[test]
+    fn needs_jobs_with_cmdline() {
+        let e = config_error(Manifest::default().submissions(&Entry::default()));
+        assert!(e.contains("does not list any jobs"), "{}", e);
+
+        let manifest = Manifest {
+            defaults: Entry::default(),
+            jobs: vec![
+                entry(Some("gwas"), None, None),
+                entry(None, Some("--chr 2"), None),
+            ],
+        };
+        let e = config_error(manifest.submissions(&Entry::default()));
+        assert!(e.contains("job #2: no cmdline"), "{}", e);
+    }
+}
@@ -0,0 +1,561 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * notify.rs
+ *
+ * Notifies external services (i.e. a web frontend) of finished jobs. The
+ * notification URL is either called with a plain GET request carrying the
+ * job ID and event, or with a POST request whose JSON body describes the
+ * finished job.
+ * 'mailto:' URLs are handed to the mailer (see mail.rs) instead.
+ * If a shared secret is configured, every call carries a timestamp and an
+ * HMAC-SHA256 signature so that the receiver can verify its origin and
+ * reject replayed calls.
+ *
+ * Notifications are not sent directly. They are put into the outbox, which
+ * is persisted next to the program state, and delivered by a separate
+ * thread that retries failed deliveries with exponential backoff.
+ **/
+use std::io::{Error, Result};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+use serde_json;
+use sha2::Sha256;
+
+use job_queue::{Job, JobEvent, JobState, NotifyTarget};
+use mail::{self, Mailer};
+use outbox::{Notification, Outbox};
+use state::State;
+
+/// Header carrying the time of the call in seconds since the epoch
+pub const TIMESTAMP_HEADER: &str = "X-Qmanager-Timestamp";
+
+/// Header carrying the hex-encoded HMAC-SHA256 signature, prefixed by 'sha256='
+pub const SIGNATURE_HEADER: &str = "X-Qmanager-Signature";
+
+/// Default number of delivery attempts before a notification is given up
+pub const DEFAULT_NOTIFY_RETRIES: u32 = 8;
+
+/// Number of bytes of stdout and stderr that are sent along with a job
+const OUTPUT_TAIL_BYTES: usize = 4096;
+
+/// How the notification URL is called
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotifyMethod {
+    /// GET request with the job ID and event appended as '?jobid=N&event=E'
+    Get,
+
+    /// POST request with a JSON description of the job
+    Post,
+}
+
+impl FromStr for NotifyMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "get" => Ok(NotifyMethod::Get),
+            "post" => Ok(NotifyMethod::Post),
+            _ => Err(format!(
+                "Invalid notification method '{}', expected 'get' or 'post'",
+                s
+            )),
+        }
+    }
+}
+
+/// The JSON body of a POST notification
+#[derive(Serialize)]
+struct Payload<'a> {
+    /// What happened to the job
+    event: &'a str,
+
+    /// Exit code of the job, if it has terminated by itself
+    exit_code: Option<i32>,
+
+    /// The job, with stdout and stderr shortened to their last few kilobytes
+    job: Job,
+}
+
+/// The way notification endpoints are called
+pub struct Webhook {
+    method: NotifyMethod,
+    secret: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    /// Sets up the notification client. Calls are signed if a secret is given.
+    pub fn new(method: NotifyMethod, secret: Option<String>) -> Webhook {
+        Webhook {
+            method,
+            secret,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Notifies the endpoint at `url` of the given event for the given job
+    pub fn send(&self, url: &Url, event: &str, job: &Job) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+
+        let (mut request, signed_data) = match self.method {
+            NotifyMethod::Get => {
+                let url = get_url(url, event, job);
+                let query = url.query().unwrap_or("").to_string();
+                (self.client.get(url), query)
+            }
+            NotifyMethod::Post => {
+                let body = serde_json::to_string(&Payload {
+                    event,
+                    exit_code: match job.state {
+                        JobState::Terminated(code) => Some(code),
+                        _ => None,
+                    },
+                    job: with_output_tail(job),
+                })?;
+                (
+                    self.client
+                        .post(url.clone())
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(body.clone()),
+                    body,
+                )
+            }
+        };
+
+        if let Some(ref secret) = self.secret {
+            request = request.header(TIMESTAMP_HEADER, timestamp.as_str()).header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(secret, &timestamp, &signed_data)),
+            );
+        }
+
+        match request.send() {
+            Err(e) => Err(Error::other(format!(
+                "Failed to call notify url {}: {}",
+                url, e
+            ))),
+            Ok(ref r) if r.status().is_success() => {
+                debug!(
+                    "Notification call to {} for job {} succeeded. Response: {}",
+                    url,
+                    job.id,
+                    r.status().as_str()
+                );
+                Ok(())
+            }
+            Ok(r) => Err(Error::other(format!(
+                "Notification call to {} failed. Response: {}",
+                url,
+                r.status().as_str()
+            ))),
+        }
+    }
+}
+
+/// The URL called for a GET notification. The job ID and event are added
+/// to the query parameters the URL has already, i.e. an access token.
+fn get_url(url: &Url, event: &str, job: &Job) -> Url {
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .append_pair("jobid", &job.id.to_string())
+        .append_pair("event", event);
+    url
+}
+
+/// Checks whether the daemon is able to notify the given target
+pub fn validate_target(target: &NotifyTarget) -> std::result::Result<(), String> {
+    match Url::parse(&target.url) {
+        Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+        Ok(ref url) if url.scheme() == "mailto" => {
+            mail::recipients(url).map(|_| ()).map_err(|e| e.to_string())
+        }
+        Ok(_) => Err(format!(
+            "Unsupported notification URL '{}', expected http(s) or mailto",
+            target.url
+        )),
+        Err(e) => Err(format!("Invalid notification URL '{}': {}", target.url, e)),
+    }
+}
+
+/// Shared handle to the notification outbox, used to enqueue, list and
+/// replay notifications while the delivery thread works on the outbox
+#[derive(Clone)]
+pub struct Notifier {
+    outbox: Arc<(Mutex<Outbox>, Condvar)>,
+    state: Arc<Mutex<State>>,
+    default_url: Option<Url>,
+}
+
+impl Notifier {
+    /// Sets up a notifier working on the given outbox. Changes to the
+    /// outbox are persisted through the given program state. Jobs without
+    /// notification targets of their own are reported to `default_url`
+    /// once they have finished.
+    pub fn new(outbox: Outbox, state: Arc<Mutex<State>>, default_url: Option<Url>) -> Notifier {
+        Notifier {
+            outbox: Arc::new((Mutex::new(outbox), Condvar::new())),
+            state,
+            default_url,
+        }
+    }
+
+    /// Notifies all targets of the job that are interested in the given
+    /// event. Falls back to the daemon's notify URL for finished jobs that
+    /// do not have targets of their own.
+    pub fn dispatch(&self, event: JobEvent, job: &Job) {
+        if job.notify.is_empty() {
+            if let Some(ref url) = self.default_url {
+                if event.is_terminal() {
+                    self.enqueue(url, &event.to_string(), job);
+                }
+            }
+            return;
+        }
+
+        for target in job.notify.iter().filter(|t| t.wants(event)) {
+            match Url::parse(&target.url) {
+                Ok(url) => self.enqueue(&url, &event.to_string(), job),
+                Err(e) => error!(
+                    "Invalid notification URL {} for job {}: {}",
+                    target.url, job.id, e
+                ),
+            }
+        }
+    }
+
+    /// Stores the outbox. Failures are logged by the state object.
+    fn save(&self, outbox: &Outbox) {
+        let _ = self.state.lock().unwrap().save_outbox(outbox);
+    }
+
+    /// Puts a notification into the outbox and wakes up the delivery thread
+    pub fn enqueue(&self, url: &Url, event: &str, job: &Job) {
+        let (ref outbox, ref cvar) = *self.outbox;
+        let mut o = outbox.lock().unwrap();
+        let id = o.push(url.to_string(), event.to_owned(), with_output_tail(job));
+        debug!(
+            "Queued notification {} ({}) for job {} to {}",
+            id, event, job.id, url
+        );
+        self.save(&o);
+        cvar.notify_one();
+    }
+
+    /// Returns all undelivered notifications
+    pub fn list(&self) -> Vec<Notification> {
+        self.outbox.0.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Schedules the given notification, or all failed notifications, for
+    /// immediate delivery and returns the affected notifications
+    pub fn retry(&self, id: Option<u64>) -> Vec<Notification> {
+        let (ref outbox, ref cvar) = *self.outbox;
+        let mut o = outbox.lock().unwrap();
+        let replayed = o.retry(id);
+        if !replayed.is_empty() {
+            self.save(&o);
+            cvar.notify_one();
+        }
+        replayed
+    }
+
+    /// Delivers notifications as they become due, by mail for 'mailto:'
+    /// URLs and through the web hook otherwise. Failed deliveries are
+    /// retried until `max_attempts` attempts have been made.
+    pub fn run(&self, webhook: Webhook, mailer: Mailer, max_attempts: u32) -> ! {
+        let (ref outbox, ref cvar) = *self.outbox;
+
+        loop {
+            // wait until at least one notification is due
+            let due: Vec<Notification> = {
+                let mut o = outbox.lock().unwrap();
+                loop {
+                    let now = SystemTime::now();
+                    let due: Vec<Notification> = o.due(now).cloned().collect();
+                    if !due.is_empty() {
+                        break due;
+                    }
+
+                    o = match o.next_attempt() {
+                        Some(t) => {
+                            let timeout = t.duration_since(now).unwrap_or_default();
+                            cvar.wait_timeout(o, timeout).unwrap().0
+                        }
+                        None => cvar.wait(o).unwrap(),
+                    };
+                }
+            };
+
+            for n in due {
+                let result = Url::parse(&n.url)
+                    .map_err(|e| Error::other(format!("Invalid notify url {}: {}", n.url, e)))
+                    .and_then(|url| match url.scheme() {
+                        "mailto" => mailer.send(&url, &n.event, &n.job),
+                        _ => webhook.send(&url, &n.event, &n.job),
+                    });
+
+                let mut o = outbox.lock().unwrap();
+                match result {
+                    Ok(_) => o.delivered(n.id),
+                    Err(e) => {
+                        error!(
+                            "Failed to deliver notification {} for job {} (attempt {} of {}): {}",
+                            n.id,
+                            n.job.id,
+                            n.attempts + 1,
+                            max_attempts,
+                            e
+                        );
+                        o.failed(n.id, e.to_string(), max_attempts);
+                    }
+                }
+                self.save(&o);
+            }
+        }
+    }
+}
+
+/// Computes the hex-encoded HMAC-SHA256 of '<timestamp>.<data>'. For POST
+/// calls, data is the request body. For GET calls, it is the query string.
+fn sign(secret: &str, timestamp: &str, data: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.input(timestamp.as_bytes());
+    mac.input(b".");
+    mac.input(data.as_bytes());
+    hex::encode(mac.result().code())
+}
+
+/// Returns a copy of the job with stdout and stderr cut down to their ends
+fn with_output_tail(job: &Job) -> Job {
+    let mut job = job.clone();
+    job.stdout = tail(&job.stdout, OUTPUT_TAIL_BYTES).to_owned();
+    job.stderr = tail(&job.stderr, OUTPUT_TAIL_BYTES).to_owned();
+    job
+}
+
+/// The last `max` bytes of a string, rounded to the next character boundary
+fn tail(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut start = s.len() - max;
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    fn job() -> Job {
+        Job {
+            id: 7,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: Some(UNIX_EPOCH + Duration::from_secs(100)),
+            finished: Some(UNIX_EPOCH + Duration::from_secs(190)),
+            stderr: String::new(),
+            stdout: String::new(),
+            state: JobState::Terminated(0),
+            pid: Some(4711),
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    /// A request received by a fake endpoint
+    struct Received {
+        /// Request line and header fields
+        lines: Vec<String>,
+        body: String,
+    }
+
+    impl Received {
+        /// Value of the header field with the given name
+        fn header(&self, name: &str) -> Option<String> {
+            self.lines.iter().skip(1).find_map(|l| {
+                let (k, v) = l.split_at(l.find(':')?);
+                if k.eq_ignore_ascii_case(name) {
+                    Some(v[1..].trim().to_string())
+                } else {
+                    None
+                }
+            })
+        }
+    }
+
+    /// Accepts a single HTTP request, answers it with 200 and returns it
+    fn fake_endpoint() -> (String, thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                lines.push(line.trim_end().to_string());
+            }
+            let mut received = Received {
+                lines,
+                body: String::new(),
+            };
+            let length = received
+                .header("Content-Length")
+                .map_or(0, |l| l.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            received.body = String::from_utf8(body).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            received
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn signature_test_vectors() {
+        // computed with 'openssl dgst -sha256 -hmac <secret>' over
+        // '<timestamp>.<data>'
+        assert_eq!(
+            sign("secret", "1622548800", "token=abc&jobid=7&event=failed"),
+            "8f47b36f3c6f4844d274dd7cf3d593a4944963d0bf8f6eff428ff28a46b0ddf5"
+        );
+        assert_eq!(
+            sign("s3cr3t key", "1622548800", r#"{"event":"succeeded"}"#),
+            "d3d6fce0625587d04935194e5bdf0307456d5752a85544b79554cda8ab5d4ea2"
+        );
+    }
+
+    #[test]
+    fn get_url_keeps_query() {
+        let url = Url::parse("https://hook.example.org/x?token=abc").unwrap();
+        assert_eq!(
+            get_url(&url, "succeeded", &job()).as_str(),
+            "https://hook.example.org/x?token=abc&jobid=7&event=succeeded"
+        );
+
+        let url = Url::parse("https://hook.example.org/x").unwrap();
+        assert_eq!(
+            get_url(&url, "started", &job()).as_str(),
+            "https://hook.example.org/x?jobid=7&event=started"
+        );
+    }
+
+    #[test]
+    fn get_signs_query() {
+        let (address, endpoint) = fake_endpoint();
+        let url = Url::parse(&format!("http://{}/x?token=abc", address)).unwrap();
+        let webhook = Webhook::new(NotifyMethod::Get, Some("secret".to_string()));
+        webhook.send(&url, "failed", &job()).unwrap();
+
+        let received = endpoint.join().unwrap();
+        assert_eq!(
+            received.lines[0],
+            "GET /x?token=abc&jobid=7&event=failed HTTP/1.1"
+        );
+        let timestamp = received.header(TIMESTAMP_HEADER).unwrap();
+        assert_eq!(
+            received.header(SIGNATURE_HEADER).unwrap(),
+            format!(
+                "sha256={}",
+                sign("secret", &timestamp, "token=abc&jobid=7&event=failed")
+            )
+        );
+    }
+
+    #[test]
+    fn post_sends_signed_job() {
+        let (address, endpoint) = fake_endpoint();
+        let url = Url::parse(&format!("http://{}/hook", address)).unwrap();
+        let webhook = Webhook::new(NotifyMethod::Post, Some("secret".to_string()));
+        let mut job = job();
+        job.stdout = format!("{}end\n", "x".repeat(OUTPUT_TAIL_BYTES));
+        webhook.send(&url, "succeeded", &job).unwrap();
+
+        let received = endpoint.join().unwrap();
+        assert_eq!(received.lines[0], "POST /hook HTTP/1.1");
+        assert_eq!(
+            received.header("Content-Type").as_deref(),
+            Some("application/json")
+        );
+
+        let body: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(body["event"], "succeeded");
+        assert_eq!(body["exit_code"], 0);
+        assert_eq!(body["job"]["id"], 7);
+        assert_eq!(body["job"]["cmdline"], "gwas --chr 1");
+        let stdout = body["job"]["stdout"].as_str().unwrap();
+        assert_eq!(stdout.len(), OUTPUT_TAIL_BYTES);
+        assert!(stdout.ends_with("xend\n"));
+
+        // the signature covers the body exactly as sent
+        let timestamp = received.header(TIMESTAMP_HEADER).unwrap();
+        assert!(timestamp.parse::<u64>().is_ok());
+        assert_eq!(
+            received.header(SIGNATURE_HEADER).unwrap(),
+            format!("sha256={}", sign("secret", &timestamp, &received.body))
+        );
+    }
+
+    #[test]
+    fn unsigned_without_secret() {
+        let (address, endpoint) = fake_endpoint();
+        let url = Url::parse(&format!("http://{}/hook", address)).unwrap();
+        let mut job = job();
+        job.state = JobState::Killed(9);
+        Webhook::new(NotifyMethod::Post, None)
+            .send(&url, "killed", &job)
+            .unwrap();
+
+        let received = endpoint.join().unwrap();
+        assert!(received.header(TIMESTAMP_HEADER).is_none());
+        assert!(received.header(SIGNATURE_HEADER).is_none());
+        let body: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(body["exit_code"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,287 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * outbox.rs
+ *
+ * Notifications that have not been delivered yet. Every notification is
+ * kept in the outbox until its target has accepted it. Failed deliveries
+ * are retried with exponential backoff until the configured number of
+ * attempts is exhausted. Notifications that failed for good stay in the
+ * outbox so that they can be inspected and replayed by an operator, up to
+ * MAX_FAILED of them; beyond that, the oldest ones are dropped.
+ **/
+use std::time::{Duration, SystemTime};
+
+use job_queue::Job;
+
+/// Delay before the first retry. Doubles with every failed attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+
+/// Upper bound for the delay between two attempts
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(3600);
+
+/// Number of failed notifications kept for inspection and replay
+pub const MAX_FAILED: usize = 1000;
+
+/// Delay before the next attempt after the given number of failed attempts
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .checked_mul(1 << attempts.saturating_sub(1).min(16))
+        .unwrap_or(RETRY_MAX_DELAY)
+        .min(RETRY_MAX_DELAY)
+}
+
+/// The delivery state of a single notification
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum DeliveryState {
+    /// waiting for its first or next delivery attempt
+    Pending,
+
+    /// all delivery attempts failed, waiting to be replayed manually
+    Failed,
+}
+
+/// A notification about a job that is waiting to be delivered
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notification {
+    /// The unique notification ID
+    pub id: u64,
+
+    /// URL to be notified
+    pub url: String,
+
+    /// What happened to the job
+    pub event: String,
+
+    /// The job as it was when the event happened
+    pub job: Job,
+
+    /// Timestamp of the event
+    pub created: SystemTime,
+
+    /// Number of failed delivery attempts
+    pub attempts: u32,
+
+    /// Earliest time of the next delivery attempt
+    pub next_attempt: SystemTime,
+
+    /// Error message of the last failed attempt
+    pub last_error: Option<String>,
+
+    /// Current delivery state
+    pub state: DeliveryState,
+}
+
+/// The notification outbox itself
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Outbox {
+    /// The last ID assigned to a notification
+    last_id: u64,
+
+    /// Notifications that have not been delivered yet
+    entries: Vec<Notification>,
+}
+
+impl Outbox {
+    /// Provides an iterator over all undelivered notifications
+    pub fn iter(&self) -> impl Iterator<Item = &Notification> {
+        self.entries.iter()
+    }
+
+    /// Adds a notification that is due immediately and returns its ID
+    pub fn push(&mut self, url: String, event: String, job: Job) -> u64 {
+        let now = SystemTime::now();
+        self.last_id += 1;
+        self.entries.push(Notification {
+            id: self.last_id,
+            url,
+            event,
+            job,
+            created: now,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+            state: DeliveryState::Pending,
+        });
+        self.last_id
+    }
+
+    /// Provides an iterator over the pending notifications that are due at the given time
+    pub fn due(&self, now: SystemTime) -> impl Iterator<Item = &Notification> {
+        self.entries
+            .iter()
+            .filter(move |n| n.state == DeliveryState::Pending && n.next_attempt <= now)
+    }
+
+    /// Returns the time of the earliest pending delivery attempt, if any
+    pub fn next_attempt(&self) -> Option<SystemTime> {
+        self.entries
+            .iter()
+            .filter(|n| n.state == DeliveryState::Pending)
+            .map(|n| n.next_attempt)
+            .min()
+    }
+
+    /// Removes a successfully delivered notification
+    pub fn delivered(&mut self, id: u64) {
+        self.entries.retain(|n| n.id != id);
+    }
+
+    /// Records a failed delivery attempt and schedules the next one. After
+    /// `max_attempts` attempts, the notification is marked as failed, and
+    /// the oldest failed notifications beyond MAX_FAILED are dropped.
+    pub fn failed(&mut self, id: u64, error: String, max_attempts: u32) {
+        if let Some(n) = self.entries.iter_mut().find(|n| n.id == id) {
+            n.attempts += 1;
+            n.last_error = Some(error);
+            if n.attempts >= max_attempts {
+                n.state = DeliveryState::Failed;
+            } else {
+                n.next_attempt = SystemTime::now() + retry_delay(n.attempts);
+            }
+        }
+        self.drop_excess_failed();
+    }
+
+    /// Drops the oldest failed notifications, keeping MAX_FAILED of them
+    fn drop_excess_failed(&mut self) {
+        let failed = self
+            .entries
+            .iter()
+            .filter(|n| n.state == DeliveryState::Failed)
+            .count();
+        if failed <= MAX_FAILED {
+            return;
+        }
+
+        let mut ids: Vec<(SystemTime, u64)> = self
+            .entries
+            .iter()
+            .filter(|n| n.state == DeliveryState::Failed)
+            .map(|n| (n.created, n.id))
+            .collect();
+        ids.sort();
+        ids.truncate(failed - MAX_FAILED);
+        warn!(
+            "Dropping {} failed notifications, only {} are kept",
+            ids.len(),
+            MAX_FAILED
+        );
+        self.entries
+            .retain(|n| !ids.iter().any(|&(_, id)| id == n.id));
+    }
+
+    /// Schedules the given notification, or all failed notifications if no
+    /// ID is given, for immediate delivery. Returns the affected notifications.
+    pub fn retry(&mut self, id: Option<u64>) -> Vec<Notification> {
+        let now = SystemTime::now();
+        self.entries
+            .iter_mut()
+            .filter(|n| match id {
+                Some(id) => n.id == id,
+                None => n.state == DeliveryState::Failed,
+            })
+            .map(|n| {
+                n.state = DeliveryState::Pending;
+                n.attempts = 0;
+                n.next_attempt = now;
+                n.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use job_queue::JobState;
+    use std::time::UNIX_EPOCH;
+
+    fn job() -> Job {
+        Job {
+            id: 7,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: None,
+            finished: None,
+            stderr: String::new(),
+            stdout: String::new(),
+            state: JobState::Terminated(0),
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    #[test]
+    fn backoff_schedule() {
+        let delays: Vec<u64> = (1..=11).map(|a| retry_delay(a).as_secs()).collect();
+        assert_eq!(
+            delays,
+            vec![10, 20, 40, 80, 160, 320, 640, 1280, 2560, 3600, 3600]
+        );
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn retries_until_failed() {
+        let mut outbox = Outbox::default();
+        let id = outbox.push("http://x".to_string(), "succeeded".to_string(), job());
+        let now = SystemTime::now();
+        assert_eq!(outbox.due(now).count(), 1);
+
+        outbox.failed(id, "refused".to_string(), 3);
+        assert_eq!(outbox.due(now).count(), 0);
+        assert!(outbox.next_attempt().unwrap() >= now + Duration::from_secs(10));
+
+        outbox.failed(id, "refused".to_string(), 3);
+        outbox.failed(id, "refused".to_string(), 3);
+        let n = outbox.iter().next().unwrap();
+        assert_eq!(n.state, DeliveryState::Failed);
+        assert_eq!(n.attempts, 3);
+        assert_eq!(outbox.next_attempt(), None);
+
+        assert_eq!(outbox.retry(None).len(), 1);
+        assert_eq!(outbox.due(SystemTime::now()).count(), 1);
+        outbox.delivered(id);
+        assert_eq!(outbox.iter().count(), 0);
+    }
+
+    #[test]
+    fn keeps_limited_failed_notifications() {
+        let mut outbox = Outbox::default();
+        for _ in 0..MAX_FAILED + 5 {
+            let id = outbox.push("http://x".to_string(), "failed".to_string(), job());
+            outbox.failed(id, "refused".to_string(), 1);
+        }
+        let pending = outbox.push("http://x".to_string(), "failed".to_string(), job());
+        outbox.failed(pending, "refused".to_string(), 2);
+
+        assert_eq!(outbox.iter().count(), MAX_FAILED + 1);
+        // the oldest failed notifications are gone, the pending one stays
+        assert_eq!(outbox.iter().next().unwrap().id, 6);
+        assert!(outbox.iter().any(|n| n.id == pending));
+    }
+}
@@ -0,0 +1,544 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * output.rs
+ *
+ * Formats the results of client commands for the console: as aligned table
+ * for humans, or as JSON, YAML or CSV for scripts. The machine-readable
+ * formats describe jobs as flat records with fixed field names and RFC 3339
+ * timestamps, independent of the protocol's internal representation.
+ **/
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use serde_json::{self, Value};
+use yaml_rust::{Yaml, YamlEmitter};
+
+use qmanager::job_queue::{Job, JobState};
+
+/// How client commands print their results
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OutputFormat {
+    /// aligned columns, local time
+    #[default]
+    Table,
+
+    /// a single JSON document
+    Json,
+
+    /// a single YAML document
+    Yaml,
+
+    /// comma-separated values with a header line
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "Invalid output format '{}', expected one of table, json, yaml, csv",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            OutputFormat::Table => "table",
+            OutputFormat::Json => "json",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Csv => "csv",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Columns of CSV job listings, in order. All fields of JobRecord except
+/// the job's output.
+const CSV_COLUMNS: &[&str] = &[
+    "id",
+    "appkey",
+    "cmdline",
+    "state",
+    "exit_code",
+    "signal",
+    "error",
+    "owner",
+    "tags",
+    "scheduled",
+    "started",
+    "finished",
+    "duration",
+    "pid",
+    "held",
+];
+
+/// A job as printed in the machine-readable formats
+#[derive(Serialize, Debug)]
+pub struct JobRecord {
+    pub id: u64,
+
+    /// First word of the command line
+    pub appkey: String,
+    pub cmdline: String,
+
+    /// queued, running, terminated, killed or failed
+    pub state: &'static str,
+
+    /// Exit code of terminated jobs
+    pub exit_code: Option<i32>,
+
+    /// Signal killed jobs were killed with
+    pub signal: Option<i32>,
+
+    /// Why failed jobs could not be run
+    pub error: Option<String>,
+    pub owner: Option<String>,
+    pub tags: Vec<String>,
+
+    /// RFC 3339 timestamps in UTC
+    pub scheduled: String,
+    pub started: Option<String>,
+    pub finished: Option<String>,
+
+    /// Run time in whole seconds, up to now for running jobs
+    pub duration: Option<u64>,
+    pub pid: Option<u32>,
+
+    /// Whether the queued job is held back by the scheduler
+    pub held: bool,
+
+    /// Output, only if it has been requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+}
+
+impl JobRecord {
+    /// Describes the job, including its output if `with_output` is set
+    pub fn new(job: &Job, with_output: bool) -> JobRecord {
+        let rfc3339 = |t: SystemTime| humantime::format_rfc3339_seconds(t).to_string();
+        JobRecord {
+            id: job.id,
+            appkey: job
+                .cmdline
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_string(),
+            cmdline: job.cmdline.clone(),
+            state: state_name(&job.state),
+            exit_code: match job.state {
+                JobState::Terminated(code) => Some(code),
+                _ => None,
+            },
+            signal: match job.state {
+                JobState::Killed(signal) => Some(signal),
+                _ => None,
+            },
+            error: match job.state {
+                JobState::Failed(ref reason) => Some(reason.clone()),
+                _ => None,
+            },
+            owner: job.owner.clone(),
+            tags: job.tags.clone(),
+            scheduled: rfc3339(job.scheduled),
+            started: job.started.map(rfc3339),
+            finished: job.finished.map(rfc3339),
+            duration: run_time(job).map(|d| d.as_secs()),
+            pid: job.pid,
+            held: job.held,
+            stdout: if with_output {
+                Some(job.stdout.clone())
+            } else {
+                None
+            },
+            stderr: if with_output {
+                Some(job.stderr.clone())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// The record's fields in the order of CSV_COLUMNS
+    fn csv_fields(&self) -> Vec<String> {
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        vec![
+            self.id.to_string(),
+            self.appkey.clone(),
+            self.cmdline.clone(),
+            self.state.to_string(),
+            opt(self.exit_code.map(|c| c.to_string())),
+            opt(self.signal.map(|s| s.to_string())),
+            opt(self.error.clone()),
+            opt(self.owner.clone()),
+            self.tags.join(";"),
+            self.scheduled.clone(),
+            opt(self.started.clone()),
+            opt(self.finished.clone()),
+            opt(self.duration.map(|d| d.to_string())),
+            opt(self.pid.map(|p| p.to_string())),
+            self.held.to_string(),
+        ]
+    }
+}
+
+/// Lower-case name of a job state, without its details
+pub fn state_name(state: &JobState) -> &'static str {
+    match state {
+        JobState::Queued => "queued",
+        JobState::Running => "running",
+        JobState::Terminated(_) => "terminated",
+        JobState::Killed(_) => "killed",
+        JobState::Failed(_) => "failed",
+    }
+}
+
+/// Time the job has been running, up to now if it has not finished yet
+pub fn run_time(job: &Job) -> Option<Duration> {
+    match (job.started, job.finished) {
+        (Some(start), Some(end)) => end.duration_since(start).ok(),
+        (Some(start), None) => SystemTime::now().duration_since(start).ok(),
+        _ => None,
+    }
+}
+
+/// Formats a point in time in the local time zone, or '-'
+pub fn local_time(t: Option<SystemTime>) -> String {
+    t.map(|t| {
+        DateTime::<Local>::from(t)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    })
+    .unwrap_or_else(|| "-".to_string())
+}
+
+/// Formats the run time of a job in whole seconds, or '-'
+pub fn format_run_time(job: &Job) -> String {
+    run_time(job)
+        .map(|d| humantime::format_duration(Duration::from_secs(d.as_secs())).to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Columns of job tables
+pub const JOB_TABLE_HEADER: &[&str] = &[
+    "ID", "APPKEY", "STATE", "OWNER", "QUEUED", "STARTED", "FINISHED", "DURATION", "EXIT",
+];
+
+/// The cells of a job's line in job tables, in the order of JOB_TABLE_HEADER
+pub fn job_table_row(job: &Job) -> Vec<String> {
+    let exit = match job.state {
+        JobState::Terminated(code) => code.to_string(),
+        JobState::Killed(signal) => format!("signal {}", signal),
+        _ => "-".to_string(),
+    };
+    vec![
+        job.id.to_string(),
+        job.cmdline
+            .split_whitespace()
+            .next()
+            .unwrap_or("-")
+            .to_string(),
+        if job.held {
+            "held".to_string()
+        } else {
+            state_name(&job.state).to_string()
+        },
+        job.owner.clone().unwrap_or_else(|| "-".to_string()),
+        local_time(Some(job.scheduled)),
+        local_time(job.started),
+        local_time(job.finished),
+        format_run_time(job),
+        exit,
+    ]
+}
+
+/// Prints jobs as table with one line per job
+pub fn print_job_table(jobs: &[Job]) {
+    let rows: Vec<Vec<String>> = jobs.iter().map(job_table_row).collect();
+    print_table(JOB_TABLE_HEADER, &rows);
+}
+
+/// Prints rows as aligned columns below a header. Rows with fewer cells
+/// than the header, i.e. warnings, do not affect the column widths; their
+/// last cell takes the rest of the line.
+pub fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows.iter().filter(|row| row.len() >= header.len()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<1$}", cell, width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header.to_vec());
+    for row in rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// Prints job records as CSV with a header line
+pub fn print_job_csv(records: &[JobRecord]) {
+    println!("{}", CSV_COLUMNS.join(","));
+    for record in records {
+        let fields: Vec<String> = record.csv_fields().iter().map(|f| csv_field(f)).collect();
+        println!("{}", fields.join(","));
+    }
+}
+
+/// Prints job records of several daemons as CSV with a header line, the
+/// daemon's name in the first column
+pub fn print_host_job_csv(records: &[(&str, JobRecord)]) {
+    println!("host,{}", CSV_COLUMNS.join(","));
+    for (host, record) in records {
+        let fields: Vec<String> = record.csv_fields().iter().map(|f| csv_field(f)).collect();
+        println!("{},{}", csv_field(host), fields.join(","));
+    }
+}
+
+/// Prints any other result as CSV with a header line and a single row
+pub fn print_csv(columns: &[&str], values: &[String]) {
+    println!("{}", columns.join(","));
+    let fields: Vec<String> = values.iter().map(|v| csv_field(v)).collect();
+    println!("{}", fields.join(","));
+}
+
+/// Quotes a CSV field if necessary (RFC 4180)
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Prints a value as JSON or YAML document. Not for tables or CSV.
+pub fn print_document<T: Serialize>(format: OutputFormat, value: &T) {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+        OutputFormat::Yaml => {
+            let mut out = String::new();
+            YamlEmitter::new(&mut out)
+                .dump(&to_yaml(serde_json::to_value(value).unwrap()))
+                .unwrap();
+            println!("{}", out);
+        }
+        OutputFormat::Table | OutputFormat::Csv => {
+            panic!("{} is not a document format", format)
+        }
+    }
+}
+
+/// Converts a JSON value into the equivalent YAML value
+fn to_yaml(value: Value) -> Yaml {
+    match value {
+        Value::Null => Yaml::Null,
+        Value::Bool(b) => Yaml::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Yaml::Integer(i),
+            None => Yaml::Real(n.to_string()),
+        },
+        Value::String(s) => Yaml::String(s),
+        Value::Array(a) => Yaml::Array(a.into_iter().map(to_yaml).collect()),
+        Value::Object(o) => Yaml::Hash(
+            o.into_iter()
+                .map(|(k, v)| (Yaml::String(k), to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn job(state: JobState) -> Job {
+        Job {
+            id: 12,
+            cmdline: "gwas --chr 1,2".to_string(),
+            scheduled: UNIX_EPOCH + Duration::from_secs(1_622_548_800),
+            started: None,
+            finished: None,
+            stderr: "warning".to_string(),
+            stdout: "done".to_string(),
+            state,
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: vec!["cohort-a".to_string(), "chr1".to_string()],
+            held: false,
+        }
+    }
+
+    fn finished(state: JobState) -> Job {
+        let mut job = job(state);
+        job.started = Some(job.scheduled + Duration::from_secs(10));
+        job.finished = Some(job.scheduled + Duration::from_secs(100));
+        job.pid = Some(4711);
+        job.owner = Some("alice".to_string());
+        job
+    }
+
+    #[test]
+    fn json_field_names() {
+        let record = JobRecord::new(&finished(JobState::Terminated(3)), false);
+        let value = serde_json::to_value(&record).unwrap();
+        let mut fields: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort_unstable();
+        let mut expected = CSV_COLUMNS.to_vec();
+        expected.sort_unstable();
+        assert_eq!(fields, expected);
+
+        assert_eq!(value["appkey"], "gwas");
+        assert_eq!(value["state"], "terminated");
+        assert_eq!(value["exit_code"], 3);
+        assert_eq!(value["signal"], Value::Null);
+        assert_eq!(value["tags"], serde_json::json!(["cohort-a", "chr1"]));
+        assert_eq!(value["scheduled"], "2021-06-01T12:00:00Z");
+        assert_eq!(value["started"], "2021-06-01T12:00:10Z");
+        assert_eq!(value["duration"], 90);
+
+        let record = JobRecord::new(&job(JobState::Failed("no such file".to_string())), true);
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["error"], "no such file");
+        assert_eq!(value["started"], Value::Null);
+        assert_eq!(value["stdout"], "done");
+        assert_eq!(value["stderr"], "warning");
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("gwas"), "gwas");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("gwas --chr 1,2"), "\"gwas --chr 1,2\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("line\r\nbreak"), "\"line\r\nbreak\"");
+
+        let record = JobRecord::new(&finished(JobState::Killed(9)), false);
+        let fields = record.csv_fields();
+        assert_eq!(fields.len(), CSV_COLUMNS.len());
+        assert_eq!(fields[5], "9");
+        assert_eq!(fields[8], "cohort-a;chr1");
+        assert_eq!(fields[14], "false");
+    }
+
+    #[test]
+    fn job_table_rows() {
+        let cases = vec![
+            (job(JobState::Queued), "queued", "-", "-"),
+            (job(JobState::Running), "running", "-", "-"),
+            (
+                finished(JobState::Terminated(0)),
+                "terminated",
+                "0",
+                "1m 30s",
+            ),
+            (
+                finished(JobState::Killed(15)),
+                "killed",
+                "signal 15",
+                "1m 30s",
+            ),
+            (
+                finished(JobState::Failed("denied".to_string())),
+                "failed",
+                "-",
+                "1m 30s",
+            ),
+        ];
+        for (job, state, exit, duration) in cases {
+            let row = job_table_row(&job);
+            assert_eq!(row.len(), JOB_TABLE_HEADER.len());
+            assert_eq!(row[0], "12");
+            assert_eq!(row[1], "gwas");
+            assert_eq!(row[2], state);
+            assert_eq!(row[3], job.owner.clone().unwrap_or_else(|| "-".to_string()));
+            assert_eq!(row[4], local_time(Some(job.scheduled)));
+            assert_eq!(row[5], local_time(job.started));
+            assert_eq!(row[6], local_time(job.finished));
+            assert_eq!(row[7], duration);
+            assert_eq!(row[8], exit);
+        }
+
+        let mut held = job(JobState::Queued);
+        held.held = true;
+        held.cmdline = String::new();
+        let row = job_table_row(&held);
+        assert_eq!(row[1], "-");
+        assert_eq!(row[2], "held");
+        assert_eq!(row[5], "-");
+    }
+
+    #[test]
+    fn yaml_documents() {
+        let record = JobRecord::new(&finished(JobState::Terminated(0)), false);
+        let yaml = to_yaml(serde_json::to_value(&record).unwrap());
+        assert_eq!(yaml["id"], Yaml::Integer(12));
+        assert_eq!(yaml["appkey"], Yaml::String("gwas".to_string()));
+        assert_eq!(yaml["signal"], Yaml::Null);
+        assert_eq!(yaml["held"], Yaml::Boolean(false));
+        assert_eq!(yaml["tags"][1], Yaml::String("chr1".to_string()));
+    }
+
+    #[test]
+    fn output_formats() {
+        for format in &[
+            OutputFormat::Table,
+            OutputFormat::Json,
+            OutputFormat::Yaml,
+            OutputFormat::Csv,
+        ] {
+            assert_eq!(format.to_string().parse::<OutputFormat>(), Ok(*format));
+        }
+        assert_eq!("JSON".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
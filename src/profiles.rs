@@ -0,0 +1,350 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * profiles.rs
+ *
+ * Named server profiles of the per-user client configuration file, by
+ * default ~/.config/qmanager/client.toml:
+ *
+ *   default = "gwas1"
+ *
+ *   [profiles.gwas1]
+ *   host = "gwas1.example.org"
+ *   ca = "/etc/qmanager/ca.pem"
+ *   token = "..."
+ *
+ *   [profiles.local]
+ *   socket = "/run/qmanager/qmanager.sock"
+ *
+ * A profile holds everything a client needs to reach one daemon. Clients
+ * without a profile use the connection settings of the system-wide
+ * configuration file instead, which is shared with the daemon.
+ **/
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use config::{self, Config};
+use qmanager::client::QmanagerClient;
+use qmanager::clierror::{ClientError, Result};
+
+use cliopts::{DEFAULT_HOST, DEFAULT_PORT};
+
+/// Location of the client configuration file below the user's
+/// configuration directory
+const CLIENT_CONFIG: &str = "qmanager/client.toml";
+
+/// Connection settings for one daemon
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    /// Host name of the daemon
+    pub host: Option<String>,
+
+    /// Port of the daemon
+    pub port: Option<u16>,
+
+    /// CA certificate to verify the daemon with
+    pub ca: Option<PathBuf>,
+
+    /// Use plain TCP instead of SSL/TLS
+    pub insecure: bool,
+
+    /// Local socket to connect to instead of host and port
+    pub socket: Option<PathBuf>,
+
+    /// Bearer token sent with every request over TCP
+    pub token: Option<String>,
+}
+
+impl Profile {
+    /// Sets up a client for the profile's daemon. A local socket, if given,
+    /// is preferred over host and port, which are used with the CA
+    /// certificate unless 'insecure' is set. The token, if any, is sent
+    /// over TCP only.
+    pub fn connect(&self, dump_protocol: bool) -> Result<QmanagerClient> {
+        let host = self.host.as_deref().unwrap_or(DEFAULT_HOST);
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        let token = self.token.as_deref();
+        let client = match self.socket {
+            Some(ref path) => QmanagerClient::unix(path.clone()),
+            None if self.insecure => QmanagerClient::tcp_with_token(host, port, None, token)?,
+            None if self.ca.is_some() => {
+                QmanagerClient::tcp_with_token(host, port, self.ca.as_deref(), token)?
+            }
+            None => {
+                return Err(ClientError::Config(format!(
+                    "Cannot connect to {}:{}, neither a CA certificate nor 'insecure' is set",
+                    host, port
+                )))
+            }
+        };
+        Ok(client.dump_protocol(dump_protocol))
+    }
+
+    /// Reads the connection settings of the system-wide configuration file
+    pub fn from_config(conf: &Config) -> Profile {
+        let mut profile = Profile::default();
+        profile.override_with(conf);
+        profile
+    }
+
+    /// Replaces the connection settings that are present in a configuration,
+    /// i.e. those given as environment variables. A host or port given
+    /// without a socket replaces the profile's socket, just like --host and
+    /// --port do.
+    pub fn override_with(&mut self, conf: &Config) {
+        if conf.get_str("socket").is_err()
+            && (conf.get_str("host").is_ok() || conf.get_int("port").is_ok())
+        {
+            self.socket = None;
+        }
+        if let Ok(host) = conf.get_str("host") {
+            self.host = Some(host);
+        }
+        if let Ok(port) = conf.get_int("port") {
+            self.port = Some(port as u16);
+        }
+        if let Ok(ca) = conf.get_str("ca") {
+            self.ca = Some(PathBuf::from(ca));
+        }
+        if let Ok(insecure) = conf.get_bool("insecure") {
+            self.insecure = insecure;
+        }
+        if let Ok(socket) = conf.get_str("socket") {
+            self.socket = Some(PathBuf::from(socket));
+        }
+        if let Ok(token) = conf.get_str("token") {
+            self.token = Some(token);
+        }
+    }
+}
+
+/// The per-user client configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// Profile used unless another one is selected
+    pub default: Option<String>,
+
+    /// Profiles by name. Names are case-insensitive.
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// Where the configuration has been read from
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+impl ClientConfig {
+    /// Default location of the client configuration file, following the
+    /// XDG base directory specification
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join(CLIENT_CONFIG))
+    }
+
+    /// Reads the client configuration file. A missing file is an empty
+    /// configuration.
+    pub fn load(path: &Path) -> Result<ClientConfig> {
+        if !path.exists() {
+            return Ok(ClientConfig {
+                path: path.to_path_buf(),
+                ..ClientConfig::default()
+            });
+        }
+
+        let invalid = |e: config::ConfigError| {
+            ClientError::Config(format!(
+                "Cannot read client configuration file {:?}: {}",
+                path, e
+            ))
+        };
+        let mut conf = Config::default();
+        conf.merge(config::File::new(
+            path.to_str().unwrap(),
+            config::FileFormat::Toml,
+        ))
+        .map_err(invalid)?;
+        let mut client_config: ClientConfig = conf.try_into().map_err(invalid)?;
+        client_config.path = path.to_path_buf();
+        Ok(client_config)
+    }
+
+    /// The profile with the given name or, if no name is given, the default
+    /// profile if there is one
+    pub fn select(&self, name: Option<&str>) -> Result<Option<Profile>> {
+        let name = match name.or(self.default.as_deref()) {
+            Some(name) => name.to_lowercase(),
+            None => return Ok(None),
+        };
+        match self.profiles.get(&name) {
+            Some(profile) => Ok(Some(profile.clone())),
+            None if self.profiles.is_empty() => Err(ClientError::Config(format!(
+                "Unknown profile '{}', no profiles are configured",
+                name
+            ))),
+            None => Err(ClientError::Config(format!(
+                "Unknown profile '{}', expected one of {}",
+                name,
+                self.profiles
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    /// Writes a client configuration file and reads it
+    fn client_config(name: &str, toml: &str) -> ClientConfig {
+        let path = env::temp_dir().join(format!("qmanager-{}-{}.toml", name, process::id()));
+        fs::write(&path, toml).unwrap();
+        let client_config = ClientConfig::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        client_config
+    }
+
+    fn config_error<T>(result: Result<T>) -> String {
+        match result {
+            Err(ClientError::Config(msg)) => msg,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn selects_profiles() {
+        let client_config = client_config(
+            "profiles",
+            r#"
+            default = "gwas1"
+
+            [profiles.gwas1]
+            host = "gwas1.example.org"
+            ca = "/etc/qmanager/ca.pem"
+            token = "secret"
+
+            [profiles.Local]
+            socket = "/run/qmanager/qmanager.sock"
+            "#,
+        );
+
+        let profile = client_config.select(None).unwrap().unwrap();
+        assert_eq!(profile.host.as_deref(), Some("gwas1.example.org"));
+        assert_eq!(profile.ca, Some(PathBuf::from("/etc/qmanager/ca.pem")));
+        assert_eq!(profile.token.as_deref(), Some("secret"));
+
+        let profile = client_config.select(Some("LOCAL")).unwrap().unwrap();
+        assert_eq!(
+            profile.socket,
+            Some(PathBuf::from("/run/qmanager/qmanager.sock"))
+        );
+        assert_eq!(profile.host, None);
+
+        let msg = config_error(client_config.select(Some("gwas2")));
+        assert_eq!(msg, "Unknown profile 'gwas2', expected one of gwas1, local");
+    }
+
+    #[test]
+    fn missing_profiles() {
+        let path = env::temp_dir().join(format!("qmanager-none-{}.toml", process::id()));
+        let empty = ClientConfig::load(&path).unwrap();
+        assert_eq!(empty.path, path);
+        assert!(empty.select(None).unwrap().is_none());
+        let msg = config_error(empty.select(Some("gwas1")));
+        assert_eq!(msg, "Unknown profile 'gwas1', no profiles are configured");
+
+        // a default that does not exist is an error, too
+        let dangling = client_config("dangling", "default = \"gwas1\"\n");
+        assert!(config_error(dangling.select(None)).starts_with("Unknown profile 'gwas1'"));
+    }
+
+    #[test]
+    fn overrides_profile() {
+        let mut profile = Profile {
+            socket: Some(PathBuf::from("/run/qmanager/qmanager.sock")),
+            token: Some("secret".to_string()),
+            ..Profile::default()
+        };
+        let mut conf = Config::default();
+        profile.override_with(&conf);
+        assert!(profile.socket.is_some());
+
+        // a port alone replaces the socket, and keeps everything else
+        conf.set("port", 1337).unwrap();
+        profile.override_with(&conf);
+        assert_eq!(profile.socket, None);
+        assert_eq!(profile.port, Some(1337));
+        assert_eq!(profile.token.as_deref(), Some("secret"));
+
+        conf.set("host", "gwas2.example.org").unwrap();
+        conf.set("socket", "/tmp/qmanager.sock").unwrap();
+        conf.set("insecure", true).unwrap();
+        profile.override_with(&conf);
+        assert_eq!(profile.host.as_deref(), Some("gwas2.example.org"));
+        assert_eq!(profile.socket, Some(PathBuf::from("/tmp/qmanager.sock")));
+        assert!(profile.insecure);
+    }
+
+    #[test]
+    fn connects_securely_or_not_at_all() {
+        let profile = Profile {
+            host: Some("gwas1.example.org".to_string()),
+            ..Profile::default()
+        };
+        let msg = config_error(profile.connect(false));
+        assert_eq!(
+            msg,
+            format!(
+                "Cannot connect to gwas1.example.org:{}, neither a CA certificate nor 'insecure' is set",
+                DEFAULT_PORT
+            )
+        );
+
+        let insecure = Profile {
+            insecure: true,
+            ..profile.clone()
+        };
+        assert!(insecure.connect(false).is_ok());
+        let with_ca = Profile {
+            ca: Some(PathBuf::from("resources/test/cert.pem")),
+            ..profile.clone()
+        };
+        assert!(with_ca.connect(false).is_ok());
+        let local = Profile {
+            socket: Some(PathBuf::from("/run/qmanager/qmanager.sock")),
+            ..profile
+        };
+        assert!(local.connect(false).is_ok());
+    }
+}
@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -20,14 +20,287 @@
  * SOFTWARE.
  **/
 
-use job_queue::{Job, QueueState};
+use std::error;
+use std::fmt;
+
+use serde_json::{self, Value};
+
+use job_queue::{Job, QueueState, Submission};
+use listing::{JobFilter, JobPage};
+use outbox::Notification;
+
+/// Version of the protocol spoken by this build. It is increased whenever
+/// requests or responses change in a way that older peers cannot handle.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Longest time in seconds a WaitJob request is held by the daemon. Clients
+/// waiting longer repeat the request.
+pub const MAX_WAIT_TIMEOUT: u64 = 300;
+
+/// First protocol version whose Error responses carry an error code. Older
+/// peers send and expect a bare error message.
+pub const ERROR_CODES_VERSION: u32 = 2;
+
+/// Optional features of the daemon that clients may check for before
+/// relying on them
+pub const FEATURES: &[&str] = &[
+    "notify-targets",
+    "mail-notifications",
+    "notifications",
+    "get-job",
+    "job-filters",
+    "error-codes",
+    "events",
+    "wait-job",
+    "job-control",
+    "submit-batch",
+];
+
+/// A request together with the protocol version the client speaks.
+/// Clients that predate versioning send bare requests instead.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestEnvelope<R = Request> {
+    /// Protocol version of the client
+    pub version: u32,
+
+    /// The actual request
+    pub request: R,
+}
+
+/// A response together with the protocol version the daemon speaks. Only
+/// sent in reply to a request envelope.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseEnvelope<R = Response> {
+    /// Protocol version of the daemon
+    pub version: u32,
+
+    /// The actual response
+    pub response: R,
+}
+
+/// Stable, machine-readable reason of a failed request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The request could not be decoded
+    MalformedRequest,
+
+    /// The request is not known to the daemon
+    UnsupportedRequest,
+
+    /// A request parameter has an invalid value
+    InvalidArgument,
+
+    /// The command line does not start with a known appkey
+    InvalidAppkey,
+
+    /// There is no job with the given ID
+    NoSuchJob,
+
+    /// The job is in the wrong state for the request, i.e. removing a
+    /// running job
+    WrongJobState,
+
+    /// There is no notification with the given ID
+    NoSuchNotification,
+
+    /// The caller is not allowed to make the request
+    Unauthorized,
+
+    /// There is no such REST resource
+    NoSuchResource,
+
+    /// The REST resource does not support the HTTP method
+    MethodNotAllowed,
+
+    /// The daemon failed to handle the request
+    Internal,
+
+    /// A code introduced by a newer daemon
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// HTTP status code of a response carrying this error
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorCode::MalformedRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::NoSuchJob | ErrorCode::NoSuchNotification | ErrorCode::NoSuchResource => 404,
+            ErrorCode::MethodNotAllowed => 405,
+            ErrorCode::WrongJobState => 409,
+            ErrorCode::InvalidArgument | ErrorCode::InvalidAppkey => 422,
+            ErrorCode::UnsupportedRequest => 501,
+            ErrorCode::Internal | ErrorCode::Unknown => 500,
+        }
+    }
+
+    /// Exit status of the client when the daemon answers with this error,
+    /// see clierror.rs
+    pub fn exit_status(self) -> i32 {
+        match self {
+            ErrorCode::NoSuchJob => 65,
+            ErrorCode::NoSuchNotification => 66,
+            ErrorCode::WrongJobState => 67,
+            ErrorCode::InvalidAppkey => 68,
+            ErrorCode::InvalidArgument => 69,
+            ErrorCode::MalformedRequest => 70,
+            ErrorCode::Unauthorized => 71,
+            ErrorCode::UnsupportedRequest => 72,
+            ErrorCode::NoSuchResource | ErrorCode::MethodNotAllowed => 73,
+            ErrorCode::Internal | ErrorCode::Unknown => 76,
+        }
+    }
+}
+
+/// Why a request failed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "ApiErrorRepr")]
+pub struct ApiError {
+    /// Machine-readable reason
+    pub code: ErrorCode,
+
+    /// Human-readable description
+    pub message: String,
+
+    /// Additional information depending on the code, i.e. the job ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// Wire format of an error. Daemons before protocol version 2 send the bare
+/// message.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ApiErrorRepr {
+    Message(String),
+    Full {
+        code: ErrorCode,
+        message: String,
+        #[serde(default)]
+        details: Option<Value>,
+    },
+}
+
+impl From<ApiErrorRepr> for ApiError {
+    fn from(r: ApiErrorRepr) -> Self {
+        match r {
+            ApiErrorRepr::Message(message) => ApiError::new(ErrorCode::Unknown, message),
+            ApiErrorRepr::Full {
+                code,
+                message,
+                details,
+            } => ApiError {
+                code,
+                message,
+                details,
+            },
+        }
+    }
+}
+
+impl ApiError {
+    /// Creates an error without details
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> ApiError {
+        ApiError {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Adds details to the error
+    pub fn with_details(mut self, details: Value) -> ApiError {
+        self.details = Some(details);
+        self
+    }
+
+    /// HTTP status code of a response carrying this error
+    pub fn status(&self) -> u16 {
+        self.code.status()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for ApiError {}
+
+/// What the daemon tells about itself in reply to a Hello request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    /// Protocol version of the daemon
+    pub protocol_version: u32,
+
+    /// Program version of the daemon
+    pub daemon_version: String,
+
+    /// Optional features supported by the daemon
+    pub features: Vec<String>,
+
+    /// Appkeys jobs can be submitted with. Empty for older daemons.
+    #[serde(default)]
+    pub appkeys: Vec<String>,
+}
+
+impl ServerInfo {
+    /// Describes this build of the daemon, offering the given appkeys
+    pub fn current<'a, I: IntoIterator<Item = &'a String>>(appkeys: I) -> ServerInfo {
+        let mut appkeys: Vec<String> = appkeys.into_iter().cloned().collect();
+        appkeys.sort();
+        ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            daemon_version: crate_version!().to_owned(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            appkeys,
+        }
+    }
+
+    /// Whether the daemon supports the given feature
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+impl RequestEnvelope {
+    /// Wraps a request for the current protocol version
+    pub fn new(request: Request) -> RequestEnvelope {
+        RequestEnvelope {
+            version: PROTOCOL_VERSION,
+            request,
+        }
+    }
+}
+
+impl<R> ResponseEnvelope<R> {
+    /// Wraps a response for the current protocol version
+    pub fn new(response: R) -> ResponseEnvelope<R> {
+        ResponseEnvelope {
+            version: PROTOCOL_VERSION,
+            response,
+        }
+    }
+}
 
 /// A request by the client for the server. May be answered by
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
+    /// Ask the daemon for its protocol version and features
+    /// Triggers a ServerInfo response
+    Hello,
+
     /// Submit a job with the given command-line string (contains an appkey)
+    /// and optional notification targets
     /// Triggers a SubmitJob or Error response
-    SubmitJob(String),
+    SubmitJob(Submission),
+
+    /// Submit several jobs at once. Either all of them are queued, in the
+    /// given order, or none if any of them is invalid.
+    /// Triggers a SubmitBatch or Error response
+    SubmitBatch(Vec<Submission>),
 
     /// Remove the job with the given ID with `Queued` or `Finished` job.
     /// Triggers a GetJob or an Error response
@@ -45,6 +318,46 @@ pub enum Request {
     /// Triggers a GetJobs response
     GetFinishedJobs,
 
+    /// Request a page of queued, running and finished jobs matching a filter
+    /// Triggers a JobPage or Error response
+    ListJobs(JobFilter),
+
+    /// Request a single queued, running or finished job. stdout and stderr
+    /// are included unless excluded explicitly.
+    /// Triggers a GetJob or Error response
+    GetJob {
+        /// Job ID (not PID)
+        id: u64,
+
+        /// Include the job's stdout
+        #[serde(default = "include_output")]
+        stdout: bool,
+
+        /// Include the job's stderr
+        #[serde(default = "include_output")]
+        stderr: bool,
+    },
+
+    /// Wait up to the given number of seconds for the job to finish. The
+    /// daemon may wait less, see MAX_WAIT_TIMEOUT.
+    /// Triggers a GetJob response with the job as it is at the end of the
+    /// wait, or an Error response
+    WaitJob { id: u64, timeout: u64 },
+
+    /// Hold the queued job with the given ID. Held jobs are skipped by the
+    /// scheduler until they are released.
+    /// Triggers a GetJob or Error response
+    HoldJob(u64),
+
+    /// Release the held job with the given ID
+    /// Triggers a GetJob or Error response
+    ReleaseJob(u64),
+
+    /// Move the queued job with the given ID to the given position among
+    /// the waiting jobs, 0 being the next one to run
+    /// Triggers a GetJob or Error response
+    MoveJob { id: u64, position: usize },
+
     /// Set the queue state
     /// Triggers a QueueState or Error response
     SetQueueState(QueueState),
@@ -52,6 +365,15 @@ pub enum Request {
     /// Request the current queue state
     /// Triggers a QueueState response
     GetQueueState,
+
+    /// Request the list of undelivered notifications
+    /// Triggers a Notifications response
+    GetNotifications,
+
+    /// Schedule the notification with the given ID for immediate delivery,
+    /// or all failed notifications if no ID is given
+    /// Triggers a Notifications response listing the affected notifications
+    RetryNotifications(Option<u64>),
 }
 
 /// A response from the server to the client
@@ -60,18 +382,129 @@ pub enum Response {
     /// The job has been submitted with the given ID
     SubmitJob(u64),
 
+    /// The jobs have been submitted with the given IDs, in order
+    SubmitBatch(Vec<u64>),
+
     /// A list of jobs
     GetJobs(Vec<Job>),
 
     /// A single job
     GetJob(Job),
 
-    /// The request could not be handled (error message given)
-    Error(String),
+    /// A page of jobs matching a filter
+    JobPage(JobPage),
+
+    /// The request could not be handled
+    Error(ApiError),
 
     /// The current queue state
     QueueState(QueueState),
 
+    /// A list of undelivered notifications
+    Notifications(Vec<Notification>),
+
+    /// Protocol version, program version and features of the daemon
+    ServerInfo(ServerInfo),
+
+    /// The daemon does not know the request (error message given)
+    Unsupported(String),
+
     /// The request was successfully handled and no return value is given
     Ok,
 }
+
+/// Job output is sent unless the client asks otherwise
+fn include_output() -> bool {
+    true
+}
+
+impl Response {
+    /// Encodes the response for a peer speaking the given protocol version
+    pub fn encode(&self, version: u32) -> Value {
+        match self {
+            Response::Error(e) if version < ERROR_CODES_VERSION => json!({ "Error": e.message }),
+            response => serde_json::to_value(response).unwrap(),
+        }
+    }
+}
+
+impl Request {
+    /// Names of all requests, as they are tagged on the wire
+    pub const NAMES: &'static [&'static str] = &[
+        "Hello",
+        "SubmitJob",
+        "SubmitBatch",
+        "RemoveJob",
+        "KillJob",
+        "GetQueuedJobs",
+        "GetFinishedJobs",
+        "ListJobs",
+        "GetJob",
+        "WaitJob",
+        "HoldJob",
+        "ReleaseJob",
+        "MoveJob",
+        "SetQueueState",
+        "GetQueueState",
+        "GetNotifications",
+        "RetryNotifications",
+    ];
+
+    /// Whether the request changes the queue or any of its jobs
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Request::SubmitJob(_)
+            | Request::SubmitBatch(_)
+            | Request::RemoveJob(_)
+            | Request::KillJob(_)
+            | Request::HoldJob(_)
+            | Request::ReleaseJob(_)
+            | Request::MoveJob { .. }
+            | Request::SetQueueState(_)
+            | Request::RetryNotifications(_) => true,
+            Request::Hello
+            | Request::GetQueuedJobs
+            | Request::GetFinishedJobs
+            | Request::GetJob { .. }
+            | Request::WaitJob { .. }
+            | Request::ListJobs(_)
+            | Request::GetQueueState
+            | Request::GetNotifications => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_names() {
+        // serde lists the expected variants when it meets an unknown one
+        let e = serde_json::from_value::<Request>(json!("Unknown")).unwrap_err();
+        let message = e.to_string();
+        let expected: Vec<&str> = message.split('`').skip(3).step_by(2).collect();
+        assert_eq!(expected, Request::NAMES);
+    }
+
+    #[test]
+    fn exit_statuses_in_sysexits_range() {
+        let codes = [
+            ErrorCode::NoSuchJob,
+            ErrorCode::NoSuchNotification,
+            ErrorCode::WrongJobState,
+            ErrorCode::InvalidAppkey,
+            ErrorCode::InvalidArgument,
+            ErrorCode::MalformedRequest,
+            ErrorCode::Unauthorized,
+            ErrorCode::UnsupportedRequest,
+            ErrorCode::NoSuchResource,
+            ErrorCode::Internal,
+        ];
+        let mut statuses: Vec<i32> = codes.iter().map(|c| c.exit_status()).collect();
+        assert!(statuses.iter().all(|s| (64..=78).contains(s)));
+        statuses.sort();
+        statuses.dedup();
+        assert_eq!(statuses.len(), codes.len());
+    }
+}
@@ -0,0 +1,641 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * rest.rs
+ *
+ * Resource-oriented HTTP API for standard HTTP tooling. Each call is
+ * translated into one or more protocol requests that are evaluated just like
+ * requests of the legacy single-endpoint protocol, so both APIs behave the
+ * same. Responses are plain JSON documents with meaningful status codes:
+ *
+ * GET    /jobs?state=queued,running&tag=...    list jobs (see job_filter())
+ * POST   /jobs                                 submit a job
+ * GET    /jobs/{id}?stdout=false&stderr=false  show a job
+ * DELETE /jobs/{id}                            remove a queued or finished job
+ * POST   /jobs/{id}/signal                     terminate a running job
+ * POST   /jobs/{id}/hold                       hold a queued job
+ * POST   /jobs/{id}/release                    release a held job
+ * PUT    /jobs/{id}/position                   move a queued job within the queue
+ * GET    /queue/state                          show the queue state
+ * PUT    /queue/state                          change the queue state
+ * GET    /events?job=...&cursor=...&format=... stream job and queue events
+ *
+ * The event stream is long-lived and therefore not answered by handle() but
+ * by the caller, see event_subscription().
+ **/
+use reqwest::Url;
+use serde_json::{self, Value};
+
+use events::{StreamFormat, Subscription};
+use job_queue::{QueueState, Submission};
+use listing::JobFilter;
+use protocol::{ApiError, ErrorCode, Request, Response};
+
+/// Evaluates a protocol request and returns the HTTP status code and response
+pub type Evaluator<'a> = dyn FnMut(Request) -> (u16, Response) + 'a;
+
+/// Result of a REST call: HTTP status code and JSON body
+type Reply = (u16, String);
+
+/// Body of a job move, i.e. '{"position": 0}' to run the job next
+#[derive(Deserialize)]
+struct PositionBody {
+    position: usize,
+}
+
+/// Body of a queue state change, i.e. '{"state": "Stopping"}'
+#[derive(Deserialize)]
+struct QueueStateBody {
+    state: QueueState,
+}
+
+/// Body of a signal request, i.e. '{"signal": "SIGTERM"}'. The signal is
+/// optional as SIGTERM is the only one supported.
+#[derive(Deserialize, Default)]
+struct SignalBody {
+    signal: Option<Value>,
+}
+
+/// JSON body of a successful call
+fn ok<T: ::serde::Serialize>(status: u16, body: &T) -> Reply {
+    (status, serde_json::to_string_pretty(body).unwrap())
+}
+
+/// JSON body of a failed call, i.e. '{"error": {"code": "NoSuchJob", ...}}'
+fn error(e: ApiError) -> Reply {
+    (
+        e.status(),
+        serde_json::to_string_pretty(&json!({ "error": e })).unwrap(),
+    )
+}
+
+/// JSON body of a call with invalid parameters
+fn invalid(message: &str) -> Reply {
+    error(ApiError::new(ErrorCode::InvalidArgument, message))
+}
+
+/// JSON body of a call with an undecodable body
+fn malformed(message: &str) -> Reply {
+    error(ApiError::new(ErrorCode::MalformedRequest, message))
+}
+
+/// Turns a protocol response into a reply
+fn reply<F: FnOnce(Response) -> Reply>((_, response): (u16, Response), on_success: F) -> Reply {
+    match response {
+        Response::Error(e) => error(e),
+        Response::Unsupported(e) => error(ApiError::new(ErrorCode::UnsupportedRequest, e)),
+        response => on_success(response),
+    }
+}
+
+/// JSON body of a response the daemon should not have given
+fn unexpected(response: Response) -> Reply {
+    error(ApiError::new(
+        ErrorCode::Internal,
+        format!("Unexpected response: {:?}", response),
+    ))
+}
+
+/// Value of a boolean query parameter, i.e. 'stdout=false'
+fn flag(url: &Url, name: &str, default: bool) -> Result<bool, Reply> {
+    match url.query_pairs().find(|(k, _)| k == name) {
+        None => Ok(default),
+        Some((_, v)) => v.parse::<bool>().map_err(|_| {
+            invalid(&format!(
+                "Invalid value '{}' for '{}', expected 'true' or 'false'",
+                v, name
+            ))
+        }),
+    }
+}
+
+/// Builds a job filter from the query parameters of GET /jobs
+fn job_filter(url: &Url) -> Result<JobFilter, Reply> {
+    let invalid_value = |name: &str, value: &str, expected: &str| {
+        invalid(&format!(
+            "Invalid value '{}' for '{}', {}",
+            value, name, expected
+        ))
+    };
+    let time = |name: &str, value: &str| {
+        humantime::parse_rfc3339_weak(value)
+            .map(Some)
+            .map_err(|_| invalid_value(name, value, "expected i.e. '2021-06-01T12:00:00Z'"))
+    };
+
+    let mut filter = JobFilter::default();
+    for (name, value) in url.query_pairs() {
+        match name.as_ref() {
+            "state" => {
+                for state in value.split(',') {
+                    filter
+                        .states
+                        .push(state.parse().map_err(|e: String| invalid(&e))?);
+                }
+            }
+            "appkey" => filter.appkey = Some(value.to_string()),
+            "owner" => filter.owner = Some(value.to_string()),
+            "tag" => filter.tags.push(value.to_string()),
+            "scheduled_after" => filter.scheduled_after = time(&name, &value)?,
+            "scheduled_before" => filter.scheduled_before = time(&name, &value)?,
+            "finished_after" => filter.finished_after = time(&name, &value)?,
+            "finished_before" => filter.finished_before = time(&name, &value)?,
+            "sort" => filter.sort = value.parse().map_err(|e: String| invalid(&e))?,
+            "order" => {
+                filter.descending = match value.as_ref() {
+                    "asc" => false,
+                    "desc" => true,
+                    _ => return Err(invalid_value(&name, &value, "expected 'asc' or 'desc'")),
+                }
+            }
+            "offset" => {
+                filter.offset = value
+                    .parse()
+                    .map_err(|_| invalid_value(&name, &value, "expected a number"))?
+            }
+            "limit" => {
+                filter.limit = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid_value(&name, &value, "expected a number"))?,
+                )
+            }
+            "cursor" => filter.cursor = Some(value.to_string()),
+            "output" => filter.with_output = flag(url, "output", false)?,
+            _ => return Err(malformed(&format!("Unknown query parameter '{}'", name))),
+        }
+    }
+    Ok(filter)
+}
+
+/// GET /jobs, optionally filtered, sorted and paged
+fn list_jobs(eval: &mut Evaluator, url: &Url) -> Reply {
+    let filter = match job_filter(url) {
+        Ok(filter) => filter,
+        Err(reply) => return reply,
+    };
+
+    reply(eval(Request::ListJobs(filter)), |r| match r {
+        Response::JobPage(page) => ok(200, &page),
+        r => unexpected(r),
+    })
+}
+
+/// The subscription asked for by a GET /events call, or None for all other
+/// calls. Clients reconnecting with the standard 'Last-Event-ID' header
+/// continue after that event unless a cursor is given. Server-Sent Events are
+/// sent to clients accepting them or asking for 'format=sse', JSON Lines to
+/// all others.
+pub fn event_subscription(
+    method: &str,
+    path: &str,
+    accept: Option<&str>,
+    last_event_id: Option<&str>,
+) -> Option<Result<Subscription, Reply>> {
+    let url = Url::parse("http://localhost")
+        .and_then(|base| base.join(path))
+        .ok()?;
+    if method != "GET" || url.path().trim_end_matches('/') != "/events" {
+        return None;
+    }
+
+    let event_id = |name: &str, value: &str| {
+        value
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| invalid(&format!("Invalid value '{}' for '{}'", value, name)))
+    };
+
+    let mut subscription = Subscription {
+        jobs: Vec::new(),
+        cursor: None,
+        format: match accept {
+            Some(a) if a.contains("text/event-stream") => StreamFormat::Sse,
+            _ => StreamFormat::JsonLines,
+        },
+    };
+    if let Some(id) = last_event_id {
+        match event_id("Last-Event-ID", id) {
+            Ok(id) => subscription.cursor = Some(id),
+            Err(reply) => return Some(Err(reply)),
+        }
+    }
+
+    for (name, value) in url.query_pairs() {
+        let result = match name.as_ref() {
+            "job" => value.split(',').try_for_each(|id| {
+                subscription.jobs.push(job_id(id)?);
+                Ok(())
+            }),
+            "cursor" => event_id(&name, &value).map(|id| subscription.cursor = Some(id)),
+            "format" => match value.as_ref() {
+                "sse" => Ok(StreamFormat::Sse),
+                "jsonl" => Ok(StreamFormat::JsonLines),
+                _ => Err(invalid(&format!(
+                    "Invalid value '{}' for 'format', expected 'sse' or 'jsonl'",
+                    value
+                ))),
+            }
+            .map(|format| subscription.format = format),
+            _ => Err(malformed(&format!("Unknown query parameter '{}'", name))),
+        };
+        if let Err(reply) = result {
+            return Some(Err(reply));
+        }
+    }
+
+    Some(Ok(subscription))
+}
+
+/// Job ID from a path segment
+fn job_id(segment: &str) -> Result<u64, Reply> {
+    segment
+        .parse::<u64>()
+        .map_err(|_| invalid(&format!("Invalid job ID '{}'", segment)))
+}
+
+/// Handles a single REST call and returns the HTTP status code and JSON body
+pub fn handle(method: &str, path: &str, body: &str, eval: &mut Evaluator) -> Reply {
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(path)) {
+        Ok(url) => url,
+        Err(e) => return malformed(&format!("Invalid path '{}': {}", path, e)),
+    };
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["jobs"]) => list_jobs(eval, &url),
+
+        ("POST", ["jobs"]) => match serde_json::from_str::<Submission>(body) {
+            Ok(submission) => reply(eval(Request::SubmitJob(submission)), |r| match r {
+                Response::SubmitJob(id) => ok(201, &json!({ "id": id })),
+                r => unexpected(r),
+            }),
+            Err(e) => malformed(&format!("Invalid job submission: {}", e)),
+        },
+
+        ("POST", ["jobs", "batch"]) => match serde_json::from_str::<Vec<Submission>>(body) {
+            Ok(submissions) => reply(eval(Request::SubmitBatch(submissions)), |r| match r {
+                Response::SubmitBatch(ids) => ok(201, &json!({ "ids": ids })),
+                r => unexpected(r),
+            }),
+            Err(e) => malformed(&format!("Invalid batch submission: {}", e)),
+        },
+
+        ("GET", ["jobs", id]) => {
+            let query = job_id(id)
+                .and_then(|id| Ok((id, flag(&url, "stdout", true)?, flag(&url, "stderr", true)?)));
+            match query {
+                Ok((id, stdout, stderr)) => {
+                    reply(eval(Request::GetJob { id, stdout, stderr }), |r| match r {
+                        Response::GetJob(job) => ok(200, &job),
+                        r => unexpected(r),
+                    })
+                }
+                Err(reply) => reply,
+            }
+        }
+
+        ("DELETE", ["jobs", id]) => match job_id(id) {
+            Ok(id) => reply(eval(Request::RemoveJob(id)), |r| match r {
+                Response::GetJob(job) => ok(200, &job),
+                r => unexpected(r),
+            }),
+            Err(reply) => reply,
+        },
+
+        ("POST", ["jobs", id, "signal"]) => {
+            let signal = if body.trim().is_empty() {
+                SignalBody::default()
+            } else {
+                match serde_json::from_str::<SignalBody>(body) {
+                    Ok(s) => s,
+                    Err(e) => return malformed(&format!("Invalid signal request: {}", e)),
+                }
+            };
+            match signal.signal {
+                None => (),
+                Some(Value::Number(ref n)) if n.as_u64() == Some(15) => (),
+                Some(Value::String(ref s)) if s == "TERM" || s == "SIGTERM" => (),
+                Some(s) => {
+                    return invalid(&format!(
+                        "Unsupported signal {}, only SIGTERM can be sent",
+                        s
+                    ))
+                }
+            }
+
+            match job_id(id) {
+                Ok(id) => reply(eval(Request::KillJob(id)), |_| {
+                    ok(202, &json!({ "id": id, "signal": "SIGTERM" }))
+                }),
+                Err(reply) => reply,
+            }
+        }
+
+        ("POST", ["jobs", id, action]) if *action == "hold" || *action == "release" => {
+            match job_id(id) {
+                Ok(id) => {
+                    let request = if *action == "hold" {
+                        Request::HoldJob(id)
+                    } else {
+                        Request::ReleaseJob(id)
+                    };
+                    reply(eval(request), |r| match r {
+                        Response::GetJob(job) => ok(200, &job),
+                        r => unexpected(r),
+                    })
+                }
+                Err(reply) => reply,
+            }
+        }
+
+        ("PUT", ["jobs", id, "position"]) => {
+            let position = match serde_json::from_str::<PositionBody>(body) {
+                Ok(b) => b.position,
+                Err(e) => return malformed(&format!("Invalid position: {}", e)),
+            };
+            match job_id(id) {
+                Ok(id) => reply(eval(Request::MoveJob { id, position }), |r| match r {
+                    Response::GetJob(job) => ok(200, &job),
+                    r => unexpected(r),
+                }),
+                Err(reply) => reply,
+            }
+        }
+
+        ("GET", ["queue", "state"]) => reply(eval(Request::GetQueueState), |r| match r {
+            Response::QueueState(s) => ok(200, &json!({ "state": s })),
+            r => unexpected(r),
+        }),
+
+        ("PUT", ["queue", "state"]) => match serde_json::from_str::<QueueStateBody>(body) {
+            Ok(QueueStateBody {
+                state: QueueState::Stopped,
+            }) => invalid("The queue cannot be stopped directly, set it to 'Stopping' instead"),
+            Ok(b) => reply(eval(Request::SetQueueState(b.state)), |r| match r {
+                Response::QueueState(s) => ok(200, &json!({ "state": s })),
+                r => unexpected(r),
+            }),
+            Err(e) => malformed(&format!("Invalid queue state: {}", e)),
+        },
+
+        (_, ["jobs"])
+        | (_, ["jobs", _])
+        | (_, ["jobs", _, "signal"])
+        | (_, ["jobs", _, "hold"])
+        | (_, ["jobs", _, "release"])
+        | (_, ["jobs", _, "position"])
+        | (_, ["queue", "state"])
+        | (_, ["events"]) => error(ApiError::new(
+            ErrorCode::MethodNotAllowed,
+            format!("Method {} not allowed on {}", method, url.path()),
+        )),
+
+        _ => error(ApiError::new(
+            ErrorCode::NoSuchResource,
+            format!("No such resource: {}", url.path()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    use job_queue::{Job, JobState};
+    use listing::JobPage;
+
+    fn job(id: u64) -> Job {
+        Job {
+            id,
+            cmdline: "gwas --chr 1".to_string(),
+            scheduled: UNIX_EPOCH,
+            started: None,
+            finished: None,
+            stderr: String::new(),
+            stdout: String::new(),
+            state: JobState::Queued,
+            pid: None,
+            owner: None,
+            notify: Vec::new(),
+            tags: Vec::new(),
+            held: false,
+        }
+    }
+
+    /// Answers requests like a daemon knowing all jobs but job 99
+    fn evaluate(request: Request) -> (u16, Response) {
+        let no_such_job = || {
+            let e = ApiError::new(ErrorCode::NoSuchJob, "No such job");
+            (e.status(), Response::Error(e))
+        };
+        match request {
+            Request::SubmitJob(_) => (200, Response::SubmitJob(1)),
+            Request::SubmitBatch(s) => (200, Response::SubmitBatch((1..=s.len() as u64).collect())),
+            Request::ListJobs(_) => (
+                200,
+                Response::JobPage(JobPage {
+                    jobs: vec![job(1)],
+                    total: 1,
+                    next_cursor: None,
+                }),
+            ),
+            Request::GetJob { id: 99, .. } | Request::RemoveJob(99) => no_such_job(),
+            Request::GetJob { id, .. }
+            | Request::RemoveJob(id)
+            | Request::HoldJob(id)
+            | Request::ReleaseJob(id)
+            | Request::MoveJob { id, .. } => (200, Response::GetJob(job(id))),
+            Request::KillJob(_) => (200, Response::Ok),
+            Request::GetQueueState => (200, Response::QueueState(QueueState::Running)),
+            Request::SetQueueState(s) => (200, Response::QueueState(s)),
+            r => panic!("unexpected request {:?}", r),
+        }
+    }
+
+    /// Status code and body of a call, and the requests it was evaluated as
+    fn call(method: &str, path: &str, body: &str) -> (u16, Value, Vec<String>) {
+        let mut requests = Vec::new();
+        let (status, body) = handle(method, path, body, &mut |r| {
+            requests.push(format!("{:?}", r));
+            evaluate(r)
+        });
+        (status, serde_json::from_str(&body).unwrap(), requests)
+    }
+
+    #[test]
+    fn status_codes() {
+        let calls = [
+            ("POST", "/jobs", "\"gwas --chr 1\"", 201),
+            (
+                "POST",
+                "/jobs",
+                r#"{"cmdline": "gwas", "tags": ["a"]}"#,
+                201,
+            ),
+            ("POST", "/jobs", "{", 400),
+            ("POST", "/jobs/batch", r#"["gwas 1", "gwas 2"]"#, 201),
+            ("POST", "/jobs/batch", "\"gwas 1\"", 400),
+            ("GET", "/jobs", "", 200),
+            (
+                "GET",
+                "/jobs/?state=queued,running&order=desc&limit=5",
+                "",
+                200,
+            ),
+            ("GET", "/jobs?colour=red", "", 400),
+            ("GET", "/jobs?limit=many", "", 422),
+            ("GET", "/jobs?state=sleeping", "", 422),
+            ("GET", "/jobs?finished_after=yesterday", "", 422),
+            ("GET", "/jobs/4", "", 200),
+            ("GET", "/jobs/4?stdout=false&stderr=false", "", 200),
+            ("GET", "/jobs/4?stdout=maybe", "", 422),
+            ("GET", "/jobs/99", "", 404),
+            ("GET", "/jobs/four", "", 422),
+            ("DELETE", "/jobs/4", "", 200),
+            ("DELETE", "/jobs/99", "", 404),
+            ("POST", "/jobs/4/hold", "", 200),
+            ("POST", "/jobs/4/release", "", 200),
+            ("PUT", "/jobs/4/position", r#"{"position": 0}"#, 200),
+            ("PUT", "/jobs/4/position", "{}", 400),
+            ("GET", "/queue/state", "", 200),
+            ("PUT", "/queue/state", r#"{"state": "Stopping"}"#, 200),
+            ("PUT", "/queue/state", r#"{"state": "Stopped"}"#, 422),
+            ("PUT", "/queue/state", r#"{"state": "Sleeping"}"#, 400),
+            ("PUT", "/jobs", "", 405),
+            ("DELETE", "/jobs", "", 405),
+            ("PATCH", "/jobs/4", "", 405),
+            ("GET", "/jobs/4/signal", "", 405),
+            ("GET", "/jobs/4/hold", "", 405),
+            ("POST", "/queue/state", "", 405),
+            ("POST", "/events", "", 405),
+            ("GET", "/", "", 404),
+            ("GET", "/queue", "", 404),
+            ("GET", "/jobs/4/output", "", 404),
+        ];
+        for &(method, path, body, expected) in calls.iter() {
+            let (status, reply, _) = call(method, path, body);
+            assert_eq!(status, expected, "{} {} {}: {}", method, path, body, reply);
+            if status >= 400 {
+                assert!(reply["error"]["code"].is_string(), "{}", reply);
+            }
+        }
+    }
+
+    #[test]
+    fn replies() {
+        let (_, reply, requests) = call("POST", "/jobs", "\"gwas --chr 1\"");
+        assert_eq!(reply, json!({"id": 1}));
+        assert_eq!(requests.len(), 1);
+
+        let (_, reply, _) = call("GET", "/jobs/99", "");
+        assert_eq!(reply["error"]["code"], "NoSuchJob");
+
+        let (_, reply, _) = call("GET", "/jobs?colour=red", "");
+        assert_eq!(reply["error"]["code"], "MalformedRequest");
+
+        // the queue is not asked to stop
+        let (_, reply, requests) = call("PUT", "/queue/state", r#"{"state": "Stopped"}"#);
+        assert_eq!(reply["error"]["code"], "InvalidArgument");
+        assert!(requests.is_empty());
+
+        let (_, reply, requests) = call("PUT", "/queue/state", r#"{"state": "Stopping"}"#);
+        assert_eq!(reply, json!({"state": "Stopping"}));
+        assert_eq!(requests, vec!["SetQueueState(Stopping)"]);
+    }
+
+    #[test]
+    fn signals() {
+        let accepted = [
+            "",
+            "{}",
+            r#"{"signal": 15}"#,
+            r#"{"signal": "TERM"}"#,
+            r#"{"signal": "SIGTERM"}"#,
+        ];
+        for body in accepted.iter() {
+            let (status, reply, requests) = call("POST", "/jobs/4/signal", body);
+            assert_eq!(status, 202, "{}", body);
+            assert_eq!(reply, json!({"id": 4, "signal": "SIGTERM"}));
+            assert_eq!(requests, vec!["KillJob(4)"]);
+        }
+
+        let rejected = [
+            r#"{"signal": 9}"#,
+            r#"{"signal": "KILL"}"#,
+            r#"{"signal": "SIGKILL"}"#,
+            r#"{"signal": "sigterm"}"#,
+            r#"{"signal": "15"}"#,
+            r#"{"signal": 15.5}"#,
+        ];
+        for body in rejected.iter() {
+            let (status, reply, requests) = call("POST", "/jobs/4/signal", body);
+            assert_eq!(status, 422, "{}", body);
+            assert_eq!(reply["error"]["code"], "InvalidArgument");
+            assert!(requests.is_empty());
+        }
+
+        let (status, _, requests) = call("POST", "/jobs/4/signal", "{");
+        assert_eq!(status, 400);
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn subscriptions() {
+        assert!(event_subscription("GET", "/jobs", None, None).is_none());
+        assert!(event_subscription("POST", "/events", None, None).is_none());
+
+        let s = event_subscription("GET", "/events?job=1,2&cursor=7", None, Some("5"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(s.jobs, vec![1, 2]);
+        assert_eq!(s.cursor, Some(7));
+        assert_eq!(s.format, StreamFormat::JsonLines);
+
+        let s = event_subscription("GET", "/events", Some("text/event-stream"), Some("5"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(s.cursor, Some(5));
+        assert_eq!(s.format, StreamFormat::Sse);
+
+        let s = event_subscription("GET", "/events?format=sse", None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(s.format, StreamFormat::Sse);
+
+        let status = |path: &str, last_event_id: Option<&str>| {
+            event_subscription("GET", path, None, last_event_id)
+                .unwrap()
+                .unwrap_err()
+                .0
+        };
+        assert_eq!(status("/events?colour=red", None), 400);
+        assert_eq!(status("/events?format=xml", None), 422);
+        assert_eq!(status("/events?job=one", None), 422);
+        assert_eq!(status("/events", Some("latest")), 422);
+    }
+}
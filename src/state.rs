@@ -1,16 +1,16 @@
-/**
+/*
  * Copyright (c) 2021 Jan Christian Kaessens
- * 
+ *
  * Permission is hereby granted, free of charge, to any person obtaining a copy
  * of this software and associated documentation files (the "Software"), to deal
  * in the Software without restriction, including without limitation the rights
  * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
  * copies of the Software, and to permit persons to whom the Software is
  * furnished to do so, subject to the following conditions:
- * 
+ *
  * The above copyright notice and this permission notice shall be included in all
  * copies or substantial portions of the Software.
- * 
+ *
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
  * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
  * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -25,21 +25,29 @@ use std::io::Result;
 use std::path::PathBuf;
 
 use job_queue::*;
+use outbox::Outbox;
 
 /// Job IDs are incremented before they are assigned to jobs. Setting the
 /// default last job id to zero makes the first submitted job to get
 /// job id 1 assigned.
 const DEFAULT_STATE_LAST_ID: u64 = 0;
 
+/// File extension of the notification outbox, stored next to the state file
+const OUTBOX_EXTENSION: &str = "outbox";
+
 /// Configuration of the program state object
 pub struct State {
     state_file: PathBuf,
+    outbox_file: PathBuf,
 }
 
 impl State {
     /// Configure the program state object
     fn load(p: PathBuf) -> State {
-        State { state_file: p }
+        State {
+            outbox_file: p.with_extension(OUTBOX_EXTENSION),
+            state_file: p,
+        }
     }
 
     /// Configures the program state or uses defaults if the state file is not available
@@ -49,7 +57,7 @@ impl State {
                 "Cannot open state file {}. Using defaults.",
                 p.to_str().unwrap()
             );
-            State { state_file: p }
+            State::load(p)
         } else {
             debug!("Loading program state from {}", p.to_str().unwrap());
             State::load(p)
@@ -86,3 +94,38 @@ impl State {
         }
     }
 }
+
+impl State {
+    /// Loads the notification outbox stored next to the state file
+    pub fn load_outbox(&self) -> Outbox {
+        let s = fs::read_to_string(&self.outbox_file).unwrap_or("".to_owned());
+        if s.is_empty() {
+            return Outbox::default();
+        }
+        serde_json::from_str(&s).unwrap_or_else(|_| {
+            warn!("Could not parse notification outbox, starting with an empty outbox");
+            Outbox::default()
+        })
+    }
+
+    /// Stores the given notification outbox next to the state file
+    pub fn save_outbox(&self, outbox: &Outbox) -> Result<()> {
+        let f = File::create(&self.outbox_file);
+        if let Err(e) = f {
+            error!(
+                "Cannot create or open outbox file {}: {:?}",
+                self.outbox_file.to_str().unwrap(),
+                e
+            );
+            Err(e)
+        } else {
+            let mut f = f.unwrap();
+            serde_json::to_writer_pretty(&mut f, outbox)?;
+            debug!(
+                "Outbox file {} updated.",
+                self.outbox_file.to_str().unwrap()
+            );
+            Ok(())
+        }
+    }
+}
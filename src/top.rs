@@ -0,0 +1,770 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * top.rs
+ *
+ * Full-screen dashboard of the queue: the queue state, the running job with
+ * the tail of its live output, the queued jobs in scheduling order and the
+ * most recently finished jobs. Jobs are selected with the cursor keys and
+ * controlled with single keys. Every action is an ordinary protocol request,
+ * so the dashboard can do nothing the other client commands cannot.
+ **/
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use nix::libc;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd;
+
+use qmanager::client::QmanagerClient;
+use qmanager::clierror::{ClientError, Result};
+use qmanager::events::{Event, EventKind, OutputStream};
+use qmanager::job_queue::{Job, JobState, QueueState};
+use qmanager::listing::{JobFilter, SortKey, StateFilter};
+
+use output::{format_run_time, local_time, state_name};
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+
+/// Time between two polls of the queue
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest time to wait for a key press before looking for events
+const INPUT_TIMEOUT_MS: i32 = 100;
+
+/// Time before the event stream is opened again after it has ended
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Number of finished jobs shown at most
+const FINISHED_JOBS: usize = 10;
+
+/// Number of output lines of the running job shown at most
+const OUTPUT_LINES: usize = 10;
+
+/// Key bindings, shown at the bottom of the screen
+const HELP: &str =
+    "j/k select  x kill  d remove  h hold/release  +/- move  s start/stop  r refresh  q quit";
+
+/// Puts the terminal into raw mode on an alternate screen and restores it
+/// when dropped, also if the dashboard fails
+struct Terminal {
+    original: Termios,
+}
+
+impl Terminal {
+    fn open() -> Result<Terminal> {
+        let original = termios::tcgetattr(STDIN).map_err(|_| {
+            ClientError::Config("qmanager top needs an interactive terminal".to_string())
+        })?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(STDIN, SetArg::TCSANOW, &raw)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        // alternate screen, hidden cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Terminal { original })
+    }
+
+    /// Number of columns and rows of the terminal
+    fn size(&self) -> (usize, usize) {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::ioctl(STDOUT, libc::TIOCGWINSZ, &mut size) } == 0;
+        if ok && size.ws_col > 0 && size.ws_row > 0 {
+            (size.ws_col as usize, size.ws_row as usize)
+        } else {
+            (80, 24)
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = termios::tcsetattr(STDIN, SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// A key press the dashboard reacts to
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Key {
+    Up,
+    Down,
+    Char(char),
+}
+
+/// Decodes the bytes read from the terminal into key presses
+fn decode_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match &bytes[i..] {
+            [0x1b, b'[', b'A', ..] | [0x1b, b'O', b'A', ..] => {
+                keys.push(Key::Up);
+                i += 3;
+            }
+            [0x1b, b'[', b'B', ..] | [0x1b, b'O', b'B', ..] => {
+                keys.push(Key::Down);
+                i += 3;
+            }
+            // Ctrl-C, as raw mode does not raise SIGINT
+            [3, ..] => {
+                keys.push(Key::Char('q'));
+                i += 1;
+            }
+            [b, ..] => {
+                if b.is_ascii_graphic() {
+                    keys.push(Key::Char(*b as char));
+                }
+                i += 1;
+            }
+            [] => break,
+        }
+    }
+    keys
+}
+
+/// Waits up to the timeout for key presses
+fn read_keys(timeout_ms: i32) -> Vec<Key> {
+    let mut fds = [PollFd::new(STDIN, EventFlags::POLLIN)];
+    match poll(&mut fds, timeout_ms) {
+        Ok(n) if n > 0 => {
+            let mut buf = [0u8; 64];
+            match unistd::read(STDIN, &mut buf) {
+                Ok(len) => decode_keys(&buf[..len]),
+                Err(_) => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Follows the daemon's events in the background. The stream is opened
+/// again at the last cursor if it ends; the first error ends following.
+fn follow_events(client: QmanagerClient) -> Receiver<Result<Event>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut cursor = None;
+        loop {
+            let stream = match client.stream_events(&[], cursor) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            for event in stream {
+                let failed = event.is_err();
+                if let Ok(ref e) = event {
+                    cursor = Some(e.id);
+                }
+                if tx.send(event).is_err() || failed {
+                    return;
+                }
+            }
+            thread::sleep(RECONNECT_DELAY);
+        }
+    });
+    rx
+}
+
+/// The last lines a job has written to stdout and stderr
+#[derive(Default)]
+struct OutputTail {
+    /// Job the output belongs to
+    job: Option<u64>,
+
+    /// Complete lines, oldest first
+    lines: VecDeque<(OutputStream, String)>,
+
+    /// Unterminated last line of each stream
+    partial: [String; 2],
+}
+
+impl OutputTail {
+    /// Starts over for the given job
+    fn reset(&mut self, job: u64) {
+        *self = OutputTail {
+            job: Some(job),
+            ..OutputTail::default()
+        };
+    }
+
+    /// Appends output of the given job
+    fn append(&mut self, job: u64, stream: OutputStream, data: &str) {
+        if self.job != Some(job) {
+            self.reset(job);
+        }
+        let partial = match stream {
+            OutputStream::Stdout => &mut self.partial[0],
+            OutputStream::Stderr => &mut self.partial[1],
+        };
+        partial.push_str(data);
+        while let Some(end) = partial.find('\n') {
+            let line: String = partial.drain(..=end).collect();
+            self.lines
+                .push_back((stream, line.trim_end_matches(['\n', '\r']).to_string()));
+            if self.lines.len() > OUTPUT_LINES {
+                self.lines.pop_front();
+            }
+        }
+    }
+
+    /// The last lines, including unterminated ones
+    fn last_lines(&self, count: usize) -> Vec<(OutputStream, &str)> {
+        let mut lines: Vec<(OutputStream, &str)> =
+            self.lines.iter().map(|(s, l)| (*s, l.as_str())).collect();
+        for (stream, partial) in [OutputStream::Stdout, OutputStream::Stderr]
+            .iter()
+            .zip(&self.partial)
+        {
+            if !partial.is_empty() {
+                lines.push((*stream, partial));
+            }
+        }
+        let skip = lines.len().saturating_sub(count);
+        lines.split_off(skip)
+    }
+}
+
+/// State of the dashboard
+struct Dashboard<'a> {
+    client: &'a QmanagerClient,
+
+    queue_state: Option<QueueState>,
+
+    /// Queued jobs in scheduling order, the running job first
+    queued: Vec<Job>,
+
+    /// Finished jobs, most recent first
+    finished: Vec<Job>,
+
+    /// ID of the selected job
+    selected: Option<u64>,
+
+    tail: OutputTail,
+
+    /// Why live output cannot be shown, if it cannot
+    tail_error: Option<String>,
+
+    /// Result of the last action or refresh
+    message: String,
+
+    last_refresh: Option<Instant>,
+}
+
+impl<'a> Dashboard<'a> {
+    fn new(client: &'a QmanagerClient) -> Dashboard<'a> {
+        Dashboard {
+            client,
+            queue_state: None,
+            queued: Vec::new(),
+            finished: Vec::new(),
+            selected: None,
+            tail: OutputTail::default(),
+            tail_error: None,
+            message: String::new(),
+            last_refresh: None,
+        }
+    }
+
+    /// Fetches the queue state and jobs from the daemon
+    fn refresh(&mut self) -> Result<()> {
+        self.last_refresh = Some(Instant::now());
+        let queue_state = self.client.queue_state()?;
+        let mut queued = self.client.queued_jobs()?;
+        for job in &mut queued {
+            job.stdout.clear();
+            job.stderr.clear();
+        }
+        let finished = self
+            .client
+            .list(JobFilter {
+                states: vec![StateFilter::Finished],
+                sort: SortKey::Finished,
+                descending: true,
+                limit: Some(FINISHED_JOBS),
+                ..JobFilter::default()
+            })?
+            .jobs;
+
+        self.queue_state = Some(queue_state);
+        self.queued = queued;
+        self.finished = finished;
+
+        let ids = self.job_ids();
+        if !self.selected.is_some_and(|id| ids.contains(&id)) {
+            self.selected = ids.first().cloned();
+        }
+        Ok(())
+    }
+
+    /// Refreshes and reports failures in the status line
+    fn refresh_or_report(&mut self) {
+        if let Err(e) = self.refresh() {
+            self.message = e.to_string();
+        }
+    }
+
+    /// IDs of all jobs shown, in the order shown
+    fn job_ids(&self) -> Vec<u64> {
+        self.queued
+            .iter()
+            .chain(self.finished.iter())
+            .map(|j| j.id)
+            .collect()
+    }
+
+    /// The selected job
+    fn selected_job(&self) -> Option<&Job> {
+        let id = self.selected?;
+        self.queued
+            .iter()
+            .chain(self.finished.iter())
+            .find(|j| j.id == id)
+    }
+
+    /// The jobs waiting to be run, in scheduling order
+    fn waiting(&self) -> impl Iterator<Item = &Job> {
+        self.queued.iter().filter(|j| j.state != JobState::Running)
+    }
+
+    /// Moves the selection by the given number of jobs
+    fn select(&mut self, offset: isize) {
+        let ids = self.job_ids();
+        if ids.is_empty() {
+            return;
+        }
+        let current = self
+            .selected
+            .and_then(|id| ids.iter().position(|i| *i == id))
+            .unwrap_or(0) as isize;
+        let next = (current + offset).max(0).min(ids.len() as isize - 1);
+        self.selected = Some(ids[next as usize]);
+    }
+
+    /// Takes the events received so far into account. Returns whether
+    /// anything has changed.
+    fn process_events(&mut self, events: &Receiver<Result<Event>>) -> bool {
+        let mut changed = false;
+        loop {
+            match events.try_recv() {
+                Ok(Ok(event)) => {
+                    changed = true;
+                    match event.event {
+                        EventKind::Output { id, stream, data } => {
+                            self.tail.append(id, stream, &data)
+                        }
+                        EventKind::JobStarted { job } => self.tail.reset(job.id),
+                        EventKind::JobSubmitted { .. }
+                        | EventKind::JobFinished { .. }
+                        | EventKind::JobRemoved { .. }
+                        | EventKind::JobChanged { .. }
+                        | EventKind::QueueState { .. }
+                        | EventKind::EventsLost { .. } => self.refresh_or_report(),
+                    }
+                }
+                Ok(Err(e)) => {
+                    self.tail_error = Some(e.to_string());
+                    return true;
+                }
+                Err(TryRecvError::Empty) => return changed,
+                Err(TryRecvError::Disconnected) => return changed,
+            }
+        }
+    }
+
+    /// Performs the action bound to the key. Returns false if the
+    /// dashboard is to be closed.
+    fn handle_key(&mut self, key: Key) -> bool {
+        let result = match key {
+            Key::Char('q') => return false,
+            Key::Up | Key::Char('k') => {
+                self.select(-1);
+                return true;
+            }
+            Key::Down | Key::Char('j') => {
+                self.select(1);
+                return true;
+            }
+            Key::Char('r') => Ok(None),
+            Key::Char('s') => self.toggle_queue(),
+            Key::Char(c) => match self.selected_job().cloned() {
+                Some(job) => self.job_action(c, &job),
+                None => Ok(None),
+            },
+        };
+
+        match result {
+            Ok(Some(message)) => self.message = message,
+            Ok(None) => (),
+            Err(e) => self.message = e.to_string(),
+        }
+        self.refresh_or_report();
+        true
+    }
+
+    /// Stops a running queue, starts it otherwise
+    fn toggle_queue(&self) -> Result<Option<String>> {
+        let new_state = match self.client.queue_state()? {
+            QueueState::Running => QueueState::Stopping,
+            QueueState::Stopping | QueueState::Stopped => QueueState::Running,
+        };
+        let state = self.client.set_state(new_state)?;
+        Ok(Some(format!("Queue is {:?}", state)))
+    }
+
+    /// Performs the action bound to the key on the job
+    fn job_action(&self, key: char, job: &Job) -> Result<Option<String>> {
+        let position = self.waiting().position(|j| j.id == job.id);
+        let message = match key {
+            'x' => {
+                self.client.kill(job.id)?;
+                format!("Asked job {} to terminate", job.id)
+            }
+            'd' => {
+                self.client.remove(job.id)?;
+                format!("Removed job {}", job.id)
+            }
+            'h' if job.held => {
+                self.client.release(job.id)?;
+                format!("Released job {}", job.id)
+            }
+            'h' => {
+                self.client.hold(job.id)?;
+                format!("Holding job {}", job.id)
+            }
+            '+' | '-' => {
+                let position = match position {
+                    Some(position) => position,
+                    None => return Ok(Some(format!("Job {} is not queued", job.id))),
+                };
+                let position = if key == '+' {
+                    position.saturating_sub(1)
+                } else {
+                    position + 1
+                };
+                self.client.move_job(job.id, position)?;
+                format!("Moved job {} to position {}", job.id, position + 1)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(message))
+    }
+
+    /// Lays out the screen for the given terminal size
+    fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        let waiting: Vec<&Job> = self.waiting().collect();
+        let running = self.queued.iter().find(|j| j.state == JobState::Running);
+
+        let state = self
+            .queue_state
+            .map(|s| format!("{:?}", s))
+            .unwrap_or_else(|| "unknown".to_string());
+        let held = waiting.iter().filter(|j| j.held).count();
+        lines.push(bold(&format!(
+            "qmanager top - queue {} - {} waiting ({} held) - {}",
+            state,
+            waiting.len(),
+            held,
+            Local::now().format("%H:%M:%S")
+        )));
+        lines.push(String::new());
+
+        // Output lines share the screen with the job lists. Eight lines are
+        // used by headings, the running job, the status line and the help.
+        let free = height.saturating_sub(8);
+        let output_lines = (free / 3).min(OUTPUT_LINES);
+        let finished_lines = (free / 4).clamp(1, FINISHED_JOBS);
+        let waiting_lines = free.saturating_sub(output_lines + finished_lines).max(1);
+
+        lines.push(bold("RUNNING"));
+        match running {
+            Some(job) => {
+                lines.push(self.job_line(
+                    job,
+                    &format!(
+                        "{:>6}  {}  pid {}  running for {}",
+                        job.id,
+                        job.cmdline,
+                        job.pid.map_or("-".to_string(), |p| p.to_string()),
+                        format_run_time(job)
+                    ),
+                ));
+                let tail: Vec<String> = match self.tail_error {
+                    Some(ref e) => vec![format!("        live output unavailable: {}", e)],
+                    None if self.tail.job == Some(job.id) => self
+                        .tail
+                        .last_lines(output_lines)
+                        .into_iter()
+                        .map(|(stream, line)| match stream {
+                            OutputStream::Stdout => format!("        {}", sanitize(line)),
+                            OutputStream::Stderr => format!("      ! {}", sanitize(line)),
+                        })
+                        .collect(),
+                    None => Vec::new(),
+                };
+                lines.extend(tail.into_iter().take(output_lines.max(1)));
+            }
+            None => lines.push("     -".to_string()),
+        }
+
+        lines.push(bold(&format!(
+            "{:>6}  {:<8}  {:<10}  {:<19}  CMDLINE",
+            "QUEUED", "STATE", "OWNER", "SUBMITTED"
+        )));
+        if waiting.is_empty() {
+            lines.push("     -".to_string());
+        }
+        let selected = self
+            .selected
+            .and_then(|id| waiting.iter().position(|j| j.id == id));
+        let first = match selected {
+            Some(index) if index >= waiting_lines => index + 1 - waiting_lines,
+            _ => 0,
+        };
+        for job in waiting.iter().skip(first).take(waiting_lines) {
+            lines.push(self.job_line(
+                job,
+                &format!(
+                    "{:>6}  {:<8}  {:<10}  {:<19}  {}",
+                    job.id,
+                    if job.held { "held" } else { "queued" },
+                    job.owner.as_deref().unwrap_or("-"),
+                    local_time(Some(job.scheduled)),
+                    job.cmdline
+                ),
+            ));
+        }
+
+        lines.push(bold(&format!(
+            "{:>6}  {:<10}  {:<9}  {:<19}  {:<10}  CMDLINE",
+            "DONE", "STATE", "EXIT", "FINISHED", "DURATION"
+        )));
+        if self.finished.is_empty() {
+            lines.push("     -".to_string());
+        }
+        for job in self.finished.iter().take(finished_lines) {
+            let exit = match job.state {
+                JobState::Terminated(code) => code.to_string(),
+                JobState::Killed(signal) => format!("signal {}", signal),
+                _ => "-".to_string(),
+            };
+            lines.push(self.job_line(
+                job,
+                &format!(
+                    "{:>6}  {:<10}  {:<9}  {:<19}  {:<10}  {}",
+                    job.id,
+                    state_name(&job.state),
+                    exit,
+                    local_time(job.finished),
+                    format_run_time(job),
+                    job.cmdline
+                ),
+            ));
+        }
+
+        // status line and help at the bottom of the screen
+        let body = height.saturating_sub(2);
+        lines.truncate(body);
+        while lines.len() < body {
+            lines.push(String::new());
+        }
+        lines.push(sanitize(&self.message));
+        lines.push(HELP.to_string());
+
+        lines.into_iter().map(|line| fit(&line, width)).collect()
+    }
+
+    /// A line describing a job, highlighted if the job is selected
+    fn job_line(&self, job: &Job, text: &str) -> String {
+        if self.selected == Some(job.id) {
+            format!("\x1b[7m{}", sanitize(text))
+        } else {
+            sanitize(text)
+        }
+    }
+
+    /// Draws the whole screen
+    fn draw(&self, terminal: &Terminal) -> io::Result<()> {
+        let (width, height) = terminal.size();
+        let mut screen = String::from("\x1b[H");
+        let lines = self.render(width, height);
+        let count = lines.len();
+        for (i, line) in lines.into_iter().enumerate() {
+            screen.push_str(&line);
+            screen.push_str("\x1b[0m\x1b[K");
+            if i + 1 < count {
+                screen.push_str("\r\n");
+            }
+        }
+        let mut stdout = io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+/// Bold text
+fn bold(text: &str) -> String {
+    format!("\x1b[1m{}", text)
+}
+
+/// Replaces tabs and drops other control characters, which would garble
+/// the screen
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+/// Cuts a line to the given number of columns, not counting the escape
+/// sequences at its start
+fn fit(line: &str, width: usize) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    while rest.starts_with('\x1b') {
+        let end = rest.find('m').map_or(rest.len(), |i| i + 1);
+        result.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    result.extend(rest.chars().take(width));
+    result
+}
+
+/// Shows the dashboard until the user quits
+pub fn run(client: &QmanagerClient) -> Result<()> {
+    let mut dashboard = Dashboard::new(client);
+
+    // fail before taking over the screen if the daemon cannot be reached
+    dashboard.refresh()?;
+    let events = follow_events(client.clone());
+
+    let terminal = Terminal::open()?;
+    print!("\x1b[2J");
+    dashboard.draw(&terminal)?;
+
+    loop {
+        let mut redraw = dashboard.process_events(&events);
+
+        if dashboard
+            .last_refresh
+            .is_none_or(|t| t.elapsed() >= REFRESH_INTERVAL)
+        {
+            dashboard.refresh_or_report();
+            redraw = true;
+        }
+
+        for key in read_keys(INPUT_TIMEOUT_MS) {
+            if !dashboard.handle_key(key) {
+                return Ok(());
+            }
+            redraw = true;
+        }
+
+        if redraw {
+            dashboard.draw(&terminal)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders the tail's lines as "o:" or "e:" followed by the text
+    fn lines(tail: &OutputTail, count: usize) -> Vec<String> {
+        tail.last_lines(count)
+            .into_iter()
+            .map(|(stream, line)| match stream {
+                OutputStream::Stdout => format!("o:{}", line),
+                OutputStream::Stderr => format!("e:{}", line),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_keys() {
+        assert_eq!(
+            decode_keys(b"\x1b[Aj\x1bOB\x03x\n\x1b[Bk\x1bOA"),
+            vec![
+                Key::Up,
+                Key::Char('j'),
+                Key::Down,
+                Key::Char('q'),
+                Key::Char('x'),
+                Key::Down,
+                Key::Char('k'),
+                Key::Up,
+            ]
+        );
+        // an incomplete escape sequence is dropped byte by byte
+        assert_eq!(decode_keys(b"\x1b["), vec![Key::Char('[')]);
+        assert!(decode_keys(b"").is_empty());
+    }
+
+    #[test]
+    fn output_tail_joins_chunks() {
+        let mut tail = OutputTail::default();
+        tail.append(1, OutputStream::Stdout, "one\r\ntw");
+        tail.append(1, OutputStream::Stderr, "warn");
+        tail.append(1, OutputStream::Stdout, "o\nthr");
+        assert_eq!(lines(&tail, 10), vec!["o:one", "o:two", "o:thr", "e:warn"]);
+
+        tail.append(1, OutputStream::Stderr, "ing\n");
+        assert_eq!(lines(&tail, 2), vec!["e:warning", "o:thr"]);
+    }
+
+    #[test]
+    fn output_tail_is_limited() {
+        let mut tail = OutputTail::default();
+        for i in 0..OUTPUT_LINES + 5 {
+            tail.append(1, OutputStream::Stdout, &format!("{}\n", i));
+        }
+        assert_eq!(tail.lines.len(), OUTPUT_LINES);
+        assert_eq!(lines(&tail, 1), vec![format!("o:{}", OUTPUT_LINES + 4)]);
+        assert_eq!(lines(&tail, 100)[0], "o:5");
+    }
+
+    #[test]
+    fn output_tail_starts_over_for_new_job() {
+        let mut tail = OutputTail::default();
+        tail.append(1, OutputStream::Stdout, "first\npartial");
+        tail.append(2, OutputStream::Stderr, "second\n");
+        assert_eq!(tail.job, Some(2));
+        assert_eq!(lines(&tail, 10), vec!["e:second"]);
+    }
+}
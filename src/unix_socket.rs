@@ -0,0 +1,501 @@
+/*
+ * Copyright (c) 2021 Jan Christian Kaessens
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ **/
+
+/**
+ * unix_socket.rs
+ *
+ * Local transport for clients running on the same host as the daemon. The
+ * same JSON requests are exchanged as over TCP, wrapped in a minimal
+ * HTTP/1.1 framing so that both transports share a common request handler.
+ * Callers are identified by the kernel-provided peer credentials
+ * (SO_PEERCRED) of the connecting process.
+ **/
+use std::fs;
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use nix::sys::socket::{getsockopt, sockopt};
+use nix::sys::stat::{self, Mode};
+use nix::unistd::{chown, Gid};
+
+/// Default file mode of the daemon's listening socket
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// Upper limit for request and response header sections
+const MAX_HEADER_LINES: usize = 100;
+
+/// Upper limit for request bodies, larger requests are rejected
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// A minimal HTTP message as exchanged over the Unix socket
+#[derive(Debug)]
+pub struct Message {
+    /// Request or status line
+    pub start_line: String,
+
+    /// Header fields in order of appearance
+    pub headers: Vec<(String, String)>,
+
+    /// Message body
+    pub body: String,
+}
+
+impl Message {
+    /// Request method (requests only)
+    pub fn method(&self) -> &str {
+        self.start_line.split_whitespace().next().unwrap_or("")
+    }
+
+    /// Request path (requests only)
+    pub fn path(&self) -> &str {
+        self.start_line.split_whitespace().nth(1).unwrap_or("/")
+    }
+
+    /// Response status code (responses only)
+    pub fn status(&self) -> Option<u16> {
+        self.start_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+    }
+
+    /// Value of the first header field with the given name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The local user account a peer process is running as
+#[derive(Debug, Clone)]
+pub struct PeerUser {
+    /// Numerical user ID
+    pub uid: u32,
+
+    /// Numerical process ID of the peer
+    pub pid: i32,
+
+    /// User name, if the uid can be resolved
+    pub name: Option<String>,
+}
+
+/// Creates the listening socket at the given path. A stale socket file
+/// left over from a previous run is removed first, any other file at the
+/// path is left alone. The socket file is created with the given mode and,
+/// if specified, assigned group ownership.
+pub fn bind(path: &Path, mode: u32, group: Option<&str>) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {
+            debug!("Removing stale socket file {:?}", path);
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a socket", path),
+            ))
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // the socket file is created according to the umask, so that it is
+    // never accessible to more users than the given mode allows
+    let umask = stat::umask(Mode::from_bits_truncate(!mode & 0o777));
+    let listener = UnixListener::bind(path);
+    stat::umask(umask);
+    let listener = listener?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+
+    if let Some(group) = group {
+        let gid = users::get_group_by_name(group)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("No such group '{}' for socket {:?}", group, path),
+                )
+            })?
+            .gid();
+        chown(path, None, Some(Gid::from_raw(gid))).map_err(|e| Error::other(e.to_string()))?;
+    }
+
+    Ok(listener)
+}
+
+/// Determines the user a connected peer process is running as
+pub fn peer_user(stream: &UnixStream) -> Result<PeerUser> {
+    let creds = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)
+        .map_err(|e| Error::other(e.to_string()))?;
+
+    let name = users::get_user_by_uid(creds.uid()).map(|u| u.name().to_string_lossy().to_string());
+
+    Ok(PeerUser {
+        uid: creds.uid(),
+        pid: creds.pid(),
+        name,
+    })
+}
+
+/// Reads a header section and a body of `Content-Length` bytes. If no length
+/// is given, requests have no body, while the body of responses extends until
+/// the peer closes the connection. Requests with a body larger than
+/// MAX_BODY_SIZE fail with FileTooLarge.
+fn read_message<R: BufRead>(reader: &mut R, is_request: bool) -> Result<Message> {
+    let mut message = read_head(reader)?;
+
+    let length = message
+        .header("Content-Length")
+        .map(|v| {
+            v.parse::<usize>()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))
+        })
+        .transpose()?;
+
+    let mut body = Vec::new();
+    match length {
+        Some(n) if is_request && n > MAX_BODY_SIZE => {
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                format!(
+                    "Request body of {} bytes exceeds the limit of {} bytes",
+                    n, MAX_BODY_SIZE
+                ),
+            ));
+        }
+        Some(n) => {
+            reader.by_ref().take(n as u64).read_to_end(&mut body)?;
+            if body.len() < n {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+        }
+        None if is_request => (),
+        None => {
+            reader.read_to_end(&mut body)?;
+        }
+    }
+
+    message.body =
+        String::from_utf8(body).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+    Ok(message)
+}
+
+/// Reads the start line and header fields of a message, leaving the body
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Message> {
+    let mut start_line = String::new();
+    reader.read_line(&mut start_line)?;
+    if start_line.is_empty() {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+
+    let mut message = Message {
+        start_line: start_line.trim_end().to_string(),
+        headers: Vec::new(),
+        body: String::new(),
+    };
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if message.headers.len() >= MAX_HEADER_LINES {
+            return Err(Error::new(ErrorKind::InvalidData, "Too many header lines"));
+        }
+        if let Some(pos) = line.find(':') {
+            message.headers.push((
+                line[..pos].trim().to_string(),
+                line[pos + 1..].trim().to_string(),
+            ));
+        }
+    }
+
+    Ok(message)
+}
+
+/// Reads a single request from a client connection
+pub fn read_request(stream: &UnixStream) -> Result<Message> {
+    read_message(&mut BufReader::new(stream), true)
+}
+
+/// Writes a complete response to a client connection
+pub fn write_response(
+    mut stream: &UnixStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason_phrase(status),
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Connects to the daemon's socket, sends a single request and returns the
+/// daemon's response
+pub fn transact(path: &Path, method: &str, request_path: &str, body: &str) -> Result<Message> {
+    let mut stream = UnixStream::connect(path)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        request_path,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    let response = read_message(&mut BufReader::new(&stream), false)?;
+    if response.status().is_none() {
+        return Err(Error::new(ErrorKind::InvalidData, "Malformed status line"));
+    }
+
+    Ok(response)
+}
+
+/// Sends a GET request to the daemon's socket and returns the response's
+/// header once it arrives, together with a reader for its body. Used for
+/// long-lived responses such as event streams.
+pub fn open_stream(path: &Path, request_path: &str) -> Result<(Message, BufReader<ChunkedReader>)> {
+    let mut stream = UnixStream::connect(path)?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        request_path
+    )?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let head = read_head(&mut reader)?;
+    if head.status().is_none() {
+        return Err(Error::new(ErrorKind::InvalidData, "Malformed status line"));
+    }
+
+    let chunked = head
+        .header("Transfer-Encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    Ok((head, BufReader::new(ChunkedReader::new(reader, chunked))))
+}
+
+/// Reader for the body of a response, decoding chunked transfer encoding if
+/// the response uses it
+pub struct ChunkedReader<R = BufReader<UnixStream>> {
+    reader: R,
+
+    /// Whether the body is chunked, read to the end of the stream otherwise
+    chunked: bool,
+
+    /// Bytes left in the current chunk
+    remaining: usize,
+
+    /// Whether the last chunk has been read
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    /// Reads the body following a header section from the given reader
+    fn new(reader: R, chunked: bool) -> Self {
+        ChunkedReader {
+            reader,
+            chunked,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        if !self.chunked {
+            return self.reader.read(buf);
+        }
+
+        if self.remaining == 0 {
+            // a chunk's data is followed by CRLF before the next size line
+            let mut line = String::new();
+            while line.trim().is_empty() {
+                line.clear();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(0);
+                }
+            }
+            let size = line.trim().split(';').next().unwrap_or("");
+            self.remaining = usize::from_str_radix(size, 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid chunk size"))?;
+            if self.remaining == 0 {
+                // the last chunk, nothing but trailers may follow
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let n = buf.len().min(self.remaining);
+        let n = self.reader.read(&mut buf[..n])?;
+        if n == 0 {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+/// Textual representation of the status codes used by the daemon
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(data: &str, is_request: bool) -> Result<Message> {
+        read_message(&mut Cursor::new(data.as_bytes()), is_request)
+    }
+
+    #[test]
+    fn reads_body_of_given_length() {
+        let message = read(
+            "POST /jobs HTTP/1.1\r\nContent-Length: 7\r\nX-Empty:\r\n\r\n{\"a\":1}trailing",
+            true,
+        )
+        .unwrap();
+        assert_eq!(message.method(), "POST");
+        assert_eq!(message.path(), "/jobs");
+        assert_eq!(message.header("content-length"), Some("7"));
+        assert_eq!(message.header("X-Empty"), Some(""));
+        assert_eq!(message.body, "{\"a\":1}");
+    }
+
+    #[test]
+    fn body_without_length() {
+        let request = read("GET /jobs HTTP/1.1\r\n\r\nignored", true).unwrap();
+        assert_eq!(request.body, "");
+
+        let response = read("HTTP/1.1 200 OK\r\n\r\nuntil the end", false).unwrap();
+        assert_eq!(response.status(), Some(200));
+        assert_eq!(response.body, "until the end");
+    }
+
+    #[test]
+    fn rejects_large_requests() {
+        let head = format!(
+            "POST /jobs HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let e = read(&head, true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FileTooLarge);
+
+        let e = read(
+            "POST /jobs HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        let e = read("POST /jobs HTTP/1.1\r\nContent-Length: ten\r\n\r\n", true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn limits_header_lines() {
+        let mut data = "GET / HTTP/1.1\r\n".to_string();
+        for i in 0..MAX_HEADER_LINES {
+            data.push_str(&format!("X-{}: {}\r\n", i, i));
+        }
+        assert!(read(&format!("{}\r\n", data), true).is_ok());
+
+        data.push_str("X-Last: 1\r\n\r\n");
+        let e = read(&data, true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let e = read("", true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    fn decode(data: &str, chunked: bool) -> Result<String> {
+        let mut body = String::new();
+        ChunkedReader::new(Cursor::new(data.as_bytes()), chunked).read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn decodes_chunks() {
+        let data = "5\r\nhello\r\n7;name=value\r\n, world\r\n\r\n0\r\nTrailer: x\r\n\r\nafter";
+        assert_eq!(decode(data, true).unwrap(), "hello, world");
+
+        // a long chunk is read in several parts
+        let long = "x".repeat(10000);
+        let data = format!("{:x}\r\n{}\r\n0\r\n\r\n", long.len(), long);
+        assert_eq!(decode(&data, true).unwrap(), long);
+
+        // the stream may end between chunks
+        assert_eq!(decode("3\r\nabc\r\n", true).unwrap(), "abc");
+    }
+
+    #[test]
+    fn rejects_broken_chunks() {
+        let e = decode("zz\r\nabc\r\n", true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let e = decode("a\r\nabc", true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn passes_unchunked_body() {
+        assert_eq!(
+            decode("5\r\nhello\r\n0\r\n", false).unwrap(),
+            "5\r\nhello\r\n0\r\n"
+        );
+    }
+}